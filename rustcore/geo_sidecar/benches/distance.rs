@@ -0,0 +1,48 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use geo_router_sidecar::geo::haversine_distance;
+use geo_router_sidecar::{GeoLocation, GeoResolver};
+
+fn location(latitude: f64, longitude: f64) -> GeoLocation {
+    GeoLocation {
+        latitude,
+        longitude,
+        ..GeoLocation::default()
+    }
+}
+
+fn bench_distance(c: &mut Criterion) {
+    let resolver = GeoResolver::new(None).unwrap();
+    let client = location(47.4979, 19.0402);
+    let cases = [
+        ("same_coordinates", location(47.4979, 19.0402)),
+        ("same_city", location(47.5316, 19.1430)),
+        ("cross_continent", location(40.7128, -74.0060)),
+    ];
+
+    let mut group = c.benchmark_group("calculate_distance");
+    for (name, replica) in &cases {
+        group.bench_function(*name, |b| {
+            b.iter(|| resolver.calculate_distance(black_box(&client), black_box(replica)))
+        });
+    }
+    group.finish();
+
+    // Baseline: the exact formula with no fast paths
+    let mut group = c.benchmark_group("haversine_distance");
+    for (name, replica) in &cases {
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                haversine_distance(
+                    black_box(client.latitude),
+                    black_box(client.longitude),
+                    black_box(replica.latitude),
+                    black_box(replica.longitude),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_distance);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/geo_router.proto");
+        tonic_build::compile_protos("proto/geo_router.proto")
+            .expect("Failed to compile geo_router.proto");
+    }
+}
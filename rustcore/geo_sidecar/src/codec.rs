@@ -0,0 +1,509 @@
+//! Wire codec negotiation for frame payloads
+//!
+//! A frame may start with a one-byte codec tag; untagged frames are JSON.
+//! Clients may open with a `hello` request naming their protocol version
+//! and codecs, and learn the server's version and the codec to use; those
+//! that don't get the behaviour of protocol version 1.
+//!
+//! The `compact` codec is for the hot `route` path: requests are still JSON,
+//! but a successful route is answered with the fixed binary layout of
+//! `CompactRoute`. Every other response to a compact request is tagged JSON,
+//! so clients tell the two apart by the response's tag. A route that is the
+//! last response before the server closes the connection is tagged JSON too,
+//! with the `CompactRoute` fields as its data, so it can carry `close`.
+//!
+//! Clients that accept compression in their `hello` may get responses of
+//! `COMPRESSION_MIN_BYTES` or more, in practice `list_replicas` and the
+//! larger `nearest_replicas` and explained routes, with `COMPRESSED_FLAG`
+//! set on the tag and the rest of the frame zstd-compressed. Untagged JSON
+//! responses gain a `JSON_TAG` when compressed.
+
+use crate::routing::RoutingResponse;
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+
+pub const JSON_TAG: u8 = 0x01;
+pub const MSGPACK_TAG: u8 = 0x02;
+pub const COMPACT_TAG: u8 = 0x03;
+
+/// Set on a response's tag when the payload after it is zstd-compressed
+pub const COMPRESSED_FLAG: u8 = 0x10;
+
+/// Smaller responses aren't worth compressing
+pub const COMPRESSION_MIN_BYTES: usize = 4096;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Protocol version this server speaks, reported in the `hello` reply
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client protocol version still accepted
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireCodec {
+    Json,
+    MsgPack,
+    Compact,
+}
+
+impl WireCodec {
+    pub const ALL: [WireCodec; 3] = [WireCodec::Json, WireCodec::MsgPack, WireCodec::Compact];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(WireCodec::Json),
+            "msgpack" => Some(WireCodec::MsgPack),
+            "compact" => Some(WireCodec::Compact),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WireCodec::Json => "json",
+            WireCodec::MsgPack => "msgpack",
+            WireCodec::Compact => "compact",
+        }
+    }
+
+    /// First of the client's codecs, in its order of preference, that the
+    /// server supports; JSON when the client names none
+    pub fn negotiate(offered: &[String]) -> Option<Self> {
+        if offered.is_empty() {
+            return Some(WireCodec::Json);
+        }
+        offered.iter().find_map(|name| WireCodec::parse(name))
+    }
+}
+
+/// Codec of a request, echoed back on its response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WireFormat {
+    pub codec: WireCodec,
+    pub tagged: bool,
+}
+
+impl WireFormat {
+    /// Legacy untagged JSON
+    pub const JSON: WireFormat = WireFormat {
+        codec: WireCodec::Json,
+        tagged: false,
+    };
+
+    /// Split a frame into its wire format and payload
+    pub fn detect(frame: &[u8]) -> (WireFormat, &[u8]) {
+        match frame.first() {
+            Some(&JSON_TAG) => (
+                WireFormat {
+                    codec: WireCodec::Json,
+                    tagged: true,
+                },
+                &frame[1..],
+            ),
+            Some(&MSGPACK_TAG) => (
+                WireFormat {
+                    codec: WireCodec::MsgPack,
+                    tagged: true,
+                },
+                &frame[1..],
+            ),
+            Some(&COMPACT_TAG) => (
+                WireFormat {
+                    codec: WireCodec::Compact,
+                    tagged: true,
+                },
+                &frame[1..],
+            ),
+            _ => (WireFormat::JSON, frame),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        Ok(match self.codec {
+            WireCodec::Json | WireCodec::Compact => serde_json::from_slice(payload)?,
+            WireCodec::MsgPack => rmp_serde::from_slice(payload)?,
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        match (self.codec, self.tagged) {
+            (WireCodec::Json, false) => serde_json::to_writer(&mut frame, value)?,
+            (WireCodec::Json, true) | (WireCodec::Compact, _) => {
+                frame.push(JSON_TAG);
+                serde_json::to_writer(&mut frame, value)?;
+            }
+            // Structs are encoded as maps so clients can read fields by name
+            (WireCodec::MsgPack, _) => {
+                frame.push(MSGPACK_TAG);
+                rmp_serde::encode::write_named(&mut frame, value)?;
+            }
+        }
+        Ok(frame)
+    }
+}
+
+/// How a connection's responses are compressed, agreed in its `hello`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /// Schemes the server can compress with, in its order of preference
+    pub const SUPPORTED: [Compression; 1] = [Compression::Zstd];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// First of the client's accepted schemes that the server supports;
+    /// unlike codecs, no match just means no compression
+    pub fn negotiate(accepted: &[String]) -> Self {
+        accepted
+            .iter()
+            .find_map(|name| Compression::parse(name))
+            .unwrap_or_default()
+    }
+
+    /// Compress an encoded response frame if it's large enough and
+    /// compression actually shrinks it
+    pub fn apply(self, frame: Vec<u8>) -> Result<Vec<u8>> {
+        if self == Compression::None || frame.len() < COMPRESSION_MIN_BYTES {
+            return Ok(frame);
+        }
+
+        let (tag, payload) = match frame.first() {
+            Some(&tag @ (JSON_TAG | MSGPACK_TAG | COMPACT_TAG)) => (tag, &frame[1..]),
+            _ => (JSON_TAG, &frame[..]),
+        };
+        let compressed = zstd::encode_all(payload, ZSTD_LEVEL)?;
+        if compressed.len() + 1 >= frame.len() {
+            return Ok(frame);
+        }
+
+        let mut compressed_frame = Vec::with_capacity(1 + compressed.len());
+        compressed_frame.push(tag | COMPRESSED_FLAG);
+        compressed_frame.extend_from_slice(&compressed);
+        Ok(compressed_frame)
+    }
+}
+
+/// Undo `Compression::apply`: a compressed frame comes back as the tagged
+/// frame it was compressed from, anything else unchanged
+pub fn decompress_frame(frame: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match frame.first() {
+        Some(&tag)
+            if tag & COMPRESSED_FLAG != 0
+                && matches!(tag & !COMPRESSED_FLAG, JSON_TAG | MSGPACK_TAG | COMPACT_TAG) =>
+        {
+            let mut decompressed = vec![tag & !COMPRESSED_FLAG];
+            zstd::stream::copy_decode(&frame[1..], &mut decompressed)
+                .context("corrupt compressed frame")?;
+            Ok(Cow::Owned(decompressed))
+        }
+        _ => Ok(Cow::Borrowed(frame)),
+    }
+}
+
+/// The fields of a `RoutingResponse` sent in reply to a compact route
+///
+/// Laid out big-endian, after the `COMPACT_TAG` byte: `node_id` and `host`
+/// each as a u16 byte length followed by UTF-8, then the u16 port, the f64
+/// `distance_km` and the u64 `response_time_micros`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompactRoute {
+    pub node_id: String,
+    pub host: String,
+    pub port: u16,
+    pub distance_km: f64,
+    pub response_time_micros: u64,
+}
+
+impl CompactRoute {
+    /// Tagged frame carrying this route
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(1 + 2 + self.node_id.len() + 2 + self.host.len() + 18);
+        frame.push(COMPACT_TAG);
+        for field in [&self.node_id, &self.host] {
+            let length = u16::try_from(field.len()).context("compact route field too long")?;
+            frame.extend_from_slice(&length.to_be_bytes());
+            frame.extend_from_slice(field.as_bytes());
+        }
+        frame.extend_from_slice(&self.port.to_be_bytes());
+        frame.extend_from_slice(&self.distance_km.to_be_bytes());
+        frame.extend_from_slice(&self.response_time_micros.to_be_bytes());
+        Ok(frame)
+    }
+
+    /// Parse a route from a frame's payload, i.e. without its tag
+    pub fn decode(mut payload: &[u8]) -> Result<Self> {
+        let node_id = take_string(&mut payload)?;
+        let host = take_string(&mut payload)?;
+        let port = u16::from_be_bytes(take(&mut payload)?);
+        let distance_km = f64::from_be_bytes(take(&mut payload)?);
+        let response_time_micros = u64::from_be_bytes(take(&mut payload)?);
+        if !payload.is_empty() {
+            bail!("{} trailing bytes after compact route", payload.len());
+        }
+
+        Ok(Self {
+            node_id,
+            host,
+            port,
+            distance_km,
+            response_time_micros,
+        })
+    }
+}
+
+impl From<&RoutingResponse> for CompactRoute {
+    fn from(response: &RoutingResponse) -> Self {
+        Self {
+            node_id: response.node_id.clone(),
+            host: response.host.clone(),
+            port: response.port,
+            distance_km: response.distance_km,
+            response_time_micros: response.response_time_micros,
+        }
+    }
+}
+
+fn take<const N: usize>(payload: &mut &[u8]) -> Result<[u8; N]> {
+    if payload.len() < N {
+        bail!("truncated compact route");
+    }
+    let (head, rest) = payload.split_at(N);
+    *payload = rest;
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(head);
+    Ok(bytes)
+}
+
+fn take_string(payload: &mut &[u8]) -> Result<String> {
+    let length = u16::from_be_bytes(take(payload)?) as usize;
+    if payload.len() < length {
+        bail!("truncated compact route");
+    }
+    let (head, rest) = payload.split_at(length);
+    *payload = rest;
+    Ok(String::from_utf8(head.to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Probe {
+        client_ip: String,
+        timestamp: u64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    enum Kind {
+        #[serde(rename = "route")]
+        Route { query_type: String },
+        #[serde(rename = "ping")]
+        Ping,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Envelope {
+        #[serde(flatten)]
+        inner: Kind,
+        timestamp: u64,
+    }
+
+    #[test]
+    fn test_untagged_frames_are_json() {
+        let (format, payload) = WireFormat::detect(br#"{"client_ip":"10.0.0.1","timestamp":7}"#);
+        assert_eq!(format, WireFormat::JSON);
+
+        let probe: Probe = format.decode(payload).unwrap();
+        assert_eq!(probe.timestamp, 7);
+        assert_eq!(format.encode(&probe).unwrap()[0], b'{');
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let probe = Probe {
+            client_ip: "10.0.0.1".to_string(),
+            timestamp: 7,
+        };
+        let format = WireFormat {
+            codec: WireCodec::MsgPack,
+            tagged: true,
+        };
+
+        let frame = format.encode(&probe).unwrap();
+        assert_eq!(frame[0], MSGPACK_TAG);
+
+        let (detected, payload) = WireFormat::detect(&frame);
+        assert_eq!(detected, format);
+        assert_eq!(detected.decode::<Probe>(payload).unwrap(), probe);
+    }
+
+    #[test]
+    fn test_negotiate_follows_client_preference() {
+        let offered = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(WireCodec::negotiate(&[]), Some(WireCodec::Json));
+        assert_eq!(
+            WireCodec::negotiate(&offered(&["cbor", "msgpack", "json"])),
+            Some(WireCodec::MsgPack)
+        );
+        assert_eq!(WireCodec::negotiate(&offered(&["cbor"])), None);
+    }
+
+    #[test]
+    fn test_compact_route_matches_json_form() {
+        let json = r#"{
+            "node_id": "replica-1",
+            "host": "10.0.0.5",
+            "port": 5432,
+            "zone": "us-east",
+            "distance_km": 1234.5,
+            "routing_strategy": "zone_local",
+            "failover_path": [],
+            "response_time_micros": 87
+        }"#;
+        let response: RoutingResponse = serde_json::from_str(json).unwrap();
+
+        let frame = CompactRoute::from(&response).encode().unwrap();
+        let (detected, payload) = WireFormat::detect(&frame);
+        assert_eq!(detected.codec, WireCodec::Compact);
+
+        let route = CompactRoute::decode(payload).unwrap();
+        assert_eq!(route.node_id, response.node_id);
+        assert_eq!(route.host, response.host);
+        assert_eq!(route.port, response.port);
+        assert_eq!(route.distance_km, response.distance_km);
+        assert_eq!(route.response_time_micros, response.response_time_micros);
+
+        assert!(CompactRoute::decode(&payload[..payload.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_compact_requests_are_json() {
+        let (format, payload) =
+            WireFormat::detect(b"\x03{\"client_ip\":\"10.0.0.1\",\"timestamp\":7}");
+        assert_eq!(format.codec, WireCodec::Compact);
+        assert_eq!(format.decode::<Probe>(payload).unwrap().timestamp, 7);
+
+        // Anything but a route reply falls back to tagged JSON
+        let probe = Probe {
+            client_ip: "10.0.0.1".to_string(),
+            timestamp: 7,
+        };
+        assert_eq!(format.encode(&probe).unwrap()[..2], [JSON_TAG, b'{']);
+    }
+
+    #[test]
+    fn test_msgpack_flattened_request_shape() {
+        let format = WireFormat {
+            codec: WireCodec::MsgPack,
+            tagged: true,
+        };
+
+        for envelope in [
+            Envelope {
+                inner: Kind::Route {
+                    query_type: "write".to_string(),
+                },
+                timestamp: 1,
+            },
+            Envelope {
+                inner: Kind::Ping,
+                timestamp: 2,
+            },
+        ] {
+            let frame = format.encode(&envelope).unwrap();
+            let (detected, payload) = WireFormat::detect(&frame);
+            assert_eq!(detected.decode::<Envelope>(payload).unwrap(), envelope);
+        }
+    }
+
+    #[test]
+    fn test_large_responses_round_trip_compressed() {
+        let replicas: Vec<_> = (0..500)
+            .map(|i| {
+                let zone = ["us-east", "eu-west", "ap-south"][i % 3];
+                serde_json::json!({
+                    "node_id": format!("replica-{}", i),
+                    "host": format!("10.0.{}.{}", i / 256, i % 256),
+                    "port": 9000,
+                    "zone": zone,
+                    "healthy": true,
+                    "load_score": i as f64 / 500.0,
+                })
+            })
+            .collect();
+        let response = serde_json::json!({"success": true, "data": {"replicas": replicas}});
+
+        for format in [
+            WireFormat::JSON,
+            WireFormat {
+                codec: WireCodec::MsgPack,
+                tagged: true,
+            },
+        ] {
+            let frame = format.encode(&response).unwrap();
+            assert!(frame.len() >= COMPRESSION_MIN_BYTES);
+            let compressed = Compression::Zstd.apply(frame.clone()).unwrap();
+            assert!(compressed.len() * 4 < frame.len());
+            assert_ne!(compressed[0] & COMPRESSED_FLAG, 0);
+
+            let decompressed = decompress_frame(&compressed).unwrap();
+            let (detected, payload) = WireFormat::detect(&decompressed);
+            // Untagged JSON comes back tagged
+            assert!(detected.tagged);
+            assert_eq!(detected.codec, format.codec);
+            assert_eq!(
+                detected.decode::<serde_json::Value>(payload).unwrap(),
+                response
+            );
+        }
+
+        // Small frames and clients without compression are left alone
+        let small = WireFormat::JSON
+            .encode(&serde_json::json!({"pong": true}))
+            .unwrap();
+        assert_eq!(Compression::Zstd.apply(small.clone()).unwrap(), small);
+        let large = WireFormat::JSON.encode(&response).unwrap();
+        assert_eq!(Compression::None.apply(large.clone()).unwrap(), large);
+        // An untagged JSON frame starts with a brace, never a flagged tag
+        assert_eq!(decompress_frame(&small).unwrap(), small);
+
+        assert_eq!(
+            Compression::negotiate(&["brotli".to_string(), "zstd".to_string()]),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::negotiate(&["brotli".to_string()]),
+            Compression::None
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! TOML config file support
+//!
+//! Config keys mirror the long CLI flags (`max-connections = 500`). File
+//! values are expanded into flags placed ahead of the real command line,
+//! so anything given on the command line takes precedence. Keys set on the
+//! command line are left out of the expansion entirely, so repeatable flags
+//! such as `--trusted-proxies` replace the file's list instead of adding to
+//! it.
+
+use crate::Args;
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Parse CLI args, merging in `--config` if given
+///
+/// Returns the args along with any config keys that were not recognized.
+pub fn parse_args() -> Result<(Args, Vec<String>)> {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let matches = Args::command().get_matches_from(&cli);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let Some(path) = args.config.clone() else {
+        return Ok((args, Vec::new()));
+    };
+
+    let (flags, unknown_keys) = load_flags(&path, &matches)?;
+    let mut argv = Vec::with_capacity(cli.len() + flags.len());
+    argv.extend(cli.first().cloned());
+    argv.extend(flags);
+    argv.extend(cli.into_iter().skip(1));
+
+    Ok((Args::parse_from(argv), unknown_keys))
+}
+
+fn load_flags(path: &Path, cli: &ArgMatches) -> Result<(Vec<OsString>, Vec<String>)> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {:?}", path))?;
+    config_flags(&contents, cli).with_context(|| format!("Invalid config file {:?}", path))
+}
+
+/// Translate config file contents into equivalent CLI flags, skipping keys
+/// already set on the command line `cli` was parsed from
+fn config_flags(contents: &str, cli: &ArgMatches) -> Result<(Vec<OsString>, Vec<String>)> {
+    let table: toml::Table = contents.parse()?;
+    let command = Args::command();
+    let mut flags = Vec::new();
+    let mut unknown_keys = Vec::new();
+
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()) && long != "config");
+
+        let Some(arg) = arg else {
+            unknown_keys.push(key);
+            continue;
+        };
+
+        if cli.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        let flag = OsString::from(format!("--{}", long));
+
+        if !arg.get_action().takes_values() {
+            match value {
+                toml::Value::Boolean(true) => flags.push(flag),
+                toml::Value::Boolean(false) => {}
+                _ => bail!("Config key {:?} expects a boolean", key),
+            }
+            continue;
+        }
+
+        let values = match value {
+            toml::Value::Array(items) => items,
+            other => vec![other],
+        };
+
+        for value in values {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                _ => bail!("Config key {:?} has an unsupported value type", key),
+            };
+            flags.push(flag.clone());
+            flags.push(OsString::from(value));
+        }
+    }
+
+    Ok((flags, unknown_keys))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn cli_matches(cli: &[&str]) -> ArgMatches {
+        Args::command()
+            .try_get_matches_from(std::iter::once("geo_router_sidecar").chain(cli.iter().copied()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cli_flags_override_config_file() {
+        let (flags, unknown_keys) = config_flags(
+            r#"
+            port = 20000
+            max-connections = 50
+            log_level = "debug"
+            retries = 3
+            "#,
+            &cli_matches(&["--port", "21000"]),
+        )
+        .unwrap();
+        assert_eq!(unknown_keys, vec!["retries".to_string()]);
+
+        let mut argv = vec![OsString::from("geo_router_sidecar")];
+        argv.extend(flags);
+        argv.extend(["--port", "21000"].map(OsString::from));
+
+        let args = Args::try_parse_from(argv).unwrap();
+        assert_eq!(args.port, 21000);
+        assert_eq!(args.max_connections, 50);
+        assert_eq!(args.log_level, "debug");
+    }
+
+    #[test]
+    fn test_cli_replaces_repeatable_config_values() {
+        let cli = ["--trusted-proxies", "127.0.0.1/32"];
+        let (flags, _) = config_flags(
+            r#"
+            trusted-proxies = ["10.0.0.0/8", "192.168.0.0/16"]
+            warmup-ip = ["10.1.2.3"]
+            "#,
+            &cli_matches(&cli),
+        )
+        .unwrap();
+
+        let mut argv = vec![OsString::from("geo_router_sidecar")];
+        argv.extend(flags);
+        argv.extend(cli.map(OsString::from));
+
+        let args = Args::try_parse_from(argv).unwrap();
+        assert_eq!(args.trusted_proxies, vec!["127.0.0.1/32".parse().unwrap()]);
+        assert_eq!(args.warmup_ips, vec!["10.1.2.3".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_boolean_flags_with_values() {
+        let (flags, _) = config_flags("tcp-nodelay = false", &cli_matches(&[])).unwrap();
+        let mut argv = vec![OsString::from("geo_router_sidecar")];
+        argv.extend(flags);
+
+        let args = Args::try_parse_from(argv).unwrap();
+        assert!(!args.tcp_nodelay);
+    }
+
+    #[test]
+    fn test_socket_mode_is_octal() {
+        let (flags, _) = config_flags(r#"socket-mode = "0660""#, &cli_matches(&[])).unwrap();
+        let mut argv = vec![OsString::from("geo_router_sidecar")];
+        argv.extend(flags);
+
+        let args = Args::try_parse_from(argv).unwrap();
+        assert_eq!(args.socket_mode, Some(0o660));
+
+        assert!(Args::try_parse_from(["geo_router_sidecar", "--socket-mode", "0980"]).is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_must_be_positive() {
+        let args = Args::try_parse_from([
+            "geo_router_sidecar",
+            "--rate-limit",
+            "2.5",
+            "--rate-limit-burst",
+            "10",
+        ])
+        .unwrap();
+        assert_eq!(args.rate_limit, Some(2.5));
+        assert_eq!(args.rate_limit_burst, Some(10.0));
+
+        for bad in ["0", "-1", "NaN", "inf"] {
+            assert!(Args::try_parse_from(["geo_router_sidecar", "--rate-limit", bad]).is_err());
+            assert!(Args::try_parse_from([
+                "geo_router_sidecar",
+                "--rate-limit",
+                "1",
+                "--rate-limit-burst",
+                bad,
+            ])
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_rejects_mistyped_values() {
+        assert!(config_flags("port = { nested = 1 }", &cli_matches(&[])).is_err());
+    }
+}
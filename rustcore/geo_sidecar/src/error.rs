@@ -0,0 +1,71 @@
+//! Typed errors returned by the routing engine and resolver
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RoutingError {
+    #[error("No healthy replicas available")]
+    NoHealthyReplicas,
+    #[error("No healthy leaders available")]
+    NoHealthyLeaders,
+    #[error("No eligible replica in zone {0}")]
+    ZoneUnavailable(String),
+    #[error("Strict zone locality needs a client zone, and none was given or derivable")]
+    ClientZoneUnknown,
+    #[error("GeoIP lookup failed: {0}")]
+    GeoLookupFailed(String),
+    #[error("Invalid client IP {0:?}")]
+    InvalidClientIp(String),
+    #[error("Unknown replica {0}")]
+    UnknownReplica(String),
+    #[error("Replica {0} is not drained")]
+    ReplicaNotDrained(String),
+    #[error("Malformed request: {0}")]
+    MalformedRequest(String),
+    #[error("Unknown request type {0:?}")]
+    UnknownRequestType(String),
+    #[error("Unknown routing strategy {0:?}")]
+    UnknownStrategy(String),
+    #[error("Routing table generation {received} is older than the current {current}")]
+    StaleGeneration { received: u64, current: u64 },
+    #[error("Protocol version {requested} is no longer supported (minimum {min})")]
+    UnsupportedProtocolVersion { requested: u32, min: u32 },
+    #[error("None of the codecs {0:?} is supported")]
+    NoCommonCodec(Vec<String>),
+    #[error("Client IP {0} may only be given by a trusted proxy")]
+    UntrustedClientIp(String),
+}
+
+impl RoutingError {
+    /// Stable identifier clients can match on; never changes once released
+    pub fn code(&self) -> &'static str {
+        match self {
+            RoutingError::NoHealthyReplicas => "NO_HEALTHY_REPLICAS",
+            RoutingError::NoHealthyLeaders => "NO_HEALTHY_LEADERS",
+            RoutingError::ZoneUnavailable(_) => "ZONE_UNAVAILABLE",
+            RoutingError::ClientZoneUnknown => "CLIENT_ZONE_UNKNOWN",
+            RoutingError::GeoLookupFailed(_) => "GEO_LOOKUP_FAILED",
+            RoutingError::InvalidClientIp(_) => "INVALID_CLIENT_IP",
+            RoutingError::UnknownReplica(_) => "UNKNOWN_REPLICA",
+            RoutingError::ReplicaNotDrained(_) => "REPLICA_NOT_DRAINED",
+            RoutingError::MalformedRequest(_) => "MALFORMED_REQUEST",
+            RoutingError::UnknownRequestType(_) => "UNKNOWN_REQUEST_TYPE",
+            RoutingError::UnknownStrategy(_) => "UNKNOWN_STRATEGY",
+            RoutingError::StaleGeneration { .. } => "STALE_GENERATION",
+            RoutingError::UnsupportedProtocolVersion { .. } => "UNSUPPORTED_PROTOCOL_VERSION",
+            RoutingError::NoCommonCodec(_) => "NO_COMMON_CODEC",
+            RoutingError::UntrustedClientIp(_) => "UNTRUSTED_CLIENT_IP",
+        }
+    }
+
+    /// Whether retrying the same request later may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            RoutingError::NoHealthyReplicas
+                | RoutingError::NoHealthyLeaders
+                | RoutingError::ZoneUnavailable(_)
+                | RoutingError::GeoLookupFailed(_)
+        )
+    }
+}
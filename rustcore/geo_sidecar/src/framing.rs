@@ -0,0 +1,230 @@
+//! Length-prefixed framing for the sidecar socket protocol
+//!
+//! Each frame is a 4-byte big-endian length followed by that many bytes.
+
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("Frame too large: {size} bytes (max {max})")]
+    TooLarge { size: usize, max: usize },
+    #[error("Connection closed mid-frame after {buffered} bytes")]
+    Truncated { buffered: usize },
+    #[error("No request received within {0:?}")]
+    IdleTimeout(Duration),
+    #[error("Request not completed within {0:?}")]
+    RequestTimeout(Duration),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Reads and writes length-prefixed frames over a byte stream
+///
+/// `read_frame` is cancel-safe: partially received frames stay buffered
+/// until the next call.
+pub struct Framed<S> {
+    stream: S,
+    read_buffer: Vec<u8>,
+    max_frame_size: usize,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    frame_started: Option<Instant>,
+}
+
+impl<S> Framed<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S, max_frame_size: usize) -> Self {
+        Self {
+            stream,
+            read_buffer: Vec::with_capacity(LENGTH_PREFIX_SIZE),
+            max_frame_size,
+            idle_timeout: None,
+            request_timeout: None,
+            frame_started: None,
+        }
+    }
+
+    /// Bound how long to wait for a frame to start and, once its first byte
+    /// arrives, how long it may take to complete
+    pub fn with_timeouts(
+        mut self,
+        idle_timeout: Option<Duration>,
+        request_timeout: Option<Duration>,
+    ) -> Self {
+        self.idle_timeout = idle_timeout;
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Read the next frame, or `None` if the peer closed between frames
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        loop {
+            if let Some(frame) = self.take_frame()? {
+                self.frame_started = None;
+                return Ok(Some(frame));
+            }
+
+            if !self.read_buffer.is_empty() && self.frame_started.is_none() {
+                self.frame_started = Some(Instant::now());
+            }
+
+            let read = self.stream.read_buf(&mut self.read_buffer);
+            let read = match (self.frame_started, self.idle_timeout, self.request_timeout) {
+                (Some(started), _, Some(timeout)) => {
+                    tokio::time::timeout_at(started + timeout, read)
+                        .await
+                        .map_err(|_| FrameError::RequestTimeout(timeout))?
+                }
+                (None, Some(timeout), _) => tokio::time::timeout(timeout, read)
+                    .await
+                    .map_err(|_| FrameError::IdleTimeout(timeout))?,
+                _ => read.await,
+            };
+
+            if read? == 0 {
+                return if self.read_buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(FrameError::Truncated {
+                        buffered: self.read_buffer.len(),
+                    })
+                };
+            }
+        }
+    }
+
+    pub async fn write_frame(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        let length = u32::try_from(data.len()).map_err(|_| FrameError::TooLarge {
+            size: data.len(),
+            max: u32::MAX as usize,
+        })?;
+
+        self.stream.write_all(&length.to_be_bytes()).await?;
+        self.stream.write_all(data).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    fn take_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.read_buffer.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        prefix.copy_from_slice(&self.read_buffer[..LENGTH_PREFIX_SIZE]);
+        let length = u32::from_be_bytes(prefix) as usize;
+
+        if length > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                size: length,
+                max: self.max_frame_size,
+            });
+        }
+
+        if self.read_buffer.len() < LENGTH_PREFIX_SIZE + length {
+            self.read_buffer
+                .reserve(LENGTH_PREFIX_SIZE + length - self.read_buffer.len());
+            return Ok(None);
+        }
+
+        let frame = self.read_buffer[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + length].to_vec();
+        self.read_buffer.drain(..LENGTH_PREFIX_SIZE + length);
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_round_trip() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, DEFAULT_MAX_FRAME_SIZE);
+        let mut server = Framed::new(server, DEFAULT_MAX_FRAME_SIZE);
+
+        client.write_frame(b"hello").await.unwrap();
+        client.write_frame(&[7u8; 200]).await.unwrap();
+
+        assert_eq!(server.read_frame().await.unwrap().unwrap(), b"hello");
+        assert_eq!(server.read_frame().await.unwrap().unwrap(), vec![7u8; 200]);
+    }
+
+    #[tokio::test]
+    async fn test_clean_eof_between_frames() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = Framed::new(server, DEFAULT_MAX_FRAME_SIZE);
+        drop(client);
+
+        assert!(server.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_eof_mid_frame_is_truncated() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Framed::new(server, DEFAULT_MAX_FRAME_SIZE);
+
+        client.write_all(&10u32.to_be_bytes()).await.unwrap();
+        client.write_all(b"abc").await.unwrap();
+        drop(client);
+
+        assert!(matches!(
+            server.read_frame().await,
+            Err(FrameError::Truncated { buffered: 7 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (_client, server) = tokio::io::duplex(64);
+        let mut server = Framed::new(server, DEFAULT_MAX_FRAME_SIZE)
+            .with_timeouts(Some(Duration::from_millis(20)), None);
+
+        assert!(matches!(
+            server.read_frame().await,
+            Err(FrameError::IdleTimeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_frame_times_out() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Framed::new(server, DEFAULT_MAX_FRAME_SIZE).with_timeouts(
+            Some(Duration::from_secs(60)),
+            Some(Duration::from_millis(20)),
+        );
+
+        client.write_all(&10u32.to_be_bytes()).await.unwrap();
+
+        assert!(matches!(
+            server.read_frame().await,
+            Err(FrameError::RequestTimeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_rejected() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Framed::new(server, 16);
+
+        client.write_all(&17u32.to_be_bytes()).await.unwrap();
+
+        assert!(matches!(
+            server.read_frame().await,
+            Err(FrameError::TooLarge { size: 17, max: 16 })
+        ));
+    }
+}
@@ -1,10 +1,89 @@
 //! Geo-location resolution module
 
+use crate::error::RoutingError;
+use crate::privacy::loggable_ip;
 use anyhow::{Context, Result};
 use maxminddb::{geoip2, Reader};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+mod centroids;
+
+/// Granularity of the loaded GeoIP database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoDatabaseKind {
+    /// No database loaded; every client resolves to the default location
+    None,
+    /// City-level database with per-network coordinates
+    City,
+    /// Country-only database; coordinates are country centroids
+    Country,
+    /// Fixed in-memory locations; see `GeoResolver::from_static`
+    Static,
+}
+
+impl GeoDatabaseKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GeoDatabaseKind::None => "none",
+            GeoDatabaseKind::City => "city",
+            GeoDatabaseKind::Country => "country",
+            GeoDatabaseKind::Static => "static",
+        }
+    }
+}
+
+/// Unit distances are reported in
+///
+/// Scoring always works in kilometers, since the scoring weights are
+/// kilometre-equivalents; the unit only affects reported distances.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DistanceUnit {
+    #[default]
+    Kilometers,
+    Miles,
+    NauticalMiles,
+}
+
+impl DistanceUnit {
+    /// Convert a distance in kilometers to this unit
+    pub fn convert_km(self, km: f64) -> f64 {
+        match self {
+            DistanceUnit::Kilometers => km,
+            DistanceUnit::Miles => km / 1.609_344,
+            DistanceUnit::NauticalMiles => km / 1.852,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DistanceUnit::Kilometers => "kilometers",
+            DistanceUnit::Miles => "miles",
+            DistanceUnit::NauticalMiles => "nautical_miles",
+        }
+    }
+}
+
+impl std::str::FromStr for DistanceUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kilometers" | "km" => Ok(DistanceUnit::Kilometers),
+            "miles" | "mi" => Ok(DistanceUnit::Miles),
+            "nautical_miles" | "nmi" => Ok(DistanceUnit::NauticalMiles),
+            _ => Err(format!(
+                "unknown distance unit {:?} (expected kilometers, miles or nautical_miles)",
+                s
+            )),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeoLocation {
@@ -14,8 +93,30 @@ pub struct GeoLocation {
     pub latitude: f64,
     pub longitude: f64,
     pub timezone: String,
+    /// Autonomous system number, when an ASN database is loaded
+    #[serde(default)]
+    pub asn: Option<u32>,
+    /// Whether the coordinates are a real position; false for the
+    /// placeholder of a client that couldn't be located, whose (0, 0) is
+    /// not actually in the Gulf of Guinea. Locations sent without this
+    /// field, such as replica positions, are taken as located.
+    #[serde(default = "default_located")]
+    pub located: bool,
+}
+
+fn default_located() -> bool {
+    true
+}
+
+impl GeoLocation {
+    /// Whether distances from this location mean anything
+    pub fn is_located(&self) -> bool {
+        self.located
+    }
 }
 
+/// The unknown-location placeholder; set `located` when filling in real
+/// coordinates
 impl Default for GeoLocation {
     fn default() -> Self {
         Self {
@@ -25,101 +126,467 @@ impl Default for GeoLocation {
             latitude: 0.0,
             longitude: 0.0,
             timezone: "UTC".to_string(),
+            asn: None,
+            located: false,
         }
     }
 }
 
+/// Counts of `resolve` outcomes since startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GeoResolutionStats {
+    /// Lookups answered from the GeoIP database
+    pub resolved: u64,
+    /// Default locations returned because no database is loaded
+    pub no_database: u64,
+    /// Default locations returned because the lookup failed
+    pub lookup_errors: u64,
+}
+
+/// One opened GeoIP database
+struct GeoDatabase {
+    path: PathBuf,
+    reader: Reader<Vec<u8>>,
+    kind: GeoDatabaseKind,
+}
+
+impl GeoDatabase {
+    fn open(path: PathBuf) -> Result<Self> {
+        let reader = Reader::open_readfile(&path)
+            .with_context(|| format!("Failed to open GeoIP database {:?}", path))?;
+        let kind = if reader.metadata.database_type.contains("Country") {
+            tracing::info!(
+                "Country-level GeoIP database {:?}; using country centroids",
+                path
+            );
+            GeoDatabaseKind::Country
+        } else {
+            GeoDatabaseKind::City
+        };
+        Ok(Self { path, reader, kind })
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Result<GeoLocation, maxminddb::MaxMindDBError> {
+        match self.kind {
+            GeoDatabaseKind::Country => self.lookup_country(ip),
+            _ => self.lookup_city(ip),
+        }
+    }
+
+    fn lookup_country(&self, ip: IpAddr) -> Result<GeoLocation, maxminddb::MaxMindDBError> {
+        let country = self.reader.lookup::<geoip2::Country>(ip)?.country;
+
+        let name = country
+            .as_ref()
+            .and_then(|c| c.names.as_ref())
+            .and_then(|n| n.get("en"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        let centroid = country
+            .as_ref()
+            .and_then(|c| c.iso_code)
+            .and_then(centroids::country_centroid);
+        let (latitude, longitude) = centroid.unwrap_or((0.0, 0.0));
+
+        Ok(GeoLocation {
+            country: name,
+            latitude,
+            longitude,
+            located: centroid.is_some(),
+            ..GeoLocation::default()
+        })
+    }
+
+    fn lookup_city(&self, ip: IpAddr) -> Result<GeoLocation, maxminddb::MaxMindDBError> {
+        let city = self.reader.lookup::<geoip2::City>(ip)?;
+
+        let country = city
+            .country
+            .as_ref()
+            .and_then(|c| c.names.as_ref())
+            .and_then(|n| n.get("en"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        let region = city
+            .subdivisions
+            .as_ref()
+            .and_then(|subdivisions| subdivisions.iter().next())
+            .and_then(|subdivision| subdivision.names.as_ref())
+            .and_then(|names| names.get("en"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        let city_name = city
+            .city
+            .as_ref()
+            .and_then(|c| c.names.as_ref())
+            .and_then(|n| n.get("en"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        let coordinates = city
+            .location
+            .as_ref()
+            .and_then(|loc| loc.latitude.zip(loc.longitude));
+        let (latitude, longitude) = coordinates.unwrap_or((0.0, 0.0));
+
+        let timezone = city
+            .location
+            .as_ref()
+            .and_then(|loc| loc.time_zone)
+            .map(|tz| tz.to_string())
+            .unwrap_or_else(|| "UTC".to_string());
+
+        Ok(GeoLocation {
+            country,
+            region,
+            city: city_name,
+            latitude,
+            longitude,
+            timezone,
+            asn: None,
+            located: coordinates.is_some(),
+        })
+    }
+}
+
 pub struct GeoResolver {
-    reader: Option<Reader<Vec<u8>>>,
+    // Consulted in order; the first to locate a client wins
+    databases: Vec<GeoDatabase>,
+    // Replaces the databases entirely when set
+    static_locations: Option<HashMap<IpAddr, GeoLocation>>,
+    asn_reader: Option<Reader<Vec<u8>>>,
+    configured: bool,
+    earth_radius_km: f64,
+    distance_unit: DistanceUnit,
+    // Mask client IPs in log lines; see `crate::privacy`
+    anonymize_ips: bool,
+    resolved: AtomicU64,
+    no_database: AtomicU64,
+    lookup_errors: AtomicU64,
 }
 
 impl GeoResolver {
     pub fn new(geoip_db_path: Option<PathBuf>) -> Result<Self> {
-        let reader = if let Some(path) = geoip_db_path {
+        Self::with_databases(geoip_db_path.into_iter().collect())
+    }
+
+    /// Consult several GeoIP databases in priority order, e.g. a
+    /// high-accuracy commercial database ahead of GeoLite2
+    ///
+    /// Missing files are skipped with a warning; a file that exists but
+    /// can't be opened is an error.
+    pub fn with_databases(geoip_db_paths: Vec<PathBuf>) -> Result<Self> {
+        let configured = !geoip_db_paths.is_empty();
+        let mut databases = Vec::with_capacity(geoip_db_paths.len());
+        for path in geoip_db_paths {
             if path.exists() {
-                Some(Reader::open_readfile(&path).context("Failed to open GeoIP database")?)
+                databases.push(GeoDatabase::open(path)?);
             } else {
                 tracing::warn!("GeoIP database not found at {:?}", path);
-                None
             }
+        }
+
+        Ok(Self {
+            databases,
+            static_locations: None,
+            asn_reader: None,
+            configured,
+            earth_radius_km: MEAN_EARTH_RADIUS_KM,
+            distance_unit: DistanceUnit::default(),
+            anonymize_ips: false,
+            resolved: AtomicU64::new(0),
+            no_database: AtomicU64::new(0),
+            lookup_errors: AtomicU64::new(0),
+        })
+    }
+
+    /// Resolve from a fixed map instead of a GeoIP database, so tests and
+    /// simulations can place clients exactly
+    ///
+    /// Locations are returned as given, ASN included. IPs missing from the
+    /// map resolve to the unlocated placeholder and count as lookup errors.
+    pub fn from_static(locations: HashMap<IpAddr, GeoLocation>) -> Self {
+        Self {
+            databases: Vec::new(),
+            static_locations: Some(locations),
+            asn_reader: None,
+            configured: true,
+            earth_radius_km: MEAN_EARTH_RADIUS_KM,
+            distance_unit: DistanceUnit::default(),
+            anonymize_ips: false,
+            resolved: AtomicU64::new(0),
+            no_database: AtomicU64::new(0),
+            lookup_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Attach a GeoLite2-ASN database so resolved locations carry an ASN
+    pub fn with_asn_db(mut self, asn_db_path: PathBuf) -> Result<Self> {
+        if asn_db_path.exists() {
+            self.asn_reader =
+                Some(Reader::open_readfile(&asn_db_path).context("Failed to open ASN database")?);
         } else {
-            None
-        };
+            tracing::warn!("ASN database not found at {:?}", asn_db_path);
+        }
+        Ok(self)
+    }
+
+    /// Model the Earth as a sphere of this radius, e.g.
+    /// `EQUATORIAL_EARTH_RADIUS_KM` instead of the default mean radius
+    pub fn with_earth_radius_km(mut self, earth_radius_km: f64) -> Self {
+        self.earth_radius_km = earth_radius_km;
+        self
+    }
 
-        Ok(Self { reader })
-    }
-
-    pub fn resolve(&self, ip: IpAddr) -> Result<GeoLocation> {
-        if let Some(ref reader) = self.reader {
-            match reader.lookup::<geoip2::City>(ip) {
-                Ok(city) => {
-                    let country = city
-                        .country
-                        .as_ref()
-                        .and_then(|c| c.names.as_ref())
-                        .and_then(|n| n.get("en"))
-                        .map(|s| s.to_string())
-                        .unwrap_or_else(|| "Unknown".to_string());
-
-                    let region = city
-                        .subdivisions
-                        .as_ref()
-                        .and_then(|subdivisions| subdivisions.iter().next())
-                        .and_then(|subdivision| subdivision.names.as_ref())
-                        .and_then(|names| names.get("en"))
-                        .map(|s| s.to_string())
-                        .unwrap_or_else(|| "Unknown".to_string());
-
-                    let city_name = city
-                        .city
-                        .as_ref()
-                        .and_then(|c| c.names.as_ref())
-                        .and_then(|n| n.get("en"))
-                        .map(|s| s.to_string())
-                        .unwrap_or_else(|| "Unknown".to_string());
-
-                    let (latitude, longitude) = city
-                        .location
-                        .as_ref()
-                        .map(|loc| (loc.latitude.unwrap_or(0.0), loc.longitude.unwrap_or(0.0)))
-                        .unwrap_or((0.0, 0.0));
-
-                    let timezone = city
-                        .location
-                        .as_ref()
-                        .and_then(|loc| loc.time_zone)
-                        .map(|tz| tz.to_string())
-                        .unwrap_or_else(|| "UTC".to_string());
-
-                    Ok(GeoLocation {
-                        country,
-                        region,
-                        city: city_name,
-                        latitude,
-                        longitude,
-                        timezone,
-                    })
+    /// Report distances from `calculate_distance` in `unit`
+    pub fn with_distance_unit(mut self, unit: DistanceUnit) -> Self {
+        self.distance_unit = unit;
+        self
+    }
+
+    /// Mask client IPs in the resolver's own log lines
+    pub fn with_anonymized_ips(mut self, anonymize_ips: bool) -> Self {
+        self.anonymize_ips = anonymize_ips;
+        self
+    }
+
+    pub fn distance_unit(&self) -> DistanceUnit {
+        self.distance_unit
+    }
+
+    fn resolve_asn(&self, ip: IpAddr) -> Option<u32> {
+        self.asn_reader
+            .as_ref()?
+            .lookup::<geoip2::Asn>(ip)
+            .ok()?
+            .autonomous_system_number
+    }
+
+    /// Whether a GeoIP database path was supplied
+    pub fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    /// Whether a GeoIP database was actually opened
+    pub fn is_loaded(&self) -> bool {
+        !self.databases.is_empty() || self.static_locations.is_some()
+    }
+
+    /// Whether the resolver can serve as configured: at least one supplied
+    /// GeoIP database must have actually loaded
+    pub fn is_ready(&self) -> bool {
+        self.is_loaded() || !self.configured
+    }
+
+    /// Which kind of GeoIP database is consulted first
+    pub fn database_kind(&self) -> GeoDatabaseKind {
+        if self.static_locations.is_some() {
+            return GeoDatabaseKind::Static;
+        }
+        self.databases
+            .first()
+            .map_or(GeoDatabaseKind::None, |database| database.kind)
+    }
+
+    /// Loaded databases in the order they are consulted
+    pub fn database_paths(&self) -> impl Iterator<Item = &Path> {
+        self.databases
+            .iter()
+            .map(|database| database.path.as_path())
+    }
+
+    pub fn resolution_stats(&self) -> GeoResolutionStats {
+        GeoResolutionStats {
+            resolved: self.resolved.load(Ordering::Relaxed),
+            no_database: self.no_database.load(Ordering::Relaxed),
+            lookup_errors: self.lookup_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn resolve(&self, ip: IpAddr) -> Result<GeoLocation, RoutingError> {
+        self.resolve_traced(ip).map(|(location, _)| location)
+    }
+
+    /// Like `resolve`, also naming the database that answered
+    ///
+    /// Databases are tried in order until one locates the client. If none
+    /// does, the first record found is returned unlocated along with its
+    /// database, or the placeholder with `None` when no database knew the IP.
+    pub fn resolve_traced(&self, ip: IpAddr) -> Result<(GeoLocation, Option<&Path>), RoutingError> {
+        if let Some(locations) = &self.static_locations {
+            return Ok((self.resolve_static(locations, ip), None));
+        }
+
+        if self.databases.is_empty() {
+            self.no_database.fetch_add(1, Ordering::Relaxed);
+            return Ok((self.with_asn(GeoLocation::default(), ip), None));
+        }
+
+        let mut fallback = None;
+        for database in &self.databases {
+            match database.lookup(ip) {
+                Ok(location) if location.is_located() => {
+                    self.resolved.fetch_add(1, Ordering::Relaxed);
+                    return Ok((self.with_asn(location, ip), Some(database.path.as_path())));
+                }
+                Ok(location) => {
+                    fallback.get_or_insert((location, database.path.as_path()));
                 }
                 Err(e) => {
-                    tracing::debug!("GeoIP lookup failed for {}: {}", ip, e);
-                    Ok(GeoLocation::default())
+                    tracing::debug!(
+                        "GeoIP lookup in {:?} failed for {}: {}",
+                        database.path,
+                        loggable_ip(ip, self.anonymize_ips),
+                        e
+                    );
                 }
             }
-        } else {
-            // No GeoIP database, return default location
-            Ok(GeoLocation::default())
+        }
+
+        match fallback {
+            Some((location, path)) => {
+                self.resolved.fetch_add(1, Ordering::Relaxed);
+                Ok((self.with_asn(location, ip), Some(path)))
+            }
+            None => {
+                self.lookup_errors.fetch_add(1, Ordering::Relaxed);
+                Ok((self.with_asn(GeoLocation::default(), ip), None))
+            }
         }
     }
 
+    fn resolve_static(&self, locations: &HashMap<IpAddr, GeoLocation>, ip: IpAddr) -> GeoLocation {
+        match locations.get(&ip) {
+            Some(location) => {
+                self.resolved.fetch_add(1, Ordering::Relaxed);
+                location.clone()
+            }
+            None => {
+                self.lookup_errors.fetch_add(1, Ordering::Relaxed);
+                GeoLocation::default()
+            }
+        }
+    }
+
+    fn with_asn(&self, mut location: GeoLocation, ip: IpAddr) -> GeoLocation {
+        location.asn = self.resolve_asn(ip);
+        location
+    }
+
+    /// Distance between two locations in the configured unit
     pub fn calculate_distance(&self, loc1: &GeoLocation, loc2: &GeoLocation) -> f64 {
-        haversine_distance(loc1.latitude, loc1.longitude, loc2.latitude, loc2.longitude)
+        self.distance_unit
+            .convert_km(self.calculate_distance_km(loc1, loc2))
+    }
+
+    /// Distance in kilometers, or `None` if either end is the unlocated
+    /// placeholder and the (0, 0) coordinates would make it meaningless
+    pub fn known_distance_km(&self, loc1: &GeoLocation, loc2: &GeoLocation) -> Option<f64> {
+        (loc1.is_located() && loc2.is_located()).then(|| self.calculate_distance_km(loc1, loc2))
     }
+
+    /// Distance between two locations in kilometers, as used for scoring
+    pub fn calculate_distance_km(&self, loc1: &GeoLocation, loc2: &GeoLocation) -> f64 {
+        let (lat1, lon1) = (loc1.latitude, loc1.longitude);
+        let (lat2, lon2) = (loc2.latitude, loc2.longitude);
+
+        // Replicas in the same city often share the exact coordinates of
+        // the client's resolved location
+        if lat1.to_bits() == lat2.to_bits() && lon1.to_bits() == lon2.to_bits() {
+            return 0.0;
+        }
+
+        if (lat2 - lat1).abs() < SHORT_RANGE_DEGREES && (lon2 - lon1).abs() < SHORT_RANGE_DEGREES {
+            return self.earth_radius_km * equirectangular_angle(lat1, lon1, lat2, lon2);
+        }
+
+        self.earth_radius_km * haversine_angle(lat1, lon1, lat2, lon2)
+    }
+}
+
+/// Spherical mean of `locations`, correct across the antimeridian
+///
+/// Points are averaged as unit vectors, so (0, 179) and (0, -179) meet at
+/// (0, 180) rather than (0, 0). Only the coordinates and a shared ASN carry
+/// over; other fields are left at their defaults. The centroid is located
+/// only if every input is, since a placeholder would drag it toward (0, 0).
+pub fn geographic_centroid(locations: &[GeoLocation]) -> GeoLocation {
+    let mut centroid = GeoLocation::default();
+    if locations.is_empty() {
+        return centroid;
+    }
+
+    let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+    for location in locations {
+        let (lat, lon) = (location.latitude.to_radians(), location.longitude.to_radians());
+        x += lat.cos() * lon.cos();
+        y += lat.cos() * lon.sin();
+        z += lat.sin();
+    }
+    let count = locations.len() as f64;
+    let (x, y, z) = (x / count, y / count, z / count);
+
+    centroid.latitude = z.atan2((x * x + y * y).sqrt()).to_degrees();
+    centroid.longitude = y.atan2(x).to_degrees();
+    centroid.located = locations.iter().all(GeoLocation::is_located);
+    centroid.asn = locations[0]
+        .asn
+        .filter(|asn| locations.iter().all(|location| location.asn == Some(*asn)));
+    centroid
+}
+
+/// Initial bearing of the great circle from `from` to `to`, in degrees
+/// clockwise from true north in `[0, 360)`: 0 is north, 90 east
+///
+/// Only meridians and the equator keep a constant bearing, so on other
+/// routes this is the direction of departure, not of arrival. Coincident
+/// points give 0.
+pub fn initial_bearing(from: &GeoLocation, to: &GeoLocation) -> f64 {
+    let lat1 = from.latitude.to_radians();
+    let lat2 = to.latitude.to_radians();
+    let delta_lon = (to.longitude - from.longitude).to_radians();
+
+    let y = delta_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * delta_lon.cos();
+    let bearing = y.atan2(x).to_degrees().rem_euclid(360.0);
+    // A tiny negative angle rounds up to a full turn
+    if bearing < 360.0 {
+        bearing
+    } else {
+        0.0
+    }
+}
+
+/// Mean Earth radius, the default
+pub const MEAN_EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// WGS 84 equatorial Earth radius
+pub const EQUATORIAL_EARTH_RADIUS_KM: f64 = 6378.137;
+
+/// Coordinate delta below which the equirectangular approximation is used
+/// (about 55km of latitude; the error stays under a meter at that range)
+const SHORT_RANGE_DEGREES: f64 = 0.5;
+
+/// Flat-earth approximation of the central angle in radians, only accurate
+/// for nearby points
+fn equirectangular_angle(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let x = (lon2 - lon1).to_radians() * ((lat1 + lat2) / 2.0).to_radians().cos();
+    let y = (lat2 - lat1).to_radians();
+
+    (x * x + y * y).sqrt()
 }
 
-/// Calculate haversine distance between two points in kilometers
-fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    const EARTH_RADIUS_KM: f64 = 6371.0;
+/// Calculate haversine distance between two points in kilometers, on a
+/// sphere of the mean Earth radius
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    MEAN_EARTH_RADIUS_KM * haversine_angle(lat1, lon1, lat2, lon2)
+}
 
+/// Central angle between two points in radians
+fn haversine_angle(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
     let lat1_rad = lat1.to_radians();
     let lat2_rad = lat2.to_radians();
     let delta_lat = (lat2 - lat1).to_radians();
@@ -127,7 +594,187 @@ fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
 
     let a = (delta_lat / 2.0).sin().powi(2)
         + lat1_rad.cos() * lat2_rad.cos() * (delta_lon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+    2.0 * a.sqrt().atan2((1.0 - a).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(latitude: f64, longitude: f64) -> GeoLocation {
+        GeoLocation {
+            latitude,
+            longitude,
+            located: true,
+            ..GeoLocation::default()
+        }
+    }
+
+    #[test]
+    fn test_identical_coordinates_are_zero_distance() {
+        let resolver = GeoResolver::new(None).unwrap();
+        let loc = location(47.4979, 19.0402);
+        assert_eq!(resolver.calculate_distance(&loc, &loc.clone()), 0.0);
+    }
+
+    #[test]
+    fn test_static_resolver_places_clients() {
+        let client: IpAddr = "198.51.100.1".parse().unwrap();
+        let resolver = GeoResolver::from_static(HashMap::from([(
+            client,
+            GeoLocation {
+                asn: Some(64500),
+                ..location(47.4979, 19.0402)
+            },
+        )]));
+        assert!(resolver.is_ready());
+        assert_eq!(resolver.database_kind(), GeoDatabaseKind::Static);
 
-    EARTH_RADIUS_KM * c
+        let located = resolver.resolve(client).unwrap();
+        assert_eq!(located.latitude, 47.4979);
+        assert_eq!(located.asn, Some(64500));
+
+        assert!(!resolver
+            .resolve("203.0.113.7".parse().unwrap())
+            .unwrap()
+            .is_located());
+        let stats = resolver.resolution_stats();
+        assert_eq!((stats.resolved, stats.lookup_errors), (1, 1));
+    }
+
+    #[test]
+    fn test_counts_missing_database_fallbacks() {
+        let resolver = GeoResolver::new(None).unwrap();
+        resolver.resolve("203.0.113.7".parse().unwrap()).unwrap();
+        resolver.resolve("198.51.100.1".parse().unwrap()).unwrap();
+
+        assert_eq!(
+            resolver.resolution_stats(),
+            GeoResolutionStats {
+                resolved: 0,
+                no_database: 2,
+                lookup_errors: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_databases_are_skipped() {
+        let resolver = GeoResolver::with_databases(vec![
+            PathBuf::from("/nonexistent/GeoIP2-City.mmdb"),
+            PathBuf::from("/nonexistent/GeoLite2-City.mmdb"),
+        ])
+        .unwrap();
+        assert!(resolver.is_configured());
+        assert!(!resolver.is_ready());
+        assert_eq!(resolver.database_paths().count(), 0);
+
+        let (location, source) = resolver
+            .resolve_traced("203.0.113.7".parse().unwrap())
+            .unwrap();
+        assert!(!location.is_located());
+        assert!(source.is_none());
+        assert_eq!(resolver.resolution_stats().no_database, 1);
+    }
+
+    #[test]
+    fn test_short_range_approximation_matches_haversine() {
+        let resolver = GeoResolver::new(None).unwrap();
+        for (a, b) in [
+            (location(47.4979, 19.0402), location(47.5316, 19.1430)),
+            (location(-33.8688, 151.2093), location(-33.7, 151.5)),
+            (location(64.1466, -21.9426), location(64.3, -21.6)),
+        ] {
+            let approx = resolver.calculate_distance(&a, &b);
+            let exact = haversine_distance(a.latitude, a.longitude, b.latitude, b.longitude);
+            assert!((approx - exact).abs() < 0.01, "{} vs {}", approx, exact);
+        }
+    }
+
+    #[test]
+    fn test_centroid_across_antimeridian() {
+        let centroid = geographic_centroid(&[location(10.0, 179.0), location(-10.0, -179.0)]);
+        assert!(centroid.latitude.abs() < 1e-9);
+        assert!((centroid.longitude.abs() - 180.0).abs() < 1e-9);
+
+        let centroid = geographic_centroid(&[location(0.0, 0.0), location(0.0, 90.0)]);
+        assert!((centroid.longitude - 45.0).abs() < 1e-9);
+
+        assert_eq!(geographic_centroid(&[]).latitude, 0.0);
+        assert!(!geographic_centroid(&[]).is_located());
+    }
+
+    #[test]
+    fn test_unresolved_clients_are_not_located() {
+        let resolver = GeoResolver::new(None).unwrap();
+        let unknown = resolver.resolve("203.0.113.7".parse().unwrap()).unwrap();
+        assert!(!unknown.is_located());
+
+        let centroid = geographic_centroid(&[unknown, location(10.0, 10.0)]);
+        assert!(!centroid.is_located());
+        assert!(geographic_centroid(&[location(10.0, 10.0)]).is_located());
+
+        assert!(resolver
+            .known_distance_km(&centroid, &location(10.0, 10.0))
+            .is_none());
+        assert!(resolver
+            .known_distance_km(&location(10.0, 10.0), &location(11.0, 10.0))
+            .is_some());
+    }
+
+    #[test]
+    fn test_long_range_uses_haversine() {
+        let resolver = GeoResolver::new(None).unwrap();
+        let (a, b) = (location(40.7128, -74.0060), location(51.5074, -0.1278));
+        assert_eq!(
+            resolver.calculate_distance(&a, &b),
+            haversine_distance(a.latitude, a.longitude, b.latitude, b.longitude)
+        );
+    }
+
+    #[test]
+    fn test_initial_bearing() {
+        let (new_york, london) = (location(40.7128, -74.0060), location(51.5074, -0.1278));
+        assert!((initial_bearing(&new_york, &london) - 51.2).abs() < 0.1);
+        assert!((initial_bearing(&london, &new_york) - 288.3).abs() < 0.1);
+
+        let origin = location(0.0, 0.0);
+        for (to, bearing) in [
+            (location(10.0, 0.0), 0.0),
+            (location(0.0, 10.0), 90.0),
+            (location(-10.0, 0.0), 180.0),
+            (location(0.0, -10.0), 270.0),
+            (origin.clone(), 0.0),
+        ] {
+            assert!((initial_bearing(&origin, &to) - bearing).abs() < 1e-9);
+        }
+
+        // Due east across the antimeridian, not west the long way round
+        let bearing = initial_bearing(&location(0.0, 179.0), &location(0.0, -179.0));
+        assert!((bearing - 90.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_distance_unit_and_radius() {
+        let (a, b) = (location(40.7128, -74.0060), location(51.5074, -0.1278));
+        let km = GeoResolver::new(None).unwrap().calculate_distance(&a, &b);
+
+        let miles = GeoResolver::new(None)
+            .unwrap()
+            .with_distance_unit(DistanceUnit::Miles);
+        assert!((miles.calculate_distance(&a, &b) - km / 1.609_344).abs() < 1e-9);
+        assert_eq!(miles.calculate_distance_km(&a, &b), km);
+
+        let equatorial = GeoResolver::new(None)
+            .unwrap()
+            .with_earth_radius_km(EQUATORIAL_EARTH_RADIUS_KM);
+        let ratio = equatorial.calculate_distance(&a, &b) / km;
+        assert!((ratio - EQUATORIAL_EARTH_RADIUS_KM / MEAN_EARTH_RADIUS_KM).abs() < 1e-12);
+
+        assert_eq!(
+            "nmi".parse::<DistanceUnit>(),
+            Ok(DistanceUnit::NauticalMiles)
+        );
+        assert!("furlongs".parse::<DistanceUnit>().is_err());
+    }
 }
@@ -0,0 +1,408 @@
+//! gRPC transport, served alongside the length-prefixed protocol
+//!
+//! Requests map onto the same routing engine, resolver and metrics as the
+//! framed listeners; only the wire types differ.
+
+use crate::error::RoutingError;
+use crate::geo::{GeoLocation, GeoResolver};
+use crate::metrics::MetricsCollector;
+use crate::routing::{
+    QueryType, ReplicaInfo, ReplicaTarget, RoutingEngine, RoutingRequest, RoutingResponse,
+    RoutingStrategy, ZoneLocality,
+};
+use crate::trusted_proxy::{check_client_ips, TrustedProxies};
+use anyhow::{Context, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("pyhmssql.georouter");
+}
+
+use proto::geo_router_server::{GeoRouter, GeoRouterServer};
+
+pub struct GeoRouterService {
+    geo_resolver: Arc<GeoResolver>,
+    routing_engine: Arc<RoutingEngine>,
+    metrics: Arc<MetricsCollector>,
+    allow_mutations: bool,
+    trusted_proxies: TrustedProxies,
+}
+
+impl GeoRouterService {
+    /// With `allow_mutations` off, `UpdateRoutingTable` is refused; used when
+    /// the framed listener requires client certificates for mutations,
+    /// since this transport has no way to present one
+    pub fn new(
+        geo_resolver: Arc<GeoResolver>,
+        routing_engine: Arc<RoutingEngine>,
+        metrics: Arc<MetricsCollector>,
+        allow_mutations: bool,
+    ) -> Self {
+        Self {
+            geo_resolver,
+            routing_engine,
+            metrics,
+            allow_mutations,
+            trusted_proxies: TrustedProxies::default(),
+        }
+    }
+
+    /// Only let peers in `trusted_proxies` route for other client IPs
+    pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl GeoRouter for GeoRouterService {
+    async fn route(
+        &self,
+        request: Request<proto::RouteRequest>,
+    ) -> Result<Response<proto::RouteResponse>, Status> {
+        let start_time = Instant::now();
+        // Peers without an address, e.g. over a Unix socket, are local
+        let untrusted_peer = request
+            .remote_addr()
+            .and_then(|addr| self.trusted_proxies.restrict(addr.ip()));
+        let request = request.into_inner();
+        let parse_ip = |ip: &String| {
+            ip.parse::<IpAddr>()
+                .map_err(|_| routing_status(RoutingError::InvalidClientIp(ip.clone())))
+        };
+        let client_ip = parse_ip(&request.client_ip)?;
+        let additional_client_ips = request
+            .additional_client_ips
+            .iter()
+            .map(parse_ip)
+            .collect::<Result<Vec<_>, _>>()?;
+        check_client_ips(
+            untrusted_peer,
+            std::iter::once(&client_ip).chain(&additional_client_ips),
+        )
+        .map_err(routing_status)?;
+
+        let strategy = request
+            .strategy
+            .as_deref()
+            .map(RoutingStrategy::parse)
+            .transpose()
+            .map_err(routing_status)?;
+
+        let zone_locality = match request.zone_locality() {
+            proto::ZoneLocality::Any => ZoneLocality::Any,
+            proto::ZoneLocality::Preferred => ZoneLocality::Preferred,
+            proto::ZoneLocality::Strict => ZoneLocality::Strict,
+        };
+
+        let routing_request = RoutingRequest {
+            client_ip,
+            additional_client_ips,
+            query_type: request.query_type,
+            timestamp: current_timestamp_micros(),
+            candidates: request.candidates.max(1) as usize,
+            zone_locality,
+            client_zone: request.client_zone,
+            preferred_zones: request.preferred_zones,
+            affinity_key: request.affinity_key,
+            deadline_micros: request.deadline_micros,
+            strategy,
+            ..Default::default()
+        };
+
+        let result = self
+            .routing_engine
+            .route_request(&routing_request, &self.geo_resolver);
+
+        let latency_micros = start_time.elapsed().as_micros() as u64;
+        self.metrics.record_dimension(
+            QueryType::parse(&routing_request.query_type),
+            result.as_ref().map_or("none", |response| response.zone.as_str()),
+            latency_micros,
+            result.is_ok(),
+        );
+        self.metrics.record_request(latency_micros, result.is_ok());
+
+        result
+            .map(|response| Response::new(response.into()))
+            .map_err(routing_status)
+    }
+
+    async fn update_routing_table(
+        &self,
+        request: Request<proto::UpdateRoutingTableRequest>,
+    ) -> Result<Response<proto::UpdateRoutingTableResponse>, Status> {
+        if !self.allow_mutations {
+            return Err(Status::permission_denied(
+                "mutating requests require a trusted client certificate",
+            ));
+        }
+
+        let request = request.into_inner();
+        let replicas = request
+            .replicas
+            .into_iter()
+            .map(ReplicaInfo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let update = self
+            .routing_engine
+            .update_replicas_at(replicas, request.generation)
+            .map_err(routing_status)?;
+
+        Ok(Response::new(proto::UpdateRoutingTableResponse {
+            updated: true,
+            generation: update.generation,
+            rejected: update.rejected as u64,
+            clamped: update.clamped as u64,
+        }))
+    }
+
+    async fn health(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthResponse>, Status> {
+        let replica_count = self.routing_engine.get_replica_count();
+        let healthy_replica_count = self.routing_engine.get_healthy_replica_count();
+        let eligible_replica_count = self.routing_engine.get_eligible_replica_count();
+
+        Ok(Response::new(proto::HealthResponse {
+            live: true,
+            ready: eligible_replica_count > 0
+                && self.geo_resolver.is_ready()
+                && self.routing_engine.is_warmed_up(),
+            replica_count: replica_count as u64,
+            healthy_replica_count: healthy_replica_count as u64,
+            eligible_replica_count: eligible_replica_count as u64,
+            geoip_loaded: self.geo_resolver.is_loaded(),
+            geoip_database: self.geo_resolver.database_kind().as_str().to_string(),
+            routing_table_stale: self.routing_engine.is_table_stale(),
+        }))
+    }
+
+    async fn get_metrics(
+        &self,
+        _request: Request<proto::GetMetricsRequest>,
+    ) -> Result<Response<proto::MetricsResponse>, Status> {
+        let snapshot = self.metrics.get_snapshot();
+        let geo_stats = self.geo_resolver.resolution_stats();
+
+        Ok(Response::new(proto::MetricsResponse {
+            total_requests: snapshot.total_requests,
+            successful_requests: snapshot.successful_requests,
+            failed_requests: snapshot.failed_requests,
+            avg_latency_micros: snapshot.avg_latency_micros,
+            min_latency_micros: snapshot.min_latency_micros,
+            max_latency_micros: snapshot.max_latency_micros,
+            p50_micros: snapshot.p50_micros,
+            p95_micros: snapshot.p95_micros,
+            p99_micros: snapshot.p99_micros,
+            geoip_resolved: geo_stats.resolved,
+            geoip_no_database: geo_stats.no_database,
+            geoip_lookup_errors: geo_stats.lookup_errors,
+            rejected_oversized: snapshot.rejected_oversized,
+            connections_rejected: snapshot.connections_rejected,
+            current_active_connections: snapshot.current_active_connections,
+        }))
+    }
+
+    async fn report_done(
+        &self,
+        request: Request<proto::ReportDoneRequest>,
+    ) -> Result<Response<proto::ReportDoneResponse>, Status> {
+        let node_id = request.into_inner().node_id;
+        self.routing_engine
+            .report_done(&node_id)
+            .map_err(routing_status)?;
+
+        Ok(Response::new(proto::ReportDoneResponse {
+            in_flight: self.routing_engine.in_flight_count(&node_id),
+        }))
+    }
+}
+
+/// Serve gRPC until the listener fails or `shutdown` flips to true
+pub async fn serve(
+    addr: SocketAddr,
+    service: GeoRouterService,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    tracing::info!("gRPC listener bound to {}", addr);
+
+    tonic::transport::Server::builder()
+        .add_service(GeoRouterServer::new(service))
+        .serve_with_shutdown(addr, async move {
+            let _ = shutdown.wait_for(|&stopped| stopped).await;
+        })
+        .await
+        .context("gRPC server failed")
+}
+
+/// Map onto a gRPC status, keeping the stable error code in the metadata
+fn routing_status(error: RoutingError) -> Status {
+    let mut status = match error {
+        RoutingError::NoHealthyReplicas
+        | RoutingError::NoHealthyLeaders
+        | RoutingError::ZoneUnavailable(_)
+        | RoutingError::GeoLookupFailed(_) => Status::unavailable(error.to_string()),
+        RoutingError::InvalidClientIp(_)
+        | RoutingError::ClientZoneUnknown
+        | RoutingError::MalformedRequest(_)
+        | RoutingError::UnknownRequestType(_)
+        | RoutingError::UnknownStrategy(_)
+        | RoutingError::UnsupportedProtocolVersion { .. }
+        | RoutingError::NoCommonCodec(_) => Status::invalid_argument(error.to_string()),
+        RoutingError::UnknownReplica(_) => Status::not_found(error.to_string()),
+        RoutingError::UntrustedClientIp(_) => Status::permission_denied(error.to_string()),
+        RoutingError::ReplicaNotDrained(_) | RoutingError::StaleGeneration { .. } => {
+            Status::failed_precondition(error.to_string())
+        }
+    };
+    status
+        .metadata_mut()
+        .insert("error-code", tonic::metadata::MetadataValue::from_static(error.code()));
+    status
+}
+
+impl From<GeoLocation> for proto::GeoLocation {
+    fn from(location: GeoLocation) -> Self {
+        Self {
+            country: location.country,
+            region: location.region,
+            city: location.city,
+            latitude: location.latitude,
+            longitude: location.longitude,
+            timezone: location.timezone,
+            asn: location.asn,
+        }
+    }
+}
+
+impl From<proto::GeoLocation> for GeoLocation {
+    fn from(location: proto::GeoLocation) -> Self {
+        Self {
+            country: location.country,
+            region: location.region,
+            city: location.city,
+            latitude: location.latitude,
+            longitude: location.longitude,
+            timezone: location.timezone,
+            asn: location.asn,
+            // Only replica positions arrive over the wire, and those are real
+            located: true,
+        }
+    }
+}
+
+impl TryFrom<proto::ReplicaInfo> for ReplicaInfo {
+    type Error = Status;
+
+    fn try_from(replica: proto::ReplicaInfo) -> Result<Self, Status> {
+        let port = u16::try_from(replica.port).map_err(|_| {
+            Status::invalid_argument(format!(
+                "replica {} has out-of-range port {}",
+                replica.node_id, replica.port
+            ))
+        })?;
+
+        Ok(Self {
+            node_id: replica.node_id,
+            host: replica.host,
+            port,
+            is_leader: replica.is_leader,
+            healthy: replica.healthy,
+            zone: replica.zone,
+            geo_location: replica.geo_location.map(Into::into).unwrap_or_default(),
+            load_score: replica.load_score,
+            latency_ms: replica.latency_ms,
+            asn: replica.asn,
+            capacity_weight: replica.capacity_weight.unwrap_or(1.0),
+            read_weight: replica.read_weight.unwrap_or(1.0),
+        })
+    }
+}
+
+impl From<ReplicaTarget> for proto::ReplicaTarget {
+    fn from(target: ReplicaTarget) -> Self {
+        Self {
+            node_id: target.node_id,
+            host: target.host,
+            port: target.port.into(),
+            zone: target.zone,
+            distance_km: target.distance_km,
+            score: target.score,
+        }
+    }
+}
+
+impl From<RoutingResponse> for proto::RouteResponse {
+    fn from(response: RoutingResponse) -> Self {
+        Self {
+            node_id: response.node_id,
+            host: response.host,
+            port: response.port.into(),
+            zone: response.zone,
+            distance_km: response.distance_km,
+            distance: response.distance,
+            distance_unit: response.distance_unit.as_str().to_string(),
+            routing_strategy: response.routing_strategy,
+            failover_path: response.failover_path,
+            response_time_micros: response.response_time_micros,
+            alternates: response.alternates.into_iter().map(Into::into).collect(),
+            forward_via: response.forward_via.map(Into::into),
+            degraded: response.degraded,
+            cached: response.cached,
+            client_zone: response.client_zone,
+            served_from_client_zone: response.served_from_client_zone,
+            preferred_zone: response.preferred_zone,
+        }
+    }
+}
+
+fn current_timestamp_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_out_of_range_port() {
+        let replica = proto::ReplicaInfo {
+            node_id: "node-1".to_string(),
+            port: 70_000,
+            ..Default::default()
+        };
+        assert!(ReplicaInfo::try_from(replica).is_err());
+    }
+
+    #[test]
+    fn test_replica_conversion_keeps_location() {
+        let replica = proto::ReplicaInfo {
+            node_id: "node-1".to_string(),
+            host: "10.0.0.1".to_string(),
+            port: 5432,
+            zone: "eu-central".to_string(),
+            geo_location: Some(proto::GeoLocation {
+                latitude: 47.5,
+                longitude: 19.0,
+                asn: Some(64512),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let replica = ReplicaInfo::try_from(replica).unwrap();
+        assert_eq!(replica.port, 5432);
+        assert_eq!(replica.geo_location.latitude, 47.5);
+        assert_eq!(replica.geo_location.asn, Some(64512));
+    }
+}
@@ -0,0 +1,70 @@
+//! Active replica health checks
+//!
+//! The control plane's `healthy` flag can lag a replica going down. When
+//! enabled, the sidecar also opens a TCP connection to every replica each
+//! interval and stops routing to those it can't reach.
+
+use crate::routing::RoutingEngine;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+/// Probe every replica each `interval` until the task is dropped
+pub async fn run(engine: Arc<RoutingEngine>, interval: Duration, timeout: Duration) -> Result<()> {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        // Probed concurrently, so one unreachable replica costs at most
+        // `timeout` per round rather than delaying the rest
+        let mut probes = JoinSet::new();
+        for (node_id, host, port) in engine.probe_targets() {
+            probes.spawn(async move {
+                let reachable = probe(&host, port, timeout).await;
+                (node_id, reachable)
+            });
+        }
+
+        while let Some(result) = probes.join_next().await {
+            match result {
+                Ok((node_id, reachable)) => engine.set_probe_result(&node_id, reachable),
+                Err(e) => tracing::debug!("Health probe task failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Whether a TCP connection to `host:port` opens within `timeout`
+pub async fn probe(host: &str, port: u16, timeout: Duration) -> bool {
+    match tokio::time::timeout(timeout, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            tracing::debug!("Health probe of {}:{} failed: {}", host, port, e);
+            false
+        }
+        Err(_) => {
+            tracing::debug!("Health probe of {}:{} timed out", host, port);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_probe_reports_reachability() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(probe("127.0.0.1", port, Duration::from_secs(1)).await);
+
+        drop(listener);
+        assert!(!probe("127.0.0.1", port, Duration::from_secs(1)).await);
+    }
+}
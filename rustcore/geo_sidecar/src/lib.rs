@@ -1,7 +1,27 @@
+pub mod codec;
+pub mod error;
+pub mod framing;
 pub mod geo;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health_probe;
 pub mod metrics;
+pub mod privacy;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod rate_limit;
+pub mod route_cache;
 pub mod routing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+pub mod trusted_proxy;
 
-pub use geo::{GeoLocation, GeoResolver};
+pub use error::RoutingError;
+pub use geo::{GeoDatabaseKind, GeoLocation, GeoResolutionStats, GeoResolver};
 pub use metrics::MetricsCollector;
-pub use routing::{ReplicaInfo, RoutingEngine, RoutingRequest, RoutingResponse};
+pub use routing::{
+    affinity_hash, AffinityMatch, AffinityRule, AffinityTarget, QueryType, ReplicaInfo,
+    ReplicaState, RoutingEngine, RoutingRequest, RoutingResponse, RoutingStrategy, ScoringWeights,
+    TableUpdate, ZoneLocality,
+};
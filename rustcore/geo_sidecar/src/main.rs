@@ -4,32 +4,66 @@
 //! for the pyHMSSQL distributed database system.
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{ArgAction, Parser, ValueEnum};
 use dashmap::DashMap;
-use maxminddb::Reader;
-use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
 use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, UnixListener};
-use tracing::{debug, error, info, warn};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Semaphore};
+use tracing::{debug, debug_span, error, info, warn, Instrument};
 
+pub mod codec;
+mod config;
+pub mod error;
+pub mod framing;
 pub mod geo;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health_probe;
+pub mod route_cache;
 pub mod routing;
 pub mod metrics;
+pub mod privacy;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod rate_limit;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+pub mod trusted_proxy;
 
-use geo::{GeoLocation, GeoResolver};
-use routing::{ReplicaInfo, RoutingEngine, RoutingRequest, RoutingResponse};
+use codec::{
+    CompactRoute, Compression, WireCodec, WireFormat, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use error::RoutingError;
+use framing::{FrameError, Framed, DEFAULT_MAX_FRAME_SIZE};
+use geo::{DistanceUnit, GeoDatabaseKind, GeoResolver, MEAN_EARTH_RADIUS_KM};
+use routing::{
+    AffinityRule, QueryType, ReplicaInfo, RoutingEngine, RoutingRequest, RoutingStrategy,
+    ScoringWeights, ZoneLocality,
+};
 use metrics::MetricsCollector;
+use rate_limit::RateLimiter;
+use transport::{ServeContext, Session};
+use trusted_proxy::{IpNet, TrustedProxies};
 
 #[derive(Parser, Debug)]
 #[command(name = "geo_router_sidecar")]
 #[command(about = "High-performance geo-routing sidecar for pyHMSSQL")]
+#[command(args_override_self = true)]
 pub struct Args {
+    /// TOML config file whose keys mirror these flags; flags take precedence
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
     /// TCP port to listen on
     #[arg(short, long, default_value = "19999")]
     pub port: u16,
@@ -38,17 +72,243 @@ pub struct Args {
     #[arg(short, long, default_value = "/tmp/pyhmssql_geo_router.sock")]
     pub socket: PathBuf,
 
+    /// Octal permissions applied to the Unix socket after binding (e.g. 0660)
+    #[arg(long, value_parser = parse_socket_mode)]
+    pub socket_mode: Option<u32>,
+
+    /// Group name or numeric gid given ownership of the Unix socket
+    #[arg(long)]
+    pub socket_group: Option<String>,
+
     /// Maximum concurrent connections
     #[arg(short = 'c', long, default_value = "1000")]
     pub max_connections: usize,
 
-    /// GeoIP database path
+    /// GeoIP database path; repeat to consult several in priority order
     #[arg(short = 'g', long)]
-    pub geoip_db: Option<PathBuf>,
+    pub geoip_db: Vec<PathBuf>,
+
+    /// GeoLite2-ASN database path, enabling same-ASN replica preference
+    #[arg(long)]
+    pub asn_db: Option<PathBuf>,
+
+    /// Unit reported distances are given in (kilometers, miles or
+    /// nautical_miles); scoring always uses km
+    #[arg(long, default_value = "kilometers")]
+    pub distance_unit: DistanceUnit,
+
+    /// Radius of the sphere distances are computed on (km)
+    #[arg(long, default_value_t = MEAN_EARTH_RADIUS_KM)]
+    pub earth_radius_km: f64,
 
     /// Log level
     #[arg(short = 'l', long, default_value = "info")]
     pub log_level: String,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Mask client IPs (last IPv4 octet, last 80 IPv6 bits) in logs and
+    /// connection ids; routing still uses the full address
+    #[arg(long)]
+    pub anonymize_ips: bool,
+
+    /// Score multiplier on client-replica distance (km per km)
+    #[arg(long, default_value_t = ScoringWeights::default().distance_km)]
+    pub distance_weight: f64,
+
+    /// Score penalty per unit of replica load (km per load unit)
+    #[arg(long, default_value_t = ScoringWeights::default().load_penalty)]
+    pub load_penalty_km: f64,
+
+    /// Score penalty per millisecond of replica latency on reads (km per ms)
+    #[arg(long, default_value_t = ScoringWeights::default().latency_weight)]
+    pub latency_weight: f64,
+
+    /// Score bonus for leaders on reads (km)
+    #[arg(long, default_value_t = ScoringWeights::default().leader_bonus)]
+    pub leader_bonus_km: f64,
+
+    /// Score bonus on reads for replicas sharing the client's ASN (km)
+    #[arg(long, default_value_t = ScoringWeights::default().asn_match_bonus)]
+    pub asn_match_bonus_km: f64,
+
+    /// Score bonus on reads per unit of a replica's read weight above 1.0 (km)
+    #[arg(long, default_value_t = ScoringWeights::default().read_weight_bonus)]
+    pub read_weight_bonus_km: f64,
+
+    /// Score penalty per request routed to a replica and not yet reported
+    /// done by the client (km); 0 disables in-flight tracking
+    #[arg(long, default_value_t = ScoringWeights::default().in_flight_penalty)]
+    pub in_flight_penalty_km: f64,
+
+    /// Score clients GeoIP couldn't locate, and replicas sent without a
+    /// position, on load and latency alone
+    #[arg(long)]
+    pub ignore_unlocated_distance: bool,
+
+    /// Suggest relaying writes through the nearest replica when the leader
+    /// is this many km farther away (disabled when unset)
+    #[arg(long)]
+    pub write_forward_margin_km: Option<f64>,
+
+    /// Client IP to resolve and route at startup before reporting ready;
+    /// repeat for several representative clients
+    #[arg(long = "warmup-ip")]
+    pub warmup_ips: Vec<IpAddr>,
+
+    /// Probe every replica with a TCP connect this often and skip those
+    /// that fail, on top of their reported health (disabled when unset)
+    #[arg(long)]
+    pub probe_interval_ms: Option<u64>,
+
+    /// Halve the weight of older samples in the reported latency
+    /// percentiles every this many seconds, so p95/p99 track the last few
+    /// windows (cumulative since the last reset when unset)
+    #[arg(long)]
+    pub percentile_window_secs: Option<u64>,
+
+    /// Milliseconds a health probe may take before the replica counts as down
+    #[arg(long, default_value = "500")]
+    pub probe_timeout_ms: u64,
+
+    /// Routing decisions to cache for identical requests from one client
+    /// subnet (0 disables the cache)
+    #[arg(long, default_value = "0")]
+    pub route_cache_size: usize,
+
+    /// Milliseconds a cached routing decision stays valid
+    #[arg(long, default_value = "100")]
+    pub route_cache_ttl_ms: u64,
+
+    /// Maximum request frame size in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    pub max_frame_bytes: usize,
+
+    /// File the routing table is persisted to and restored from on startup
+    #[arg(long)]
+    pub routing_snapshot: Option<PathBuf>,
+
+    /// Ignore routing snapshots older than this many seconds
+    #[arg(long, default_value = "3600")]
+    pub routing_snapshot_max_age_secs: u64,
+
+    /// Drop pushed replicas whose coordinates are out of range instead of
+    /// clamping them into range
+    #[arg(long)]
+    pub strict_geo_validation: bool,
+
+    /// Warn and report `routing_table_stale` in health checks when no
+    /// routing table update has arrived for this many seconds (disabled
+    /// when unset)
+    #[arg(long)]
+    pub routing_table_max_age_secs: Option<u64>,
+
+    /// Requests per second allowed per TCP client IP (unlimited when unset)
+    #[arg(long, value_parser = parse_positive_rate)]
+    pub rate_limit: Option<f64>,
+
+    /// Burst size for per-client rate limiting (defaults to the rate)
+    #[arg(long, requires = "rate_limit", value_parser = parse_positive_rate)]
+    pub rate_limit_burst: Option<f64>,
+
+    /// Networks, in CIDR notation, allowed to route on behalf of other
+    /// client IPs, e.g. a load balancer; other TCP and gRPC peers may only
+    /// route for their own address. Comma-separated or repeated; everyone
+    /// is trusted when unset
+    #[arg(long, value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Milliseconds a started request may take to arrive in full (0 disables)
+    #[arg(long, default_value = "5000")]
+    pub request_timeout_ms: u64,
+
+    /// Seconds an idle connection stays open between requests (0 disables);
+    /// clients learn it as `keepalive_secs` from `ping`
+    #[arg(long, default_value = "300")]
+    pub idle_timeout_secs: u64,
+
+    /// Requests served on a connection before closing it, with `close: true`
+    /// on the last response, so clients reconnect and spread over sidecar
+    /// workers (0 is unlimited)
+    #[arg(long, default_value = "0")]
+    pub max_requests_per_connection: u64,
+
+    /// Disable Nagle's algorithm on accepted TCP connections
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub tcp_nodelay: bool,
+
+    /// Idle seconds before TCP keepalive probes start (disabled when unset)
+    #[arg(long)]
+    pub tcp_keepalive_secs: Option<u64>,
+
+    /// Seconds between TCP keepalive probes (OS default when unset)
+    #[arg(long, requires = "tcp_keepalive_secs")]
+    pub tcp_keepalive_interval_secs: Option<u64>,
+
+    /// Set SO_REUSEPORT on the TCP listener so several sidecar processes
+    /// can share the port, with the kernel spreading connections between
+    /// them (Linux only)
+    #[arg(long)]
+    pub reuse_port: bool,
+
+    /// Seconds to wait for active connections to drain on shutdown
+    #[arg(long, default_value = "10")]
+    pub shutdown_grace_secs: u64,
+
+    /// PEM certificate chain; enables TLS on the TCP listener
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM CA bundle; TCP clients must present a certificate signed by it to
+    /// send mutating requests. The Unix socket relies on file permissions.
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Port for the gRPC listener (disabled when unset)
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    pub grpc_port: Option<u16>,
+
+    /// Port for the Prometheus `/metrics` endpoint (disabled when unset)
+    #[cfg(feature = "prometheus")]
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+}
+
+fn parse_socket_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{:?} is not an octal file mode", mode)),
+    }
+}
+
+fn parse_positive_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("{:?} is not a positive number", rate)),
+    }
+}
+
+fn default_route_candidates() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line
+    Json,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -61,19 +321,158 @@ pub struct SidecarRequest {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum SidecarRequestType {
+    /// Version and codec handshake, meant as a connection's first frame
+    #[serde(rename = "hello")]
+    Hello {
+        protocol_version: u32,
+        /// Codec names in order of preference
+        #[serde(default)]
+        supported_codecs: Vec<String>,
+        /// Compression schemes the client can read, in order of preference
+        #[serde(default)]
+        accept_compression: Vec<String>,
+    },
     #[serde(rename = "route")]
     Route {
         client_ip: String,
+        /// Other clients to route from the centroid of, with `client_ip`
+        #[serde(default)]
+        additional_client_ips: Vec<String>,
         query_type: String,
+        #[serde(default)]
+        explain: bool,
+        #[serde(default = "default_route_candidates")]
+        candidates: usize,
+        #[serde(default)]
+        zone_locality: ZoneLocality,
+        #[serde(default)]
+        client_zone: Option<String>,
+        /// Zones to serve from, in order, ahead of the geographic choice
+        #[serde(default)]
+        preferred_zones: Vec<String>,
+        /// Tenant or shard key matched against the affinity rules
+        #[serde(default)]
+        affinity_key: Option<String>,
+        /// Routing time budget; past it the response is marked degraded
+        #[serde(default)]
+        deadline_micros: Option<u64>,
+        /// Ranking strategy overriding the default for this request
+        #[serde(default)]
+        strategy: Option<String>,
     },
+    /// Closest healthy replicas by distance alone, for clients applying
+    /// their own balancing policy
+    #[serde(rename = "nearest_replicas")]
+    NearestReplicas { client_ip: String, n: usize },
+    /// Every replica with the engine's drain, probe and latency state
+    #[serde(rename = "list_replicas")]
+    ListReplicas,
     #[serde(rename = "update_routing_table")]
     UpdateRoutingTable {
         replicas: Vec<ReplicaInfo>,
+        /// Rejected when older than the table's current generation
+        #[serde(default)]
+        generation: Option<u64>,
+    },
+    /// Upsert and remove individual replicas, leaving the rest in place
+    #[serde(rename = "patch_routing_table")]
+    PatchRoutingTable {
+        #[serde(default)]
+        upserts: Vec<ReplicaInfo>,
+        #[serde(default)]
+        removals: Vec<String>,
+    },
+    #[serde(rename = "set_failover_order")]
+    SetFailoverOrder {
+        zone: String,
+        order: Vec<String>,
+    },
+    /// Replace all affinity rules; an empty list clears them
+    #[serde(rename = "set_affinity_rules")]
+    SetAffinityRules {
+        rules: Vec<AffinityRule>,
+    },
+    #[serde(rename = "drain_replica")]
+    DrainReplica {
+        node_id: String,
+    },
+    #[serde(rename = "undrain_replica")]
+    UndrainReplica {
+        node_id: String,
+    },
+    /// Client-observed latency of a request served by `node_id`
+    #[serde(rename = "report_latency")]
+    ReportLatency {
+        node_id: String,
+        latency_micros: u64,
+    },
+    /// A request routed to `node_id` has finished
+    #[serde(rename = "report_done")]
+    ReportDone {
+        node_id: String,
     },
     #[serde(rename = "ping")]
     Ping,
+    #[serde(rename = "health")]
+    Health,
     #[serde(rename = "metrics")]
     GetMetrics,
+    #[serde(rename = "reset_metrics")]
+    ResetMetrics,
+}
+
+impl SidecarRequestType {
+    /// The request's `type` tag, for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SidecarRequestType::Hello { .. } => "hello",
+            SidecarRequestType::Route { .. } => "route",
+            SidecarRequestType::NearestReplicas { .. } => "nearest_replicas",
+            SidecarRequestType::ListReplicas => "list_replicas",
+            SidecarRequestType::UpdateRoutingTable { .. } => "update_routing_table",
+            SidecarRequestType::PatchRoutingTable { .. } => "patch_routing_table",
+            SidecarRequestType::SetFailoverOrder { .. } => "set_failover_order",
+            SidecarRequestType::SetAffinityRules { .. } => "set_affinity_rules",
+            SidecarRequestType::DrainReplica { .. } => "drain_replica",
+            SidecarRequestType::UndrainReplica { .. } => "undrain_replica",
+            SidecarRequestType::ReportLatency { .. } => "report_latency",
+            SidecarRequestType::ReportDone { .. } => "report_done",
+            SidecarRequestType::Ping => "ping",
+            SidecarRequestType::Health => "health",
+            SidecarRequestType::GetMetrics => "metrics",
+            SidecarRequestType::ResetMetrics => "reset_metrics",
+        }
+    }
+
+    /// Requests that change sidecar state and may require authentication
+    ///
+    /// Latency and completion reports only feed routing hints, and come
+    /// from the same read clients as routes, so they aren't gated.
+    pub fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            SidecarRequestType::UpdateRoutingTable { .. }
+                | SidecarRequestType::PatchRoutingTable { .. }
+                | SidecarRequestType::SetFailoverOrder { .. }
+                | SidecarRequestType::SetAffinityRules { .. }
+                | SidecarRequestType::DrainReplica { .. }
+                | SidecarRequestType::UndrainReplica { .. }
+                | SidecarRequestType::ResetMetrics
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthStatus {
+    pub live: bool,
+    pub ready: bool,
+    pub replica_count: usize,
+    pub healthy_replica_count: usize,
+    /// Healthy replicas that also pass the probe and aren't drained
+    pub eligible_replica_count: usize,
+    pub geoip_loaded: bool,
+    pub geoip_database: GeoDatabaseKind,
+    pub routing_table_stale: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -81,7 +480,21 @@ pub struct SidecarResponse {
     pub success: bool,
     pub data: Option<serde_json::Value>,
     pub error: Option<String>,
+    /// Stable machine-readable error identifier, e.g. `NO_HEALTHY_REPLICAS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
     pub timestamp: u64,
+    /// Sent in place of this response when set; see `CompactRoute`
+    #[serde(skip)]
+    pub compact_route: Option<CompactRoute>,
+    /// Set by a successful `hello`: how later responses on the connection
+    /// are compressed
+    #[serde(skip)]
+    pub compression: Option<Compression>,
+    /// The server closes the connection after this response; sent only
+    /// when true
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub close: bool,
 }
 
 impl SidecarResponse {
@@ -90,7 +503,25 @@ impl SidecarResponse {
             success: true,
             data: Some(data),
             error: None,
+            error_code: None,
+            timestamp: current_timestamp_micros(),
+            compact_route: None,
+            compression: None,
+            close: false,
+        }
+    }
+
+    /// Successful route for a client using the compact codec
+    pub fn compact_route(route: CompactRoute) -> Self {
+        Self {
+            success: true,
+            data: None,
+            error: None,
+            error_code: None,
             timestamp: current_timestamp_micros(),
+            compact_route: Some(route),
+            compression: None,
+            close: false,
         }
     }
 
@@ -99,25 +530,113 @@ impl SidecarResponse {
             success: false,
             data: None,
             error: Some(error),
+            error_code: None,
             timestamp: current_timestamp_micros(),
+            compact_route: None,
+            compression: None,
+            close: false,
+        }
+    }
+
+    pub fn error_with_code(error: String, code: &str) -> Self {
+        Self {
+            error_code: Some(code.to_string()),
+            ..Self::error(error)
+        }
+    }
+
+    /// Error response carrying a code when `error` is a `RoutingError`
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<RoutingError>() {
+            Some(routing_error) => Self::error_with_code(error.to_string(), routing_error.code()),
+            None => Self::error(error.to_string()),
         }
     }
 }
 
+/// Numbers Unix socket connections for their connection ids
+static NEXT_UNIX_CONNECTION: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps TCP connection ids unique once anonymized addresses can collide
+static NEXT_TCP_CONNECTION: AtomicU64 = AtomicU64::new(0);
+
 pub struct GeoRouterSidecar {
     args: Args,
     geo_resolver: Arc<GeoResolver>,
-    routing_engine: Arc<RwLock<RoutingEngine>>,
+    routing_engine: Arc<RoutingEngine>,
     metrics: Arc<MetricsCollector>,
     active_connections: Arc<dashmap::DashMap<String, SystemTime>>,
+    connection_limit: Arc<Semaphore>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    trusted_proxies: TrustedProxies,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tls::TlsServer>>,
+    shutdown: watch::Sender<bool>,
 }
 
 impl GeoRouterSidecar {
     pub fn new(args: Args) -> Result<Self> {
-        let geo_resolver = Arc::new(GeoResolver::new(args.geoip_db.clone())?);
-        let routing_engine = Arc::new(RwLock::new(RoutingEngine::new()));
+        let mut geo_resolver = GeoResolver::with_databases(args.geoip_db.clone())?;
+        for path in geo_resolver.database_paths() {
+            info!("Loaded GeoIP database {:?}", path);
+        }
+        if let Some(path) = &args.asn_db {
+            geo_resolver = geo_resolver.with_asn_db(path.clone())?;
+        }
+        let geo_resolver = geo_resolver
+            .with_earth_radius_km(args.earth_radius_km)
+            .with_distance_unit(args.distance_unit)
+            .with_anonymized_ips(args.anonymize_ips);
+        let geo_resolver = Arc::new(geo_resolver);
+        let mut routing_engine = RoutingEngine::new();
+        routing_engine.set_scoring_weights(ScoringWeights {
+            distance_km: args.distance_weight,
+            load_penalty: args.load_penalty_km,
+            latency_weight: args.latency_weight,
+            leader_bonus: args.leader_bonus_km,
+            asn_match_bonus: args.asn_match_bonus_km,
+            read_weight_bonus: args.read_weight_bonus_km,
+            in_flight_penalty: args.in_flight_penalty_km,
+            ignore_unlocated_distance: args.ignore_unlocated_distance,
+        });
+        routing_engine.set_write_forward_margin(args.write_forward_margin_km);
+        if let Some(path) = &args.routing_snapshot {
+            if path.exists() {
+                let max_age = Duration::from_secs(args.routing_snapshot_max_age_secs);
+                if let Err(e) = routing_engine.load_snapshot(path, max_age) {
+                    warn!("Failed to restore routing snapshot: {:#}", e);
+                }
+            }
+            routing_engine.set_snapshot_path(path.clone());
+        }
+        routing_engine.set_strict_geo_validation(args.strict_geo_validation);
+        if args.route_cache_size > 0 {
+            routing_engine.set_route_cache(
+                args.route_cache_size,
+                Duration::from_millis(args.route_cache_ttl_ms),
+            );
+        }
+        if !args.warmup_ips.is_empty() {
+            routing_engine.require_warm_up();
+        }
+        let routing_engine = Arc::new(routing_engine);
         let metrics = Arc::new(MetricsCollector::new());
         let active_connections = Arc::new(DashMap::new());
+        let connection_limit = Arc::new(Semaphore::new(args.max_connections));
+        let rate_limiter = args.rate_limit.map(|rate| {
+            Arc::new(RateLimiter::new(rate, args.rate_limit_burst.unwrap_or(rate)))
+        });
+        let trusted_proxies = TrustedProxies::new(args.trusted_proxies.clone());
+        #[cfg(feature = "tls")]
+        let tls = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => Some(Arc::new(tls::TlsServer::new(
+                cert,
+                key,
+                args.tls_client_ca.as_deref(),
+            )?)),
+            _ => None,
+        };
+        let (shutdown, _) = watch::channel(false);
 
         Ok(Self {
             args,
@@ -125,6 +644,12 @@ impl GeoRouterSidecar {
             routing_engine,
             metrics,
             active_connections,
+            connection_limit,
+            rate_limiter,
+            trusted_proxies,
+            #[cfg(feature = "tls")]
+            tls,
+            shutdown,
         })
     }
 
@@ -134,62 +659,159 @@ impl GeoRouterSidecar {
         // Start both TCP and Unix socket listeners
         let tcp_task = self.start_tcp_listener();
         let unix_task = self.start_unix_listener();
-        let metrics_task = self.start_metrics_collector();
+        let prometheus_task = self.start_prometheus_exporter();
+        let grpc_task = self.start_grpc_listener();
+        let warm_up_task = self.start_warm_up();
+        let probe_task = self.start_health_probes();
+        let decay_task = self.start_percentile_decay();
+        let freshness_task = self.start_freshness_check();
 
-        // Run all tasks concurrently
-        tokio::select! {
-            result = tcp_task => {
-                error!("TCP listener stopped: {:?}", result);
-                result
-            }
-            result = unix_task => {
-                error!("Unix socket listener stopped: {:?}", result);
-                result
-            }
-            result = metrics_task => {
-                error!("Metrics collector stopped: {:?}", result);
-                result
+        // The collector runs beside the tasks below rather than racing them,
+        // so it sees the shutdown and exits after a final report
+        let metrics_task = self.start_metrics_collector(self.shutdown.subscribe());
+        let serve = async {
+            // Run all tasks concurrently; whichever branch wins drops the
+            // listeners, so no new connections are accepted past this point
+            let result = tokio::select! {
+                result = tcp_task => {
+                    error!("TCP listener stopped: {:?}", result);
+                    result
+                }
+                result = unix_task => {
+                    error!("Unix socket listener stopped: {:?}", result);
+                    result
+                }
+                result = prometheus_task => {
+                    error!("Prometheus exporter stopped: {:?}", result);
+                    result
+                }
+                result = grpc_task => {
+                    error!("gRPC listener stopped: {:?}", result);
+                    result
+                }
+                result = warm_up_task => {
+                    error!("Warm-up failed: {:?}", result);
+                    result
+                }
+                result = probe_task => {
+                    error!("Health prober stopped: {:?}", result);
+                    result
+                }
+                result = decay_task => {
+                    error!("Percentile decay stopped: {:?}", result);
+                    result
+                }
+                result = freshness_task => {
+                    error!("Routing table freshness check stopped: {:?}", result);
+                    result
+                }
+                result = shutdown_signal() => {
+                    info!("Shutdown signal received, draining connections");
+                    result
+                }
+            };
+
+            self.shutdown.send_replace(true);
+            self.drain_connections().await;
+            result
+        };
+
+        let (result, metrics_result) = tokio::join!(serve, metrics_task);
+        if let Err(e) = metrics_result {
+            error!("Metrics collector stopped: {:?}", e);
+        }
+
+        if self.args.socket.exists() {
+            if let Err(e) = std::fs::remove_file(&self.args.socket) {
+                warn!("Failed to remove Unix socket {:?}: {}", self.args.socket, e);
             }
         }
+
+        info!("Geo-routing sidecar stopped");
+        result
+    }
+
+    async fn drain_connections(&self) {
+        let deadline = tokio::time::Instant::now()
+            + Duration::from_secs(self.args.shutdown_grace_secs);
+
+        while !self.active_connections.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        if !self.active_connections.is_empty() {
+            warn!(
+                "Grace period elapsed with {} connections still active",
+                self.active_connections.len()
+            );
+        }
     }
 
     async fn start_tcp_listener(&self) -> Result<()> {
         let addr = SocketAddr::from(([127, 0, 0, 1], self.args.port));
-        let listener = TcpListener::bind(addr)
-            .await
+        let listener = self
+            .bind_tcp_listener(addr)
             .context("Failed to bind TCP listener")?;
 
         info!("TCP listener bound to {}", addr);
 
-        loop {
-            let (stream, peer_addr) = listener.accept().await?;
-            
-            // Check connection limit
-            if self.active_connections.len() >= self.args.max_connections {
-                warn!("Connection limit reached, rejecting {}", peer_addr);
-                continue;
+        transport::serve(listener, self.serve_context(), |stream, peer_addr| {
+            let log_addr = privacy::loggable_addr(peer_addr, self.args.anonymize_ips);
+            if let Err(e) = self.configure_tcp_stream(&stream) {
+                warn!("Failed to set socket options for {}: {}", log_addr, e);
             }
 
-            let connection_id = format!("tcp:{}", peer_addr);
-            self.active_connections.insert(connection_id.clone(), SystemTime::now());
+            let id = if self.args.anonymize_ips {
+                format!(
+                    "tcp:{}#{}",
+                    log_addr,
+                    NEXT_TCP_CONNECTION.fetch_add(1, Ordering::Relaxed)
+                )
+            } else {
+                format!("tcp:{}", peer_addr)
+            };
+            let rate_limit = self
+                .rate_limiter
+                .as_ref()
+                .map(|limiter| (Arc::clone(limiter), peer_addr.ip()));
+            let untrusted_peer = self.trusted_proxies.restrict(peer_addr.ip());
 
             let geo_resolver = Arc::clone(&self.geo_resolver);
             let routing_engine = Arc::clone(&self.routing_engine);
             let metrics = Arc::clone(&self.metrics);
-            let active_connections = Arc::clone(&self.active_connections);
+            let shutdown = self.shutdown.subscribe();
+            let max_frame_bytes = self.args.max_frame_bytes;
+            let (idle_timeout, request_timeout) = self.connection_timeouts();
+            let max_requests = self.max_requests_per_connection();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
+
+            let task = async move {
+                #[cfg(feature = "tls")]
+                let (stream, can_mutate) = match tls {
+                    Some(tls) => tls.accept(stream, request_timeout).await?,
+                    None => (Box::new(stream) as Box<dyn tls::Connection>, true),
+                };
+                #[cfg(not(feature = "tls"))]
+                let can_mutate = true;
 
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(
-                    stream,
+                handle_connection(
+                    Framed::new(stream, max_frame_bytes)
+                        .with_timeouts(idle_timeout, request_timeout),
                     geo_resolver,
                     routing_engine,
                     metrics,
-                ).await {
-                    debug!("Connection error for {}: {}", peer_addr, e);
-                }
-                active_connections.remove(&connection_id);
-            });
-        }
+                    shutdown,
+                    rate_limit,
+                    untrusted_peer,
+                    can_mutate,
+                    max_requests,
+                )
+                .await
+            };
+            Session { id, task }
+        })
+        .await
     }
 
     async fn start_unix_listener(&self) -> Result<()> {
@@ -200,149 +822,738 @@ impl GeoRouterSidecar {
 
         let listener = UnixListener::bind(&self.args.socket)
             .context("Failed to bind Unix socket")?;
+        self.restrict_unix_socket()?;
 
         info!("Unix socket listener bound to {:?}", self.args.socket);
 
-        loop {
-            let (stream, _) = listener.accept().await?;
-            
-            // Check connection limit
-            if self.active_connections.len() >= self.args.max_connections {
-                warn!("Connection limit reached, rejecting Unix socket connection");
-                continue;
+        transport::serve(listener, self.serve_context(), |stream, _| {
+            // Unix peers have no address, so number them instead
+            let id = format!(
+                "unix:{}",
+                NEXT_UNIX_CONNECTION.fetch_add(1, Ordering::Relaxed)
+            );
+
+            let (idle_timeout, request_timeout) = self.connection_timeouts();
+            let task = handle_connection(
+                Framed::new(stream, self.args.max_frame_bytes)
+                    .with_timeouts(idle_timeout, request_timeout),
+                Arc::clone(&self.geo_resolver),
+                Arc::clone(&self.routing_engine),
+                Arc::clone(&self.metrics),
+                self.shutdown.subscribe(),
+                None,
+                None,
+                true,
+                self.max_requests_per_connection(),
+            );
+            Session { id, task }
+        })
+        .await
+    }
+
+    /// Apply `--socket-group` and `--socket-mode` before accepting, since
+    /// anyone who can connect can also push routing updates
+    fn restrict_unix_socket(&self) -> Result<()> {
+        let path = &self.args.socket;
+
+        if let Some(group) = &self.args.socket_group {
+            let gid = match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => nix::unistd::Group::from_name(group)
+                    .with_context(|| format!("Failed to look up group {:?}", group))?
+                    .with_context(|| format!("Unknown group {:?}", group))?
+                    .gid
+                    .as_raw(),
+            };
+            std::os::unix::fs::chown(path, None, Some(gid))
+                .with_context(|| format!("Failed to set group of {:?} to {}", path, group))?;
+        }
+
+        if let Some(mode) = self.args.socket_mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to set mode {:o} on {:?}", mode, path))?;
+        }
+        Ok(())
+    }
+
+    fn bind_tcp_listener(&self, addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        // Rebind straight away on restart rather than failing while the
+        // previous process's connections sit in TIME_WAIT
+        socket.set_reuse_address(true)?;
+        if self.args.reuse_port {
+            #[cfg(target_os = "linux")]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(target_os = "linux"))]
+            warn!("--reuse-port is only supported on Linux; ignoring it");
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        // Same backlog as `TcpListener::bind`
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    fn configure_tcp_stream(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.args.tcp_nodelay)?;
+
+        if let Some(idle_secs) = self.args.tcp_keepalive_secs {
+            let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle_secs));
+            if let Some(interval_secs) = self.args.tcp_keepalive_interval_secs {
+                keepalive = keepalive.with_interval(Duration::from_secs(interval_secs));
             }
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    fn connection_timeouts(&self) -> (Option<Duration>, Option<Duration>) {
+        let idle_timeout = (self.args.idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(self.args.idle_timeout_secs));
+        let request_timeout = (self.args.request_timeout_ms > 0)
+            .then(|| Duration::from_millis(self.args.request_timeout_ms));
+        (idle_timeout, request_timeout)
+    }
 
-            let connection_id = format!("unix:{}", current_timestamp_micros());
-            self.active_connections.insert(connection_id.clone(), SystemTime::now());
+    fn max_requests_per_connection(&self) -> Option<u64> {
+        (self.args.max_requests_per_connection > 0).then_some(self.args.max_requests_per_connection)
+    }
+
+    fn serve_context(&self) -> ServeContext {
+        ServeContext {
+            connection_limit: Arc::clone(&self.connection_limit),
+            max_connections: self.args.max_connections,
+            active_connections: Arc::clone(&self.active_connections),
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
 
+    #[cfg(feature = "prometheus")]
+    async fn start_prometheus_exporter(&self) -> Result<()> {
+        match self.args.metrics_port {
+            Some(port) => {
+                let addr = SocketAddr::from(([0, 0, 0, 0], port));
+                prometheus::serve(
+                    addr,
+                    Arc::clone(&self.metrics),
+                    Arc::clone(&self.geo_resolver),
+                    Arc::clone(&self.routing_engine),
+                )
+                .await
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    #[cfg(not(feature = "prometheus"))]
+    async fn start_prometheus_exporter(&self) -> Result<()> {
+        std::future::pending().await
+    }
+
+    #[cfg(feature = "grpc")]
+    async fn start_grpc_listener(&self) -> Result<()> {
+        let Some(port) = self.args.grpc_port else {
+            return std::future::pending().await;
+        };
+
+        // gRPC clients cannot present the certificate that mutations over
+        // TCP require, so don't let this transport bypass that check
+        #[cfg(feature = "tls")]
+        let allow_mutations = self.args.tls_client_ca.is_none();
+        #[cfg(not(feature = "tls"))]
+        let allow_mutations = true;
+
+        let service = grpc::GeoRouterService::new(
+            Arc::clone(&self.geo_resolver),
+            Arc::clone(&self.routing_engine),
+            Arc::clone(&self.metrics),
+            allow_mutations,
+        )
+        .with_trusted_proxies(self.trusted_proxies.clone());
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        grpc::serve(addr, service, self.shutdown.subscribe()).await
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    async fn start_grpc_listener(&self) -> Result<()> {
+        std::future::pending().await
+    }
+
+    /// Runs alongside the listeners so health checks can report not-ready
+    /// while warm-up is in progress
+    async fn start_warm_up(&self) -> Result<()> {
+        if !self.args.warmup_ips.is_empty() {
+            let client_ips = self.args.warmup_ips.clone();
             let geo_resolver = Arc::clone(&self.geo_resolver);
             let routing_engine = Arc::clone(&self.routing_engine);
-            let metrics = Arc::clone(&self.metrics);
-            let active_connections = Arc::clone(&self.active_connections);
+            tokio::task::spawn_blocking(move || {
+                routing_engine.warm_up(&client_ips, &geo_resolver)
+            })
+            .await
+            .context("Warm-up task panicked")?;
+        }
+        std::future::pending().await
+    }
 
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(
-                    stream,
-                    geo_resolver,
-                    routing_engine,
-                    metrics,
-                ).await {
-                    debug!("Unix socket connection error: {}", e);
-                }
-                active_connections.remove(&connection_id);
-            });
+    async fn start_health_probes(&self) -> Result<()> {
+        let Some(interval_ms) = self.args.probe_interval_ms else {
+            return std::future::pending().await;
+        };
+
+        health_probe::run(
+            Arc::clone(&self.routing_engine),
+            Duration::from_millis(interval_ms),
+            Duration::from_millis(self.args.probe_timeout_ms),
+        )
+        .await
+    }
+
+    async fn start_percentile_decay(&self) -> Result<()> {
+        let Some(window_secs) = self.args.percentile_window_secs else {
+            return std::future::pending().await;
+        };
+
+        let window = Duration::from_secs(window_secs.max(1));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + window, window);
+        loop {
+            interval.tick().await;
+            self.metrics.decay_percentiles();
+        }
+    }
+
+    async fn start_freshness_check(&self) -> Result<()> {
+        let Some(max_age_secs) = self.args.routing_table_max_age_secs else {
+            return std::future::pending().await;
+        };
+
+        // Checking several times per max age bounds how late the flag
+        // trips to a fraction of it
+        let max_age = Duration::from_secs(max_age_secs);
+        let mut interval = tokio::time::interval((max_age / 4).max(Duration::from_secs(1)));
+        loop {
+            interval.tick().await;
+            self.routing_engine.check_freshness(max_age);
         }
     }
 
-    async fn start_metrics_collector(&self) -> Result<()> {
+    /// Prune stale connection state, decay in-flight counts and log metrics
+    /// every minute, then log them a final time and return once shutdown
+    /// begins
+    async fn start_metrics_collector(&self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
         
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.wait_for(|&stopping| stopping) => {
+                    self.log_metrics();
+                    return Ok(());
+                }
+            }
             
-            // Clean up old connections
-            let now = SystemTime::now();
-            self.active_connections.retain(|_, &mut connected_at| {
-                now.duration_since(connected_at)
-                    .map_or(false, |d| d.as_secs() < 300) // 5 minutes
-            });
-
-            // Log metrics
-            let metrics = self.metrics.get_snapshot();
-            info!("Metrics: active_connections={}, total_requests={}, avg_latency_us={:.2}", 
-                self.active_connections.len(),
-                metrics.total_requests,
-                metrics.avg_latency_micros
-            );
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.remove_idle(Duration::from_secs(300));
+            }
+            self.routing_engine.decay_in_flight();
+
+            self.log_metrics();
         }
     }
+
+    fn log_metrics(&self) {
+        let metrics = self.metrics.get_snapshot();
+        let geo_stats = self.geo_resolver.resolution_stats();
+        info!("Metrics: active_connections={}, total_requests={}, avg_latency_us={:.2}, p99_latency_us={}, geoip_resolved={}, geoip_no_database={}, geoip_lookup_errors={}, rejected_oversized={}", 
+            self.active_connections.len(),
+            metrics.total_requests,
+            metrics.avg_latency_micros,
+            metrics.p99_micros,
+            geo_stats.resolved,
+            geo_stats.no_database,
+            geo_stats.lookup_errors,
+            metrics.rejected_oversized
+        );
+    }
 }
 
+/// Log a connection's lifetime around `serve_connection`
+///
+/// Runs inside the caller's `connection` span, so every event from the
+/// session carries the connection id.
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection<S>(
-    mut stream: S,
+    framed: Framed<S>,
     geo_resolver: Arc<GeoResolver>,
-    routing_engine: Arc<RwLock<RoutingEngine>>,
+    routing_engine: Arc<RoutingEngine>,
     metrics: Arc<MetricsCollector>,
+    shutdown: watch::Receiver<bool>,
+    rate_limit: Option<(Arc<RateLimiter>, IpAddr)>,
+    untrusted_peer: Option<IpAddr>,
+    can_mutate: bool,
+    max_requests: Option<u64>,
 ) -> Result<()>
 where
-    S: AsyncReadExt + AsyncWriteExt + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut buffer = [0u8; 4];
-    
-    loop {
-        // Read request length
-        stream.read_exact(&mut buffer).await?;
-        let length = u32::from_be_bytes(buffer) as usize;
-        
-        if length > 1024 * 1024 {
-            return Err(anyhow::anyhow!("Request too large: {} bytes", length));
+    info!(can_mutate, "Connection opened");
+    let opened_at = std::time::Instant::now();
+    let mut requests = 0u64;
+
+    let result = serve_connection(
+        framed,
+        geo_resolver,
+        routing_engine,
+        metrics,
+        shutdown,
+        rate_limit,
+        untrusted_peer,
+        can_mutate,
+        max_requests,
+        &mut requests,
+    )
+    .await;
+
+    let reason = match &result {
+        Ok(reason) => reason.as_str(),
+        Err(e) => match e.downcast_ref::<FrameError>() {
+            Some(FrameError::RequestTimeout(_)) => "request_timeout",
+            Some(FrameError::TooLarge { .. }) => "oversized",
+            _ => "error",
+        },
+    };
+    info!(
+        reason,
+        requests,
+        duration_ms = opened_at.elapsed().as_millis() as u64,
+        "Connection closed"
+    );
+    result.map(drop)
+}
+
+/// Why a connection ended without an error
+#[derive(Debug, Clone, Copy)]
+enum CloseReason {
+    Eof,
+    IdleTimeout,
+    Shutdown,
+    MaxRequests,
+}
+
+impl CloseReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Eof => "eof",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::MaxRequests => "max_requests",
         }
+    }
+}
 
-        // Read request data
-        let mut request_data = vec![0u8; length];
-        stream.read_exact(&mut request_data).await?;
+#[allow(clippy::too_many_arguments)]
+async fn serve_connection<S>(
+    mut framed: Framed<S>,
+    geo_resolver: Arc<GeoResolver>,
+    routing_engine: Arc<RoutingEngine>,
+    metrics: Arc<MetricsCollector>,
+    mut shutdown: watch::Receiver<bool>,
+    rate_limit: Option<(Arc<RateLimiter>, IpAddr)>,
+    untrusted_peer: Option<IpAddr>,
+    can_mutate: bool,
+    max_requests: Option<u64>,
+    requests: &mut u64,
+) -> Result<CloseReason>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let keepalive = framed.idle_timeout();
+    let mut compression = Compression::None;
+    loop {
+        // Idle connections close once shutdown begins, while a request
+        // already being processed runs to completion
+        let frame = tokio::select! {
+            frame = framed.read_frame() => frame,
+            _ = shutdown.wait_for(|&stopping| stopping) => {
+                return Ok(CloseReason::Shutdown);
+            }
+        };
+        // Handled outside the select, whose shutdown arm holds a `watch::Ref`
+        // that would make this future `!Send` across the await below
+        let request_data = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(CloseReason::Eof),
+            Err(FrameError::IdleTimeout(timeout)) => {
+                debug!("Closing connection idle for {:?}", timeout);
+                return Ok(CloseReason::IdleTimeout);
+            }
+            Err(FrameError::RequestTimeout(timeout)) => {
+                metrics.record_request(timeout.as_micros() as u64, false);
+                return Err(FrameError::RequestTimeout(timeout).into());
+            }
+            Err(FrameError::TooLarge { size, max }) => {
+                metrics.record_rejected_oversized();
+                if let Err(e) = reject_oversized_frame(&mut framed, size, max).await {
+                    debug!("Failed to send frame-too-large response: {}", e);
+                }
+                return Err(FrameError::TooLarge { size, max }.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         let start_time = std::time::Instant::now();
+        *requests += 1;
+        // `process_request` fills in the type once the request is decoded
+        let span = debug_span!("request", request_type = tracing::field::Empty);
+
+        let (format, payload) = WireFormat::detect(&request_data);
+
+        let rate_limited = rate_limit
+            .as_ref()
+            .is_some_and(|(limiter, ip)| !limiter.check(*ip));
 
         // Process request
-        let response = match process_request(
-            &request_data,
-            &geo_resolver,
-            &routing_engine,
-        ).await {
-            Ok(resp) => resp,
-            Err(e) => SidecarResponse::error(e.to_string()),
+        let mut response = if rate_limited {
+            SidecarResponse::error_with_code("rate limited".to_string(), "RATE_LIMITED")
+        } else {
+            match process_request(
+                payload,
+                format,
+                &geo_resolver,
+                &routing_engine,
+                &metrics,
+                untrusted_peer,
+                can_mutate,
+                keepalive,
+            )
+            .instrument(span.clone())
+            .await
+            {
+                Ok(resp) => resp,
+                Err(e) => SidecarResponse::from_error(&e),
+            }
         };
 
         // Record metrics
         let latency_micros = start_time.elapsed().as_micros() as u64;
         metrics.record_request(latency_micros, response.success);
+        span.in_scope(|| {
+            debug!(
+                latency_us = latency_micros,
+                success = response.success,
+                error_code = response.error_code.as_deref(),
+                "Request handled"
+            )
+        });
+
+        let last_request = max_requests.is_some_and(|max| *requests >= max);
+        if last_request {
+            response.close = true;
+            // The compact layout has no room for the hint
+            if let Some(route) = response.compact_route.take() {
+                response.data = Some(serde_json::to_value(route)?);
+            }
+        }
 
         // Send response
-        let response_data = serde_json::to_vec(&response)?;
-        let response_length = (response_data.len() as u32).to_be_bytes();
-        
-        stream.write_all(&response_length).await?;
-        stream.write_all(&response_data).await?;
-        stream.flush().await?;
+        let response_data = match &response.compact_route {
+            Some(route) => route.encode()?,
+            None => format.encode(&response)?,
+        };
+        let response_data = compression.apply(response_data)?;
+        framed.write_frame(&response_data).await?;
+        // Takes effect after the hello reply
+        if let Some(negotiated) = response.compression {
+            compression = negotiated;
+        }
+        if last_request {
+            return Ok(CloseReason::MaxRequests);
+        }
     }
 }
 
+/// Tell the client why its connection is about to close
+///
+/// The rest of the oversized frame is never read, so the stream can't be
+/// resynchronized; the reply is best-effort JSON since the frame's codec tag
+/// was never seen.
+async fn reject_oversized_frame<S>(framed: &mut Framed<S>, size: usize, max: usize) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    warn!("Rejecting {} byte frame (max {})", size, max);
+
+    let response = SidecarResponse::error_with_code(
+        format!("frame too large: {} bytes (max {})", size, max),
+        "FRAME_TOO_LARGE",
+    );
+    let response_data = WireFormat::JSON.encode(&response)?;
+    framed.write_frame(&response_data).await?;
+    Ok(())
+}
+
+/// Just the tag of a request, to tell an unknown request type apart from
+/// other decoding failures
+#[derive(Deserialize)]
+struct RequestTag {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+fn decode_request(request_data: &[u8], format: WireFormat) -> Result<SidecarRequest, RoutingError> {
+    format.decode(request_data).map_err(|e| {
+        let message = e.to_string();
+        // serde reports an unrecognized tag as an unknown variant; matching
+        // the tag itself rules out unknown variants of nested enums
+        match format.decode::<RequestTag>(request_data) {
+            Ok(RequestTag { kind: Some(kind) })
+                if message.contains(&format!("unknown variant `{}`", kind)) =>
+            {
+                RoutingError::UnknownRequestType(kind)
+            }
+            _ => RoutingError::MalformedRequest(message),
+        }
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_request(
     request_data: &[u8],
+    format: WireFormat,
     geo_resolver: &GeoResolver,
-    routing_engine: &Arc<RwLock<RoutingEngine>>,
+    routing_engine: &Arc<RoutingEngine>,
+    metrics: &MetricsCollector,
+    untrusted_peer: Option<IpAddr>,
+    can_mutate: bool,
+    keepalive: Option<Duration>,
 ) -> Result<SidecarResponse> {
-    let request: SidecarRequest = serde_json::from_slice(request_data)?;
+    let request = decode_request(request_data, format)?;
+    tracing::Span::current().record("request_type", request.inner.kind());
+
+    if request.inner.is_mutating() && !can_mutate {
+        return Ok(SidecarResponse::error_with_code(
+            "unauthorized: mutating requests require a trusted client certificate".to_string(),
+            "UNAUTHORIZED",
+        ));
+    }
 
     match request.inner {
-        SidecarRequestType::Route { client_ip, query_type } => {
+        SidecarRequestType::Hello {
+            protocol_version,
+            supported_codecs,
+            accept_compression,
+        } => {
+            // Newer clients are accepted and expected to step down to ours
+            if protocol_version < MIN_PROTOCOL_VERSION {
+                return Err(RoutingError::UnsupportedProtocolVersion {
+                    requested: protocol_version,
+                    min: MIN_PROTOCOL_VERSION,
+                }
+                .into());
+            }
+            let codec = WireCodec::negotiate(&supported_codecs)
+                .ok_or(RoutingError::NoCommonCodec(supported_codecs))?;
+            let compression = Compression::negotiate(&accept_compression);
+            Ok(SidecarResponse {
+                compression: Some(compression),
+                ..SidecarResponse::success(serde_json::json!({
+                    "protocol_version": PROTOCOL_VERSION,
+                    "min_protocol_version": MIN_PROTOCOL_VERSION,
+                    "codec": codec.as_str(),
+                    "supported_codecs": WireCodec::ALL.map(|codec| codec.as_str()),
+                    "compression": compression.as_str(),
+                    "supported_compression": Compression::SUPPORTED.map(|scheme| scheme.as_str()),
+                }))
+            })
+        }
+
+        SidecarRequestType::Route {
+            client_ip,
+            additional_client_ips,
+            query_type,
+            explain,
+            candidates,
+            zone_locality,
+            client_zone,
+            preferred_zones,
+            affinity_key,
+            deadline_micros,
+            strategy,
+        } => {
+            let parse_ip = |ip: &String| {
+                ip.parse::<IpAddr>()
+                    .map_err(|_| RoutingError::InvalidClientIp(ip.clone()))
+            };
             let routing_request = RoutingRequest {
-                client_ip: client_ip.parse()?,
+                client_ip: parse_ip(&client_ip)?,
+                additional_client_ips: additional_client_ips
+                    .iter()
+                    .map(parse_ip)
+                    .collect::<Result<_, _>>()?,
                 query_type,
                 timestamp: request.timestamp,
+                explain,
+                candidates,
+                zone_locality,
+                client_zone,
+                preferred_zones,
+                affinity_key,
+                deadline_micros,
+                strategy: strategy
+                    .as_deref()
+                    .map(RoutingStrategy::parse)
+                    .transpose()?,
             };
+            trusted_proxy::check_client_ips(
+                untrusted_peer,
+                std::iter::once(&routing_request.client_ip)
+                    .chain(&routing_request.additional_client_ips),
+            )?;
+
+            let start_time = std::time::Instant::now();
+            let result = routing_engine.route_request(&routing_request, geo_resolver);
 
-            let routing_response = routing_engine
-                .read()
-                .route_request(&routing_request, geo_resolver)?;
+            // Failed routes have no selected replica, so bucket them under "none"
+            metrics.record_dimension(
+                QueryType::parse(&routing_request.query_type),
+                result.as_ref().map_or("none", |response| response.zone.as_str()),
+                start_time.elapsed().as_micros() as u64,
+                result.is_ok(),
+            );
+
+            let routing_response = result?;
 
+            if format.codec == WireCodec::Compact {
+                return Ok(SidecarResponse::compact_route(CompactRoute::from(
+                    &routing_response,
+                )));
+            }
             Ok(SidecarResponse::success(serde_json::to_value(routing_response)?))
         }
         
-        SidecarRequestType::UpdateRoutingTable { replicas } => {
-            routing_engine.write().update_replicas(replicas)?;
-            Ok(SidecarResponse::success(serde_json::json!({"updated": true})))
+        SidecarRequestType::NearestReplicas { client_ip, n } => {
+            let client_ip = client_ip
+                .parse::<IpAddr>()
+                .map_err(|_| RoutingError::InvalidClientIp(client_ip.clone()))?;
+            trusted_proxy::check_client_ips(untrusted_peer, [&client_ip])?;
+            let replicas = routing_engine.nearest_replicas(client_ip, n, geo_resolver)?;
+            Ok(SidecarResponse::success(
+                serde_json::json!({"replicas": replicas}),
+            ))
+        }
+
+        SidecarRequestType::ListReplicas => Ok(SidecarResponse::success(
+            serde_json::json!({"replicas": routing_engine.list_replicas()}),
+        )),
+
+        SidecarRequestType::UpdateRoutingTable {
+            replicas,
+            generation,
+        } => {
+            let update = routing_engine.update_replicas_at(replicas, generation)?;
+            Ok(SidecarResponse::success(serde_json::json!({
+                "updated": true,
+                "generation": update.generation,
+                "rejected": update.rejected,
+                "clamped": update.clamped
+            })))
+        }
+
+        SidecarRequestType::PatchRoutingTable { upserts, removals } => {
+            let update = routing_engine.patch_replicas(upserts, removals)?;
+            Ok(SidecarResponse::success(serde_json::json!({
+                "updated": true,
+                "rejected": update.rejected,
+                "clamped": update.clamped
+            })))
         }
         
-        SidecarRequestType::Ping => {
-            Ok(SidecarResponse::success(serde_json::json!({"pong": true})))
+        SidecarRequestType::SetFailoverOrder { zone, order } => {
+            routing_engine.set_failover_order(zone, order);
+            Ok(SidecarResponse::success(serde_json::json!({"updated": true})))
         }
+
+        SidecarRequestType::SetAffinityRules { rules } => {
+            routing_engine.set_affinity_rules(rules);
+            Ok(SidecarResponse::success(serde_json::json!({"updated": true})))
+        }
+
+        SidecarRequestType::DrainReplica { node_id } => {
+            routing_engine.drain_replica(&node_id)?;
+            Ok(SidecarResponse::success(serde_json::json!({"drained": true})))
+        }
+
+        SidecarRequestType::UndrainReplica { node_id } => {
+            routing_engine.undrain_replica(&node_id)?;
+            Ok(SidecarResponse::success(serde_json::json!({"drained": false})))
+        }
+
+        SidecarRequestType::ReportLatency {
+            node_id,
+            latency_micros,
+        } => {
+            routing_engine.report_latency(&node_id, latency_micros)?;
+            Ok(SidecarResponse::success(serde_json::json!({
+                "latency_ewma_ms": routing_engine.latency_ewma_ms(&node_id)
+            })))
+        }
+
+        SidecarRequestType::ReportDone { node_id } => {
+            routing_engine.report_done(&node_id)?;
+            Ok(SidecarResponse::success(serde_json::json!({
+                "in_flight": routing_engine.in_flight_count(&node_id)
+            })))
+        }
+
+        // Clients pooling connections should reuse one idle for less than
+        // `keepalive_secs`, or ping within it; null means it never expires
+        SidecarRequestType::Ping => Ok(SidecarResponse::success(serde_json::json!({
+            "pong": true,
+            "keepalive_secs": keepalive.map(|timeout| timeout.as_secs())
+        }))),
         
+        SidecarRequestType::Health => {
+            let replica_count = routing_engine.get_replica_count();
+            let healthy_replica_count = routing_engine.get_healthy_replica_count();
+            let eligible_replica_count = routing_engine.get_eligible_replica_count();
+
+            // Ready once there is somewhere to route, a configured GeoIP
+            // database actually loaded and any startup warm-up has finished
+            let ready = eligible_replica_count > 0
+                && geo_resolver.is_ready()
+                && routing_engine.is_warmed_up();
+
+            let health = HealthStatus {
+                live: true,
+                ready,
+                replica_count,
+                healthy_replica_count,
+                eligible_replica_count,
+                geoip_loaded: geo_resolver.is_loaded(),
+                geoip_database: geo_resolver.database_kind(),
+                routing_table_stale: routing_engine.is_table_stale(),
+            };
+            Ok(SidecarResponse::success(serde_json::to_value(health)?))
+        }
+
         SidecarRequestType::GetMetrics => {
-            // Return current metrics
-            Ok(SidecarResponse::success(serde_json::json!({"metrics": "todo"})))
+            let snapshot = metrics.get_snapshot();
+            Ok(SidecarResponse::success(serde_json::to_value(snapshot)?))
         }
+
+        SidecarRequestType::ResetMetrics => {
+            // Hand back the final numbers of the window being closed
+            let snapshot = metrics.snapshot_and_reset();
+            Ok(SidecarResponse::success(serde_json::to_value(snapshot)?))
+        }
+    }
+}
+
+async fn shutdown_signal() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
+
+    tokio::select! {
+        _ = sigterm.recv() => Ok(()),
+        result = tokio::signal::ctrl_c() => result.context("Failed to listen for SIGINT"),
     }
 }
 
@@ -353,7 +1564,7 @@ fn current_timestamp_micros() -> u64 {
         .as_micros() as u64
 }
 
-fn init_tracing(level: &str) -> Result<()> {
+fn init_tracing(level: &str, format: LogFormat) -> Result<()> {
     let level = match level.to_lowercase().as_str() {
         "trace" => tracing::Level::TRACE,
         "debug" => tracing::Level::DEBUG,
@@ -363,23 +1574,153 @@ fn init_tracing(level: &str) -> Result<()> {
         _ => tracing::Level::INFO,
     };
 
-    tracing_subscriber::fmt()
+    let builder = tracing_subscriber::fmt()
         .with_max_level(level)
         .with_target(false)
-        .with_thread_ids(true)
-        .init();
+        .with_thread_ids(true);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        // Keep the target so aggregated logs can be filtered by module
+        LogFormat::Json => builder.json().with_target(true).init(),
+    }
 
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let (args, unknown_config_keys) = config::parse_args()?;
     
-    init_tracing(&args.log_level)?;
+    init_tracing(&args.log_level, args.log_format)?;
+
+    for key in unknown_config_keys {
+        warn!("Ignoring unknown config key {:?}", key);
+    }
     
     info!("Starting pyHMSSQL geo-routing sidecar v{}", env!("CARGO_PKG_VERSION"));
     
     let sidecar = GeoRouterSidecar::new(args)?;
     sidecar.run().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn process_unauthenticated(
+        request: serde_json::Value,
+        routing_engine: &Arc<RoutingEngine>,
+    ) -> SidecarResponse {
+        process_request(
+            request.to_string().as_bytes(),
+            WireFormat::JSON,
+            &GeoResolver::new(None).unwrap(),
+            routing_engine,
+            &MetricsCollector::new(),
+            None,
+            false,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn replica(node_id: &str) -> ReplicaInfo {
+        serde_json::from_value(serde_json::json!({
+            "node_id": node_id,
+            "host": "127.0.0.1",
+            "port": 9000,
+            "is_leader": false,
+            "healthy": true,
+            "zone": "us-east",
+            "geo_location": {
+                "country": "Unknown",
+                "region": "Unknown",
+                "city": "Unknown",
+                "latitude": 0.0,
+                "longitude": 0.0,
+                "timezone": "UTC"
+            },
+            "load_score": 0.0,
+            "latency_ms": 0.0
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_clients_without_a_certificate_can_report_done() {
+        let routing_engine = Arc::new(RoutingEngine::new());
+        routing_engine.update_replicas(vec![replica("a")]).unwrap();
+        routing_engine.set_scoring_weights(ScoringWeights {
+            in_flight_penalty: 50.0,
+            ..ScoringWeights::default()
+        });
+
+        let route = serde_json::json!({
+            "type": "route",
+            "client_ip": "10.0.0.1",
+            "query_type": "read",
+            "timestamp": 0
+        });
+        let response = process_unauthenticated(route, &routing_engine).await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(routing_engine.in_flight_count("a"), 1);
+
+        for report in [
+            serde_json::json!({
+                "type": "report_latency",
+                "node_id": "a",
+                "latency_micros": 800,
+                "timestamp": 0
+            }),
+            serde_json::json!({"type": "report_done", "node_id": "a", "timestamp": 0}),
+        ] {
+            let response = process_unauthenticated(report, &routing_engine).await;
+            assert!(response.success, "{:?}", response.error);
+        }
+        assert_eq!(routing_engine.in_flight_count("a"), 0);
+
+        // Changing the table still takes a certificate
+        let drain = serde_json::json!({"type": "drain_replica", "node_id": "a", "timestamp": 0});
+        let response = process_unauthenticated(drain, &routing_engine).await;
+        assert_eq!(response.error_code.as_deref(), Some("UNAUTHORIZED"));
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_without_an_eligible_replica() {
+        let routing_engine = Arc::new(RoutingEngine::new());
+        routing_engine
+            .update_replicas(vec![replica("a"), replica("b")])
+            .unwrap();
+        let health = serde_json::json!({"type": "health", "timestamp": 0});
+
+        let response = process_unauthenticated(health.clone(), &routing_engine).await;
+        assert_eq!(response.data.unwrap()["ready"], true);
+
+        // Still reported healthy, but every route would fail
+        routing_engine.set_probe_result("a", false);
+        routing_engine.set_probe_result("b", false);
+        let data = process_unauthenticated(health.clone(), &routing_engine)
+            .await
+            .data
+            .unwrap();
+        assert_eq!(data["ready"], false);
+        assert_eq!(data["healthy_replica_count"], 2);
+        assert_eq!(data["eligible_replica_count"], 0);
+
+        // Likewise with every replica drained
+        routing_engine.set_probe_result("a", true);
+        routing_engine.set_probe_result("b", true);
+        let response = process_unauthenticated(health.clone(), &routing_engine).await;
+        assert_eq!(response.data.unwrap()["ready"], true);
+        routing_engine.drain_replica("a").unwrap();
+        routing_engine.drain_replica("b").unwrap();
+        let data = process_unauthenticated(health, &routing_engine)
+            .await
+            .data
+            .unwrap();
+        assert_eq!(data["ready"], false);
+        assert_eq!(data["eligible_replica_count"], 0);
+    }
+}
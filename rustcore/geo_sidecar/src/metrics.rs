@@ -1,15 +1,231 @@
 //! Performance metrics collection
 
-use std::sync::atomic::{AtomicU64, Ordering};
+use crate::routing::QueryType;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-#[derive(Debug, Clone)]
+/// Number of bits of sub-bucket precision; bounds relative error to 1/16
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+const BUCKET_COUNT: usize = SUB_BUCKET_COUNT * (64 - SUB_BUCKET_BITS as usize + 1);
+
+/// Shards per breakdown counter; threads are spread across them round-robin
+const COUNTER_SHARDS: usize = 16;
+
+static NEXT_COUNTER_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTER_SHARD: usize = NEXT_COUNTER_SHARD.fetch_add(1, Ordering::Relaxed) % COUNTER_SHARDS;
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct MetricsSnapshot {
     pub total_requests: u64,
     pub successful_requests: u64,
     pub failed_requests: u64,
+    pub total_latency_micros: u64,
     pub avg_latency_micros: f64,
     pub min_latency_micros: u64,
     pub max_latency_micros: u64,
+    /// Percentiles weight samples by age when a percentile window is set;
+    /// see `MetricsCollector::decay_percentiles`
+    pub p50_micros: u64,
+    pub p95_micros: u64,
+    pub p99_micros: u64,
+    /// Frames refused for exceeding the size cap, never parsed as requests
+    pub rejected_oversized: u64,
+    /// Connections that found every slot taken and were held in the listen
+    /// backlog until one freed; accepting pauses rather than refusing them
+    pub connections_rejected: u64,
+    /// Connections currently open; a gauge, so resets leave it alone
+    pub current_active_connections: u64,
+    pub breakdown: Vec<DimensionSnapshot>,
+}
+
+/// Stats for a single `(QueryType, zone)` pair
+#[derive(Debug, Clone, Serialize)]
+pub struct DimensionSnapshot {
+    pub query_type: QueryType,
+    pub zone: String,
+    pub total_requests: u64,
+    pub successful_requests: u64,
+    pub failed_requests: u64,
+    pub avg_latency_micros: f64,
+    pub p50_micros: u64,
+    pub p95_micros: u64,
+    pub p99_micros: u64,
+}
+
+/// Counter split across cache-line padded shards to avoid contention
+#[derive(Default)]
+struct ShardedCounter {
+    shards: [PaddedCounter; COUNTER_SHARDS],
+}
+
+#[derive(Default)]
+#[repr(align(64))]
+struct PaddedCounter(AtomicU64);
+
+impl ShardedCounter {
+    fn add(&self, value: u64) {
+        let shard = COUNTER_SHARD.with(|shard| *shard);
+        self.shards[shard].0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn sum(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.0.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+#[derive(Default)]
+struct DimensionStats {
+    successful_requests: ShardedCounter,
+    failed_requests: ShardedCounter,
+    total_latency_micros: ShardedCounter,
+    latency_histogram: LatencyHistogram,
+}
+
+impl DimensionStats {
+    fn record(&self, latency_micros: u64, success: bool) {
+        if success {
+            self.successful_requests.add(1);
+        } else {
+            self.failed_requests.add(1);
+        }
+        self.total_latency_micros.add(latency_micros);
+        self.latency_histogram.record(latency_micros);
+    }
+
+    fn snapshot(&self, query_type: QueryType, zone: &str) -> DimensionSnapshot {
+        let successful_requests = self.successful_requests.sum();
+        let failed_requests = self.failed_requests.sum();
+        let total_requests = successful_requests + failed_requests;
+        let avg_latency_micros = if total_requests > 0 {
+            self.total_latency_micros.sum() as f64 / total_requests as f64
+        } else {
+            0.0
+        };
+
+        DimensionSnapshot {
+            query_type,
+            zone: zone.to_string(),
+            total_requests,
+            successful_requests,
+            failed_requests,
+            avg_latency_micros,
+            p50_micros: self.latency_histogram.value_at_quantile(0.50),
+            p95_micros: self.latency_histogram.value_at_quantile(0.95),
+            p99_micros: self.latency_histogram.value_at_quantile(0.99),
+        }
+    }
+}
+
+/// Lock-free log-linear latency histogram (HDR-style)
+///
+/// Values below 16 are recorded exactly; larger values land in one of 16
+/// linear sub-buckets per power of two.
+pub struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: (0..BUCKET_COUNT).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.buckets[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Highest value equivalent to the given quantile (0.0..=1.0)
+    pub fn value_at_quantile(&self, quantile: f64) -> u64 {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+
+        let rank = ((quantile.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut cumulative = 0;
+        for (index, count) in counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                return bucket_upper_bound(index);
+            }
+        }
+
+        bucket_upper_bound(BUCKET_COUNT - 1)
+    }
+
+    /// Number of samples in buckets lying entirely at or below `value`
+    pub fn count_at_or_below(&self, value: u64) -> u64 {
+        self.buckets
+            .iter()
+            .enumerate()
+            .take_while(|(index, _)| bucket_upper_bound(*index) <= value)
+            .map(|(_, bucket)| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Halve every bucket, rounding down
+    pub fn decay(&self) {
+        for bucket in self.buckets.iter() {
+            let _ = bucket.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                Some(count / 2)
+            });
+        }
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKET_COUNT as u64 {
+        return value as usize;
+    }
+
+    let magnitude = 63 - value.leading_zeros();
+    let shift = magnitude - SUB_BUCKET_BITS;
+    let sub_bucket = ((value >> shift) as usize) - SUB_BUCKET_COUNT;
+    SUB_BUCKET_COUNT * (shift as usize + 1) + sub_bucket
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKET_COUNT {
+        return index as u64;
+    }
+
+    let shift = (index / SUB_BUCKET_COUNT - 1) as u32;
+    let sub_bucket = (index % SUB_BUCKET_COUNT) as u64;
+    let lower = (SUB_BUCKET_COUNT as u64 + sub_bucket) << shift;
+    lower + ((1u64 << shift) - 1)
 }
 
 pub struct MetricsCollector {
@@ -19,6 +235,20 @@ pub struct MetricsCollector {
     total_latency_micros: AtomicU64,
     min_latency_micros: AtomicU64,
     max_latency_micros: AtomicU64,
+    latency_histogram: LatencyHistogram,
+    // Same samples as `latency_histogram`, but decayed for percentiles
+    recent_latency: LatencyHistogram,
+    rejected_oversized: AtomicU64,
+    connections_rejected: AtomicU64,
+    active_connections: AtomicU64,
+    // Keyed by zone, then indexed by `QueryType` so lookups don't allocate
+    dimensions: DashMap<String, [DimensionStats; 2]>,
+}
+
+impl Default for MetricsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MetricsCollector {
@@ -30,12 +260,38 @@ impl MetricsCollector {
             total_latency_micros: AtomicU64::new(0),
             min_latency_micros: AtomicU64::new(u64::MAX),
             max_latency_micros: AtomicU64::new(0),
+            latency_histogram: LatencyHistogram::new(),
+            recent_latency: LatencyHistogram::new(),
+            rejected_oversized: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            dimensions: DashMap::new(),
+        }
+    }
+
+    /// Record a routing outcome under its `(QueryType, zone)` breakdown
+    pub fn record_dimension(
+        &self,
+        query_type: QueryType,
+        zone: &str,
+        latency_micros: u64,
+        success: bool,
+    ) {
+        if let Some(stats) = self.dimensions.get(zone) {
+            stats[query_type as usize].record(latency_micros, success);
+            return;
         }
+
+        self.dimensions.entry(zone.to_string()).or_default()[query_type as usize]
+            .record(latency_micros, success);
     }
 
     pub fn record_request(&self, latency_micros: u64, success: bool) {
         self.total_requests.fetch_add(1, Ordering::Relaxed);
-        self.total_latency_micros.fetch_add(latency_micros, Ordering::Relaxed);
+        self.total_latency_micros
+            .fetch_add(latency_micros, Ordering::Relaxed);
+        self.latency_histogram.record(latency_micros);
+        self.recent_latency.record(latency_micros);
 
         if success {
             self.successful_requests.fetch_add(1, Ordering::Relaxed);
@@ -71,6 +327,24 @@ impl MetricsCollector {
         }
     }
 
+    /// Count a frame refused for exceeding the size cap
+    ///
+    /// Kept apart from request counts since the frame was never parsed.
+    pub fn record_rejected_oversized(&self) {
+        self.rejected_oversized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection that queued in the listen backlog for a free slot
+    pub fn record_connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update the open connection gauge
+    pub fn set_active_connections(&self, count: usize) {
+        self.active_connections
+            .store(count as u64, Ordering::Relaxed);
+    }
+
     pub fn get_snapshot(&self) -> MetricsSnapshot {
         let total_requests = self.total_requests.load(Ordering::Relaxed);
         let successful_requests = self.successful_requests.load(Ordering::Relaxed);
@@ -91,16 +365,79 @@ impl MetricsCollector {
             min_latency_micros
         };
 
+        // Bucket bounds can overshoot the largest observed value
+        let percentile = |quantile: f64| {
+            self.recent_latency
+                .value_at_quantile(quantile)
+                .min(max_latency_micros)
+        };
+
+        let mut breakdown: Vec<DimensionSnapshot> = self
+            .dimensions
+            .iter()
+            .flat_map(|entry| {
+                QueryType::ALL
+                    .iter()
+                    .map(|&query_type| {
+                        entry.value()[query_type as usize].snapshot(query_type, entry.key())
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|dimension| dimension.total_requests > 0)
+            .collect();
+        breakdown.sort_by(|a, b| {
+            (a.zone.as_str(), a.query_type.as_str()).cmp(&(b.zone.as_str(), b.query_type.as_str()))
+        });
+
         MetricsSnapshot {
             total_requests,
             successful_requests,
             failed_requests,
+            total_latency_micros,
             avg_latency_micros,
             min_latency_micros,
             max_latency_micros,
+            p50_micros: percentile(0.50),
+            p95_micros: percentile(0.95),
+            p99_micros: percentile(0.99),
+            rejected_oversized: self.rejected_oversized.load(Ordering::Relaxed),
+            connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+            current_active_connections: self.active_connections.load(Ordering::Relaxed),
+            breakdown,
         }
     }
 
+    /// Cumulative since the last reset, unaffected by `decay_percentiles`
+    pub fn latency_histogram(&self) -> &LatencyHistogram {
+        &self.latency_histogram
+    }
+
+    /// Halve the weight of every latency sample seen so far in the reported
+    /// percentiles, overall and per breakdown
+    ///
+    /// Called once per window, a sample counts half as much for each window
+    /// that has passed since it was recorded, so a spike stops dominating
+    /// p99 within a few windows instead of for the whole uptime. Request
+    /// counts, averages, min/max and the Prometheus histogram stay
+    /// cumulative since the last reset.
+    pub fn decay_percentiles(&self) {
+        self.recent_latency.decay();
+        for entry in self.dimensions.iter() {
+            for stats in entry.value() {
+                stats.latency_histogram.decay();
+            }
+        }
+    }
+
+    /// Snapshot, then reset, so timed runs can capture their final numbers
+    ///
+    /// Requests completing while this runs may be dropped from both windows.
+    pub fn snapshot_and_reset(&self) -> MetricsSnapshot {
+        let snapshot = self.get_snapshot();
+        self.reset();
+        snapshot
+    }
+
     pub fn reset(&self) {
         self.total_requests.store(0, Ordering::Relaxed);
         self.successful_requests.store(0, Ordering::Relaxed);
@@ -108,5 +445,137 @@ impl MetricsCollector {
         self.total_latency_micros.store(0, Ordering::Relaxed);
         self.min_latency_micros.store(u64::MAX, Ordering::Relaxed);
         self.max_latency_micros.store(0, Ordering::Relaxed);
+        self.latency_histogram.reset();
+        self.recent_latency.reset();
+        self.rejected_oversized.store(0, Ordering::Relaxed);
+        self.connections_rejected.store(0, Ordering::Relaxed);
+        self.dimensions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_bounds_round_trip() {
+        for value in [0, 1, 15, 16, 17, 31, 32, 1_000, 123_456, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(index < BUCKET_COUNT);
+            assert!(bucket_upper_bound(index) >= value);
+        }
+    }
+
+    #[test]
+    fn test_percentiles_uniform_distribution() {
+        let metrics = MetricsCollector::new();
+        for latency in 1..=10_000 {
+            metrics.record_request(latency, true);
+        }
+
+        let snapshot = metrics.get_snapshot();
+        let within = |actual: u64, expected: u64| {
+            let error = actual.abs_diff(expected) as f64 / expected as f64;
+            assert!(
+                error <= 1.0 / 16.0,
+                "{} not within 1/16 of {}",
+                actual,
+                expected
+            );
+        };
+
+        within(snapshot.p50_micros, 5_000);
+        within(snapshot.p95_micros, 9_500);
+        within(snapshot.p99_micros, 9_900);
+    }
+
+    #[test]
+    fn test_percentiles_tail_distribution() {
+        let metrics = MetricsCollector::new();
+        for _ in 0..90 {
+            metrics.record_request(10, true);
+        }
+        for _ in 0..9 {
+            metrics.record_request(12, true);
+        }
+        metrics.record_request(15, false);
+
+        let snapshot = metrics.get_snapshot();
+        assert_eq!(snapshot.p50_micros, 10);
+        assert_eq!(snapshot.p95_micros, 12);
+        assert_eq!(snapshot.p99_micros, 12);
+
+        metrics.reset();
+        assert_eq!(metrics.get_snapshot().p99_micros, 0);
+    }
+
+    #[test]
+    fn test_decay_lets_old_spikes_age_out_of_percentiles() {
+        let metrics = MetricsCollector::new();
+        for _ in 0..10 {
+            metrics.record_request(5_000, true);
+        }
+        metrics.record_dimension(QueryType::Read, "us-east", 5_000, true);
+        assert!(metrics.get_snapshot().p99_micros >= 5_000);
+
+        for _ in 0..4 {
+            metrics.decay_percentiles();
+        }
+        for _ in 0..100 {
+            metrics.record_request(10, true);
+            metrics.record_dimension(QueryType::Read, "us-east", 10, true);
+        }
+
+        let snapshot = metrics.get_snapshot();
+        assert_eq!(snapshot.p99_micros, 10);
+        assert_eq!(snapshot.breakdown[0].p99_micros, 10);
+        assert_eq!(snapshot.max_latency_micros, 5_000);
+        assert_eq!(metrics.latency_histogram().count(), 110);
+    }
+
+    #[test]
+    fn test_breakdown_by_query_type_and_zone() {
+        let metrics = MetricsCollector::new();
+        metrics.record_dimension(QueryType::Read, "us-east", 10, true);
+        metrics.record_dimension(QueryType::Read, "us-east", 30, true);
+        metrics.record_dimension(QueryType::Write, "us-east", 12, false);
+        metrics.record_dimension(QueryType::Write, "eu-west", 500, true);
+
+        let breakdown = metrics.get_snapshot().breakdown;
+        assert_eq!(breakdown.len(), 3);
+
+        assert_eq!(breakdown[0].zone, "eu-west");
+        assert_eq!(breakdown[0].query_type, QueryType::Write);
+        assert_eq!(breakdown[0].total_requests, 1);
+
+        assert_eq!(breakdown[1].zone, "us-east");
+        assert_eq!(breakdown[1].query_type, QueryType::Read);
+        assert_eq!(breakdown[1].successful_requests, 2);
+        assert_eq!(breakdown[1].avg_latency_micros, 20.0);
+
+        assert_eq!(breakdown[2].query_type, QueryType::Write);
+        assert_eq!(breakdown[2].failed_requests, 1);
+
+        metrics.reset();
+        assert!(metrics.get_snapshot().breakdown.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_and_reset_returns_final_window() {
+        let metrics = MetricsCollector::new();
+        metrics.record_request(40, true);
+        metrics.record_request(60, false);
+        metrics.record_rejected_oversized();
+
+        let snapshot = metrics.snapshot_and_reset();
+        assert_eq!(snapshot.total_requests, 2);
+        assert_eq!(snapshot.failed_requests, 1);
+        assert_eq!(snapshot.max_latency_micros, 60);
+        assert_eq!(snapshot.rejected_oversized, 1);
+
+        let after = metrics.get_snapshot();
+        assert_eq!(after.total_requests, 0);
+        assert_eq!(after.p99_micros, 0);
+        assert_eq!(after.rejected_oversized, 0);
     }
 }
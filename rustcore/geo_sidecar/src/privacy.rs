@@ -0,0 +1,54 @@
+//! Client IP masking for logs
+//!
+//! With `--anonymize-ips`, client addresses are truncated before they reach
+//! log lines or connection ids. Geo resolution, rate limiting and the route
+//! cache still see the full address, so routing is unaffected.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Zero the last octet of an IPv4 address or the last 80 bits of an IPv6
+/// one
+pub fn anonymize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1))),
+    }
+}
+
+/// `ip` as it may appear in logs
+pub fn loggable_ip(ip: IpAddr, anonymize_ips: bool) -> IpAddr {
+    if anonymize_ips {
+        anonymize(ip)
+    } else {
+        ip
+    }
+}
+
+/// `addr` as it may appear in logs; the port is kept
+pub fn loggable_addr(addr: SocketAddr, anonymize_ips: bool) -> SocketAddr {
+    SocketAddr::new(loggable_ip(addr.ip(), anonymize_ips), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anonymize_masks_host_bits() {
+        let v4: IpAddr = "203.0.113.77".parse().unwrap();
+        assert_eq!(anonymize(v4), "203.0.113.0".parse::<IpAddr>().unwrap());
+
+        let v6: IpAddr = "2001:db8:1234:5678:9abc:def0:1234:5678".parse().unwrap();
+        assert_eq!(anonymize(v6), "2001:db8:1234::".parse::<IpAddr>().unwrap());
+
+        let addr: SocketAddr = "203.0.113.77:5432".parse().unwrap();
+        assert_eq!(
+            loggable_addr(addr, true),
+            "203.0.113.0:5432".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(loggable_addr(addr, false), addr);
+    }
+}
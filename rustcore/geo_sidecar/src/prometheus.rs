@@ -0,0 +1,400 @@
+//! Prometheus text exposition endpoint
+
+use crate::geo::{GeoResolutionStats, GeoResolver};
+use crate::metrics::MetricsCollector;
+use crate::routing::{ReplicaState, RoutingEngine};
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Histogram bucket bounds in microseconds
+const LATENCY_BUCKETS_MICROS: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000,
+];
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Render all collector metrics in Prometheus text format (version 0.0.4)
+pub fn render(
+    metrics: &MetricsCollector,
+    geo_stats: &GeoResolutionStats,
+    replicas: &[ReplicaState],
+) -> String {
+    let snapshot = metrics.get_snapshot();
+    let histogram = metrics.latency_histogram();
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP geo_router_requests_total Total routing requests processed."
+    );
+    let _ = writeln!(out, "# TYPE geo_router_requests_total counter");
+    let _ = writeln!(
+        out,
+        "geo_router_requests_total{{outcome=\"success\"}} {}",
+        snapshot.successful_requests
+    );
+    let _ = writeln!(
+        out,
+        "geo_router_requests_total{{outcome=\"failure\"}} {}",
+        snapshot.failed_requests
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP geo_router_rejected_oversized_frames_total Frames refused for exceeding the size cap."
+    );
+    let _ = writeln!(out, "# TYPE geo_router_rejected_oversized_frames_total counter");
+    let _ = writeln!(
+        out,
+        "geo_router_rejected_oversized_frames_total {}",
+        snapshot.rejected_oversized
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP geo_router_connections_rejected_total Connections held in the listen backlog until a slot freed under max_connections."
+    );
+    let _ = writeln!(out, "# TYPE geo_router_connections_rejected_total counter");
+    let _ = writeln!(
+        out,
+        "geo_router_connections_rejected_total {}",
+        snapshot.connections_rejected
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP geo_router_active_connections Connections currently open."
+    );
+    let _ = writeln!(out, "# TYPE geo_router_active_connections gauge");
+    let _ = writeln!(
+        out,
+        "geo_router_active_connections {}",
+        snapshot.current_active_connections
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP geo_router_request_duration_seconds Request processing latency."
+    );
+    let _ = writeln!(out, "# TYPE geo_router_request_duration_seconds histogram");
+    for bound in LATENCY_BUCKETS_MICROS {
+        let _ = writeln!(
+            out,
+            "geo_router_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            micros_to_seconds(bound),
+            histogram.count_at_or_below(bound)
+        );
+    }
+    let _ = writeln!(
+        out,
+        "geo_router_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        snapshot.total_requests
+    );
+    let _ = writeln!(
+        out,
+        "geo_router_request_duration_seconds_sum {}",
+        micros_to_seconds(snapshot.total_latency_micros)
+    );
+    let _ = writeln!(
+        out,
+        "geo_router_request_duration_seconds_count {}",
+        snapshot.total_requests
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP geo_router_route_requests_total Route requests by query type and zone."
+    );
+    let _ = writeln!(out, "# TYPE geo_router_route_requests_total counter");
+    for dimension in &snapshot.breakdown {
+        for (outcome, count) in [
+            ("success", dimension.successful_requests),
+            ("failure", dimension.failed_requests),
+        ] {
+            let _ = writeln!(
+                out,
+                "geo_router_route_requests_total{{query_type=\"{}\",zone=\"{}\",outcome=\"{}\"}} {}",
+                dimension.query_type.as_str(),
+                escape_label(&dimension.zone),
+                outcome,
+                count
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP geo_router_geoip_resolutions_total Client IP resolutions by outcome."
+    );
+    let _ = writeln!(out, "# TYPE geo_router_geoip_resolutions_total counter");
+    for (outcome, count) in [
+        ("resolved", geo_stats.resolved),
+        ("no_database", geo_stats.no_database),
+        ("lookup_error", geo_stats.lookup_errors),
+    ] {
+        let _ = writeln!(
+            out,
+            "geo_router_geoip_resolutions_total{{outcome=\"{}\"}} {}",
+            outcome, count
+        );
+    }
+
+    render_replicas(&mut out, replicas);
+    out
+}
+
+/// One per-replica metric family
+struct ReplicaFamily {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&ReplicaState) -> f64,
+}
+
+/// Per-replica series, labelled by node id and zone
+///
+/// Series disappear with their replica; a replica rejoining the table starts
+/// its selection counter from zero again.
+fn render_replicas(out: &mut String, replicas: &[ReplicaState]) {
+    let families = [
+        ReplicaFamily {
+            name: "geo_router_replica_selections_total",
+            kind: "counter",
+            help: "Route requests answered with the replica.",
+            value: |state| state.selections as f64,
+        },
+        ReplicaFamily {
+            name: "geo_router_replica_load",
+            kind: "gauge",
+            help: "Load the control plane last reported for the replica.",
+            value: |state| state.replica.load_score,
+        },
+        ReplicaFamily {
+            name: "geo_router_replica_latency_milliseconds",
+            kind: "gauge",
+            help: "Latency used to score the replica: the reported average, else the static value.",
+            value: |state| state.latency_ewma_ms.unwrap_or(state.replica.latency_ms),
+        },
+        ReplicaFamily {
+            name: "geo_router_replica_eligible",
+            kind: "gauge",
+            help: "1 if the replica is healthy, probe-reachable and not drained.",
+            value: |state| if state.eligible { 1.0 } else { 0.0 },
+        },
+    ];
+
+    for family in families {
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+        for state in replicas {
+            let _ = writeln!(
+                out,
+                "{}{{node_id=\"{}\",zone=\"{}\"}} {}",
+                family.name,
+                escape_label(&state.replica.node_id),
+                escape_label(&state.replica.zone),
+                (family.value)(state)
+            );
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `/metrics` until the listener fails
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: Arc<MetricsCollector>,
+    geo_resolver: Arc<GeoResolver>,
+    routing_engine: Arc<RoutingEngine>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .context("Failed to bind Prometheus metrics listener")?;
+
+    tracing::info!(
+        "Prometheus metrics endpoint bound to http://{}/metrics",
+        addr
+    );
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        let geo_resolver = Arc::clone(&geo_resolver);
+        let routing_engine = Arc::clone(&routing_engine);
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_scrape(stream, &metrics, &geo_resolver, &routing_engine).await {
+                tracing::debug!("Metrics scrape error for {}: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_scrape(
+    mut stream: TcpStream,
+    metrics: &MetricsCollector,
+    geo_resolver: &GeoResolver,
+    routing_engine: &RoutingEngine,
+) -> Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    // Only the request line matters; read until the end of the headers
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+            break;
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+
+    let request_line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let (status, content_type, body) = if method == b"GET" && path == b"/metrics" {
+        (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render(
+                metrics,
+                &geo_resolver.resolution_stats(),
+                &routing_engine.list_replicas(),
+            ),
+        )
+    } else {
+        ("404 Not Found", "text/plain", "Not Found\n".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn micros_to_seconds(micros: u64) -> f64 {
+    micros as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::{ReplicaInfo, RoutingRequest};
+
+    #[test]
+    fn test_render_histogram_is_cumulative() {
+        let metrics = MetricsCollector::new();
+        metrics.record_request(5, true);
+        metrics.record_request(80, true);
+        metrics.record_request(200_000, false);
+
+        let text = render(&metrics, &GeoResolutionStats::default(), &[]);
+        assert!(text.contains("geo_router_requests_total{outcome=\"success\"} 2"));
+        assert!(text.contains("geo_router_requests_total{outcome=\"failure\"} 1"));
+        assert!(text.contains("geo_router_request_duration_seconds_bucket{le=\"0.00001\"} 1"));
+        assert!(text.contains("geo_router_request_duration_seconds_bucket{le=\"0.0001\"} 2"));
+        assert!(text.contains("geo_router_request_duration_seconds_bucket{le=\"0.1\"} 2"));
+        assert!(text.contains("geo_router_request_duration_seconds_bucket{le=\"+Inf\"} 3"));
+        assert!(text.contains("geo_router_request_duration_seconds_count 3"));
+    }
+
+    #[test]
+    fn test_render_route_breakdown() {
+        let metrics = MetricsCollector::new();
+        metrics.record_dimension(crate::routing::QueryType::Write, "us-\"east\"", 10, true);
+
+        let text = render(&metrics, &GeoResolutionStats::default(), &[]);
+        assert!(text.contains(
+            "geo_router_route_requests_total{query_type=\"write\",zone=\"us-\\\"east\\\"\",outcome=\"success\"} 1"
+        ));
+    }
+
+    #[test]
+    fn test_render_geoip_outcomes() {
+        let geo_stats = GeoResolutionStats {
+            resolved: 7,
+            no_database: 2,
+            lookup_errors: 1,
+        };
+
+        let text = render(&MetricsCollector::new(), &geo_stats, &[]);
+        assert!(text.contains("geo_router_geoip_resolutions_total{outcome=\"resolved\"} 7"));
+        assert!(text.contains("geo_router_geoip_resolutions_total{outcome=\"no_database\"} 2"));
+        assert!(text.contains("geo_router_geoip_resolutions_total{outcome=\"lookup_error\"} 1"));
+    }
+
+    #[test]
+    fn test_render_connection_saturation() {
+        let metrics = MetricsCollector::new();
+        metrics.record_connection_rejected();
+        metrics.set_active_connections(3);
+        metrics.reset();
+        metrics.record_connection_rejected();
+
+        let text = render(&metrics, &GeoResolutionStats::default(), &[]);
+        assert!(text.contains("geo_router_connections_rejected_total 1"));
+        assert!(text.contains("# TYPE geo_router_active_connections gauge"));
+        assert!(text.contains("geo_router_active_connections 3"));
+    }
+
+    #[test]
+    fn test_render_replica_series() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![ReplicaInfo {
+                node_id: "node-1".to_string(),
+                host: "127.0.0.1".to_string(),
+                port: 9000,
+                is_leader: true,
+                healthy: true,
+                zone: "us-east".to_string(),
+                geo_location: Default::default(),
+                load_score: 0.25,
+                latency_ms: 3.0,
+                asn: None,
+                capacity_weight: 1.0,
+                read_weight: 1.0,
+            }])
+            .unwrap();
+        let request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            ..Default::default()
+        };
+        let geo_resolver = GeoResolver::new(None).unwrap();
+        for _ in 0..3 {
+            engine.route_request(&request, &geo_resolver).unwrap();
+        }
+
+        let text = render(
+            &MetricsCollector::new(),
+            &GeoResolutionStats::default(),
+            &engine.list_replicas(),
+        );
+        let labels = "{node_id=\"node-1\",zone=\"us-east\"}";
+        assert!(text.contains("# TYPE geo_router_replica_selections_total counter"));
+        assert!(text.contains(&format!("geo_router_replica_selections_total{} 3", labels)));
+        assert!(text.contains(&format!("geo_router_replica_load{} 0.25", labels)));
+        assert!(text.contains(&format!(
+            "geo_router_replica_latency_milliseconds{} 3",
+            labels
+        )));
+        assert!(text.contains(&format!("geo_router_replica_eligible{} 1", labels)));
+    }
+}
@@ -0,0 +1,97 @@
+//! Per-client token-bucket rate limiting
+
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub struct RateLimiter {
+    buckets: DashMap<IpAddr, TokenBucket>,
+    rate_per_sec: f64,
+    burst: f64,
+}
+
+impl RateLimiter {
+    /// Allow `rate_per_sec` requests per second with bursts of up to `burst`
+    pub fn new(rate_per_sec: f64, burst: f64) -> Self {
+        Self {
+            buckets: DashMap::new(),
+            rate_per_sec,
+            burst: burst.max(1.0),
+        }
+    }
+
+    /// Take a token for `ip`, returning false if it is over its limit
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut bucket = self.buckets.entry(ip).or_insert_with(|| TokenBucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate_per_sec).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop buckets untouched for `idle`; they would have refilled anyway
+    pub fn remove_idle(&self, idle: Duration) {
+        let now = Instant::now();
+        self.buckets
+            .retain(|_, bucket| now.saturating_duration_since(bucket.last_refill) < idle);
+    }
+
+    pub fn tracked_clients(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new(10.0, 3.0);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.check_at(ip, start));
+        assert!(limiter.check_at(ip, start));
+        assert!(limiter.check_at(ip, start));
+        assert!(!limiter.check_at(ip, start));
+        assert!(limiter.check_at(other, start));
+
+        // 10/s refills one token every 100ms
+        assert!(limiter.check_at(ip, start + Duration::from_millis(100)));
+        assert!(!limiter.check_at(ip, start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_remove_idle() {
+        let limiter = RateLimiter::new(1.0, 1.0);
+        limiter.check("10.0.0.1".parse().unwrap());
+        assert_eq!(limiter.tracked_clients(), 1);
+
+        limiter.remove_idle(Duration::from_secs(60));
+        assert_eq!(limiter.tracked_clients(), 1);
+
+        limiter.remove_idle(Duration::ZERO);
+        assert_eq!(limiter.tracked_clients(), 0);
+    }
+}
@@ -0,0 +1,302 @@
+//! Short-lived LRU memo of routing decisions
+//!
+//! Clients behind one NAT tend to send identical route requests within
+//! milliseconds of each other; they share a decision for up to the TTL.
+
+use crate::routing::{QueryType, RoutingRequest, RoutingResponse, RoutingStrategy, ZoneLocality};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Prefix lengths clients are grouped by
+const IPV4_SUBNET_BITS: u32 = 24;
+const IPV6_SUBNET_BITS: u32 = 48;
+
+/// Everything about a request that its routing decision depends on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RouteCacheKey {
+    client_subnet: IpAddr,
+    query_type: QueryType,
+    zone_locality: ZoneLocality,
+    client_zone: Option<String>,
+    preferred_zones: Vec<String>,
+    affinity_key: Option<String>,
+    candidates: usize,
+    strategy: Option<RoutingStrategy>,
+}
+
+impl RouteCacheKey {
+    /// Key for `request`, or `None` if its decision mustn't be shared
+    ///
+    /// Fanned-out requests depend on every client's location and explained
+    /// ones on the full scoring, so neither is cached. Nor are random
+    /// `PowerOfTwoChoices` picks, which a cache would turn into one pick
+    /// per subnet.
+    pub fn for_request(request: &RoutingRequest) -> Option<Self> {
+        if !request.additional_client_ips.is_empty()
+            || request.explain
+            || request.strategy == Some(RoutingStrategy::PowerOfTwoChoices)
+        {
+            return None;
+        }
+
+        Some(Self {
+            client_subnet: client_subnet(request.client_ip),
+            query_type: QueryType::parse(&request.query_type),
+            zone_locality: request.zone_locality,
+            client_zone: request.client_zone.clone(),
+            preferred_zones: request.preferred_zones.clone(),
+            affinity_key: request.affinity_key.clone(),
+            candidates: request.candidates,
+            strategy: request.strategy,
+        })
+    }
+}
+
+fn client_subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(
+            u32::from(ip) & (u32::MAX << (32 - IPV4_SUBNET_BITS)),
+        )),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(
+            u128::from(ip) & (u128::MAX << (128 - IPV6_SUBNET_BITS)),
+        )),
+    }
+}
+
+struct CacheEntry {
+    response: RoutingResponse,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct LruState {
+    entries: HashMap<RouteCacheKey, CacheEntry>,
+    // Access tick -> key, oldest first
+    recency: BTreeMap<u64, RouteCacheKey>,
+    tick: u64,
+}
+
+impl LruState {
+    fn touch(&mut self, key: &RouteCacheKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.recency.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &RouteCacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+pub struct RouteCache {
+    capacity: usize,
+    ttl: Duration,
+    // Bumped by `invalidate`, so decisions computed from older routing
+    // state are never cached
+    generation: AtomicU64,
+    state: Mutex<LruState>,
+}
+
+impl RouteCache {
+    /// Hold up to `capacity` decisions, each for at most `ttl`
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            generation: AtomicU64::new(0),
+            state: Mutex::new(LruState::default()),
+        }
+    }
+
+    /// Current generation, to be passed to `insert` along with a decision
+    /// computed after this call
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Drop every cached decision, e.g. after the replica set changed
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        state.entries.clear();
+        state.recency.clear();
+    }
+
+    pub fn get(&self, key: &RouteCacheKey) -> Option<RoutingResponse> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &RouteCacheKey, now: Instant) -> Option<RoutingResponse> {
+        let mut state = self.state.lock();
+        let entry = state.entries.get(key)?;
+        if now >= entry.expires_at {
+            state.remove(key);
+            return None;
+        }
+
+        let response = entry.response.clone();
+        state.touch(key);
+        Some(response)
+    }
+
+    /// Cache `response` unless the cache was invalidated since `generation`
+    /// was read
+    pub fn insert(&self, key: RouteCacheKey, response: RoutingResponse, generation: u64) {
+        self.insert_at(key, response, generation, Instant::now());
+    }
+
+    fn insert_at(
+        &self,
+        key: RouteCacheKey,
+        response: RoutingResponse,
+        generation: u64,
+        now: Instant,
+    ) {
+        let mut state = self.state.lock();
+        if generation != self.generation() {
+            return;
+        }
+
+        state.remove(&key);
+        while state.entries.len() >= self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.recency.insert(tick, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                response,
+                expires_at: now + self.ttl,
+                last_used: tick,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::DistanceUnit;
+
+    fn request(client_ip: &str) -> RoutingRequest {
+        RoutingRequest {
+            client_ip: client_ip.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn response(node_id: &str) -> RoutingResponse {
+        RoutingResponse {
+            node_id: node_id.to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 9000,
+            zone: "us-east".to_string(),
+            distance_km: 0.0,
+            distance: 0.0,
+            distance_unit: DistanceUnit::Kilometers,
+            routing_strategy: "closest_healthy".to_string(),
+            failover_path: Vec::new(),
+            response_time_micros: 0,
+            alternates: Vec::new(),
+            forward_via: None,
+            degraded: false,
+            cached: false,
+            client_zone: None,
+            served_from_client_zone: false,
+            preferred_zone: None,
+            explain: None,
+        }
+    }
+
+    fn key(client_ip: &str) -> RouteCacheKey {
+        RouteCacheKey::for_request(&request(client_ip)).unwrap()
+    }
+
+    #[test]
+    fn test_client_subnet_masks_to_24_and_48_bits() {
+        let subnet = |ip: &str| client_subnet(ip.parse().unwrap()).to_string();
+        assert_eq!(subnet("203.0.113.77"), "203.0.113.0");
+        assert_eq!(subnet("10.255.255.255"), "10.255.255.0");
+        assert_eq!(subnet("2001:db8:1:ffff::2"), "2001:db8:1::");
+        assert_eq!(subnet("2001:db8:abcd:12::1"), "2001:db8:abcd::");
+    }
+
+    #[test]
+    fn test_clients_share_a_subnet_key() {
+        assert_eq!(key("203.0.113.7"), key("203.0.113.200"));
+        assert_ne!(key("203.0.113.7"), key("203.0.114.7"));
+        assert_eq!(key("2001:db8:1::1"), key("2001:db8:1:ffff::2"));
+        assert_ne!(key("2001:db8:1::1"), key("2001:db8:2::1"));
+
+        let mut explained = request("203.0.113.7");
+        explained.explain = true;
+        assert!(RouteCacheKey::for_request(&explained).is_none());
+    }
+
+    #[test]
+    fn test_entries_expire_after_ttl() {
+        let cache = RouteCache::new(8, Duration::from_millis(50));
+        let now = Instant::now();
+        cache.insert_at(key("10.0.0.1"), response("a"), cache.generation(), now);
+
+        assert!(cache.get_at(&key("10.0.0.1"), now).is_some());
+        assert!(cache
+            .get_at(&key("10.0.0.1"), now + Duration::from_millis(50))
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = RouteCache::new(2, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(key("10.0.1.1"), response("a"), generation);
+        cache.insert(key("10.0.2.1"), response("b"), generation);
+
+        assert!(cache.get(&key("10.0.1.1")).is_some());
+        cache.insert(key("10.0.3.1"), response("c"), generation);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key("10.0.1.1")).is_some());
+        assert!(cache.get(&key("10.0.2.1")).is_none());
+        assert!(cache.get(&key("10.0.3.1")).is_some());
+    }
+
+    #[test]
+    fn test_invalidate_discards_in_flight_decisions() {
+        let cache = RouteCache::new(8, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(key("10.0.0.1"), response("a"), generation);
+
+        cache.invalidate();
+        assert!(cache.get(&key("10.0.0.1")).is_none());
+
+        // Computed before the invalidation, so it may be stale
+        cache.insert(key("10.0.0.1"), response("a"), generation);
+        assert!(cache.is_empty());
+    }
+}
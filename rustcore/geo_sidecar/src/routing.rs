@@ -1,10 +1,24 @@
 //! High-performance routing engine
 
-use crate::geo::{GeoLocation, GeoResolver};
-use anyhow::{anyhow, Result};
-use dashmap::DashMap;
+use crate::error::RoutingError;
+use crate::geo::{geographic_centroid, DistanceUnit, GeoLocation, GeoResolver};
+use crate::route_cache::{RouteCache, RouteCacheKey};
+use anyhow::{Context, Result};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use dashmap::{DashMap, DashSet};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Weight of each new sample in the observed latency average
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReplicaInfo {
@@ -17,28 +31,521 @@ pub struct ReplicaInfo {
     pub geo_location: GeoLocation,
     pub load_score: f64,
     pub latency_ms: f64,
+    /// Autonomous system the replica is hosted in, if known
+    #[serde(default)]
+    pub asn: Option<u32>,
+    /// Relative capacity of the replica's hardware; `load_score` is divided
+    /// by it, so a node with weight 2.0 at load 0.8 scores like a weight 1.0
+    /// node at load 0.4
+    #[serde(default = "default_capacity_weight")]
+    pub capacity_weight: f64,
+    /// Share of read traffic to steer here relative to other replicas,
+    /// e.g. 2.0 for a follower dedicated to analytics
+    ///
+    /// Unlike `capacity_weight`, this doesn't change how load is judged:
+    /// each unit above 1.0 earns the read score a flat bonus (see
+    /// `ScoringWeights::read_weight_bonus`), so it only decides between
+    /// replicas that otherwise score within that bonus of each other. A
+    /// busy analytics follower still sheds reads through its load penalty.
+    /// Writes ignore it.
+    #[serde(default = "default_read_weight")]
+    pub read_weight: f64,
+}
+
+fn default_capacity_weight() -> f64 {
+    1.0
+}
+
+fn default_read_weight() -> f64 {
+    1.0
+}
+
+impl ReplicaInfo {
+    /// Load relative to capacity, the load that scoring and least-loaded
+    /// selection use
+    ///
+    /// A non-positive or non-finite `capacity_weight` counts as 1.0.
+    pub fn effective_load(&self) -> f64 {
+        if self.capacity_weight.is_finite() && self.capacity_weight > 0.0 {
+            self.load_score / self.capacity_weight
+        } else {
+            self.load_score
+        }
+    }
+
+    /// `read_weight`, with a negative or non-finite weight counting as 1.0
+    pub fn effective_read_weight(&self) -> f64 {
+        if self.read_weight.is_finite() && self.read_weight >= 0.0 {
+            self.read_weight
+        } else {
+            1.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryType {
+    Read,
+    Write,
+}
+
+impl QueryType {
+    pub const ALL: [QueryType; 2] = [QueryType::Read, QueryType::Write];
+
+    /// Anything other than "write" is served as a read
+    pub fn parse(query_type: &str) -> Self {
+        if query_type == "write" {
+            QueryType::Write
+        } else {
+            QueryType::Read
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueryType::Read => "read",
+            QueryType::Write => "write",
+        }
+    }
+}
+
+/// How eligible candidates are ordered once affinity, zone locality and
+/// failover have narrowed them down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingStrategy {
+    /// Lowest combined score under the engine's `ScoringWeights`
+    #[default]
+    Scored,
+    /// Shortest distance, ignoring load, latency and leadership
+    Nearest,
+    /// Lowest effective load, ignoring distance
+    LeastLoaded,
+    /// The less loaded of two candidates drawn at random, so bursts spread
+    /// over the eligible set instead of piling onto the top-scored replica
+    /// while its load reports lag behind
+    PowerOfTwoChoices,
+}
+
+impl RoutingStrategy {
+    /// Look up a strategy by name; unknown names are an error rather than
+    /// a silent fallback to the default
+    pub fn parse(name: &str) -> Result<Self, RoutingError> {
+        match name {
+            "scored" => Ok(RoutingStrategy::Scored),
+            "nearest" => Ok(RoutingStrategy::Nearest),
+            "least_loaded" => Ok(RoutingStrategy::LeastLoaded),
+            "p2c" => Ok(RoutingStrategy::PowerOfTwoChoices),
+            _ => Err(RoutingError::UnknownStrategy(name.to_string())),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoutingStrategy::Scored => "scored",
+            RoutingStrategy::Nearest => "nearest",
+            RoutingStrategy::LeastLoaded => "least_loaded",
+            RoutingStrategy::PowerOfTwoChoices => "p2c",
+        }
+    }
+
+    /// Value candidates are sorted by, lowest first; `PowerOfTwoChoices`
+    /// then moves its pick to the front
+    fn rank_key(self, score: &CandidateScore, replica: &ReplicaInfo) -> f64 {
+        match self {
+            RoutingStrategy::Scored | RoutingStrategy::PowerOfTwoChoices => score.score,
+            RoutingStrategy::Nearest => score.distance_km,
+            RoutingStrategy::LeastLoaded => replica.effective_load(),
+        }
+    }
+}
+
+/// Whether reads may leave the client's zone
+///
+/// The client's zone is the request's explicit `client_zone` when given,
+/// otherwise the zone of the replica nearest to the client's resolved
+/// location (healthy or not), the same zone failover ordering starts from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoneLocality {
+    /// Only the client's zone; fail if it has no eligible replica
+    Strict,
+    /// The client's zone when it has an eligible replica, else anywhere
+    Preferred,
+    /// No zone restriction
+    #[default]
+    Any,
 }
 
 #[derive(Debug)]
 pub struct RoutingRequest {
     pub client_ip: IpAddr,
+    /// Other clients of a fanned-out query; when present, routing starts
+    /// from the geographic centroid of all client locations
+    pub additional_client_ips: Vec<IpAddr>,
     pub query_type: String,
     pub timestamp: u64,
+    /// Attach the full candidate scoring to the response
+    pub explain: bool,
+    /// Number of ranked targets wanted, including the selected one
+    pub candidates: usize,
+    pub zone_locality: ZoneLocality,
+    /// Overrides the zone derived from the client's location
+    pub client_zone: Option<String>,
+    /// Zones to serve from in order of preference, tried before the
+    /// geographic zone logic; affinity rules still take precedence
+    pub preferred_zones: Vec<String>,
+    /// Tenant or shard key matched against the engine's affinity rules
+    pub affinity_key: Option<String>,
+    /// Time budget for routing; once spent, the engine settles for the
+    /// best target found so far and marks the response degraded
+    pub deadline_micros: Option<u64>,
+    /// Rank candidates with this strategy instead of the default `Scored`,
+    /// e.g. to try a strategy on a slice of traffic
+    pub strategy: Option<RoutingStrategy>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A single-target read from an unspecified client with no routing hints
+impl Default for RoutingRequest {
+    fn default() -> Self {
+        Self {
+            client_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            additional_client_ips: Vec::new(),
+            query_type: QueryType::Read.as_str().to_string(),
+            timestamp: 0,
+            explain: false,
+            candidates: 1,
+            zone_locality: ZoneLocality::Any,
+            client_zone: None,
+            preferred_zones: Vec::new(),
+            affinity_key: None,
+            deadline_micros: None,
+            strategy: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoutingResponse {
     pub node_id: String,
     pub host: String,
     pub port: u16,
+    pub zone: String,
     pub distance_km: f64,
+    /// `distance_km` in the resolver's configured unit
+    #[serde(default)]
+    pub distance: f64,
+    #[serde(default)]
+    pub distance_unit: DistanceUnit,
+    /// How candidates were chosen, e.g. `zone_failover`; prefixed with the
+    /// request's strategy override when one was given, as in
+    /// `nearest:closest_healthy`
     pub routing_strategy: String,
+    /// Zones walked when the client's nearest zone had no eligible replica,
+    /// starting with that nearest zone; empty when no failover happened
+    pub failover_path: Vec<String>,
     pub response_time_micros: u64,
+    /// Next-best targets for client-side hedging, ordered by score
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternates: Vec<ReplicaTarget>,
+    /// Nearby replica to relay a write through when the leader is much
+    /// farther away; see `set_write_forward_margin`. Its `score` is the
+    /// distance saved in km compared to reaching the leader directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_via: Option<ReplicaTarget>,
+    /// The request's deadline cut routing short, so the target may not be
+    /// the best one
+    #[serde(default)]
+    pub degraded: bool,
+    /// Served from the route cache; see `set_route_cache`
+    #[serde(default)]
+    pub cached: bool,
+    /// The zone the client counts as being in: the request's `client_zone`
+    /// if given, else the zone of the replica nearest the client's resolved
+    /// location, healthy or not. GeoIP regions are never matched to zone
+    /// names, so a client only has a zone once some replica is placed near
+    /// it. `None` when neither is known, e.g. for clients GeoIP couldn't
+    /// locate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_zone: Option<String>,
+    /// The target is in `client_zone`; false when that is unknown
+    #[serde(default)]
+    pub served_from_client_zone: bool,
+    /// Entry of the request's `preferred_zones` the target was taken from;
+    /// `None` when none of them had an eligible replica
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_zone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explain: Option<RoutingExplanation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaTarget {
+    pub node_id: String,
+    pub host: String,
+    pub port: u16,
+    pub zone: String,
+    pub distance_km: f64,
+    pub score: f64,
+}
+
+/// Weights combining each scoring term into a single score
+///
+/// Scores are in kilometre-equivalents: every term is converted to the
+/// number of kilometres of extra distance it is considered worth.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ScoringWeights {
+    /// Multiplier on great-circle distance (km per km)
+    pub distance_km: f64,
+    /// Penalty per unit of effective load, i.e. `load_score` divided by the
+    /// replica's `capacity_weight` (km per load unit); raising a replica's
+    /// capacity weight has the same effect as lowering this for it alone
+    pub load_penalty: f64,
+    /// Penalty per millisecond of latency on reads (km per ms); applied to
+    /// the observed average once reported, else to `latency_ms`
+    pub latency_weight: f64,
+    /// Bonus subtracted for leaders on reads (km)
+    pub leader_bonus: f64,
+    /// Bonus subtracted on reads when client and replica share an ASN (km)
+    #[serde(default)]
+    pub asn_match_bonus: f64,
+    /// Bonus subtracted on reads per unit of `read_weight` above 1.0, and
+    /// added per unit below it (km)
+    #[serde(default)]
+    pub read_weight_bonus: f64,
+    /// Penalty per request routed to a replica that the client hasn't yet
+    /// reported done via `report_done` (km per request), so a burst steers
+    /// traffic away before the pushed `load_score` catches up. Requests are
+    /// only counted while this is non-zero, which it is not by default, and
+    /// counts halve every `decay_in_flight` so unreported requests fade out.
+    #[serde(default)]
+    pub in_flight_penalty: f64,
+    /// Score clients that couldn't be located, and replicas reported
+    /// without a position, on load and latency alone instead of their
+    /// distance from the placeholder (0, 0)
+    #[serde(default)]
+    pub ignore_unlocated_distance: bool,
+}
+
+impl Default for ScoringWeights {
+    fn default() -> Self {
+        Self {
+            distance_km: 1.0,
+            load_penalty: 100.0,
+            latency_weight: 1.0,
+            leader_bonus: 50.0,
+            asn_match_bonus: 100.0,
+            read_weight_bonus: 50.0,
+            in_flight_penalty: 0.0,
+            ignore_unlocated_distance: false,
+        }
+    }
+}
+
+/// Score breakdown for one candidate; the lowest score wins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandidateScore {
+    pub node_id: String,
+    pub zone: String,
+    pub is_leader: bool,
+    pub distance_km: f64,
+    pub distance_penalty: f64,
+    pub load_penalty: f64,
+    /// Latency the penalty was computed from
+    pub latency_ms: f64,
+    /// Moving average of client-reported latency, if any were reported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ewma_ms: Option<f64>,
+    pub latency_penalty: f64,
+    pub leader_bonus: f64,
+    /// Client and replica resolved to the same autonomous system
+    pub asn_match: bool,
+    pub asn_bonus: f64,
+    /// Negative for replicas weighted above 1.0 for reads
+    pub read_weight_bonus: f64,
+    /// Routed requests not yet reported done
+    pub in_flight: u64,
+    pub in_flight_penalty: f64,
+    pub score: f64,
+}
+
+/// Why a replica was left out of selection entirely
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionReason {
+    Unhealthy,
+    /// Taken out of rotation for maintenance via `drain_replica`
+    Drained,
+    /// Reported healthy but failing the sidecar's own health probe
+    ProbeFailed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExcludedReplica {
+    pub node_id: String,
+    pub zone: String,
+    pub reason: ExclusionReason,
+}
+
+/// A replica and the engine's own state for it, for debugging routing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaState {
+    #[serde(flatten)]
+    pub replica: ReplicaInfo,
+    pub drained: bool,
+    pub probe_failed: bool,
+    /// Average of reported latencies, which replaces `latency_ms` in scoring
+    pub latency_ewma_ms: Option<f64>,
+    pub effective_load: f64,
+    /// Whether routing would currently consider the replica at all
+    pub eligible: bool,
+    /// Route requests answered with this replica since it joined the table,
+    /// cache hits included
+    #[serde(default)]
+    pub selections: u64,
+    /// Routed requests not yet reported done; only counted while the
+    /// `in_flight_penalty` weight is set
+    #[serde(default)]
+    pub in_flight: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingExplanation {
+    pub selected: String,
+    pub reason: String,
+    /// Eligible candidates ordered best first
+    pub candidates: Vec<CandidateScore>,
+    /// Replicas never considered, sorted by node id
+    #[serde(default)]
+    pub excluded: Vec<ExcludedReplica>,
+}
+
+/// Which affinity keys a rule applies to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AffinityMatch {
+    Key {
+        key: String,
+    },
+    /// Keys whose `affinity_hash` falls in `start..=end`
+    HashRange {
+        start: u64,
+        end: u64,
+    },
+}
+
+impl AffinityMatch {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            AffinityMatch::Key { key: rule_key } => rule_key == key,
+            AffinityMatch::HashRange { start, end } => {
+                (*start..=*end).contains(&affinity_hash(key))
+            }
+        }
+    }
+}
+
+/// Where traffic for a matching key prefers to go
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AffinityTarget {
+    Node {
+        node_id: String,
+    },
+    Zone {
+        zone: String,
+    },
+}
+
+impl AffinityTarget {
+    fn contains(&self, replica: &ReplicaInfo) -> bool {
+        match self {
+            AffinityTarget::Node { node_id } => &replica.node_id == node_id,
+            AffinityTarget::Zone { zone } => &replica.zone == zone,
+        }
+    }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AffinityRule {
+    #[serde(rename = "match")]
+    pub matcher: AffinityMatch,
+    pub target: AffinityTarget,
+}
+
+/// Stable 64-bit FNV-1a hash of an affinity key
+///
+/// Hash ranges are configured by clients, so this must not change between
+/// releases the way `std`'s `DefaultHasher` may.
+pub fn affinity_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// On-disk copy of the replica set, restored on startup
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoutingSnapshot {
+    /// Seconds since the Unix epoch when the snapshot was written
+    pub saved_at_secs: u64,
+    /// Restored so pushes older than the snapshot stay rejected
+    #[serde(default)]
+    pub generation: u64,
+    pub replicas: Vec<ReplicaInfo>,
+}
+
+/// Outcome of a routing table update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableUpdate {
+    /// Generation in effect after the update
+    pub generation: u64,
+    /// Replicas left out for coordinates outside [-90, 90] x [-180, 180]
+    pub rejected: usize,
+    /// Replicas kept with out-of-range coordinates clamped into range
+    pub clamped: usize,
+}
+
+/// Routing state shared by all connections
+///
+/// Routing and all runtime updates take `&self`, so the engine is shared as
+/// a plain `Arc` and routing never waits for a table update to finish.
 pub struct RoutingEngine {
     replicas: DashMap<String, ReplicaInfo>,
     zone_replicas: DashMap<String, Vec<String>>,
+    failover_order: DashMap<String, Vec<String>>,
+    drained: DashSet<String>,
+    // Failing the active health probe; see `set_probe_result`
+    probe_failed: DashSet<String>,
+    // Milliseconds, fed by `report_latency`
+    latency_ewma: DashMap<String, f64>,
+    // Times each replica was returned by `route_request`
+    selections: DashMap<String, AtomicU64>,
+    // Selections not yet matched by `report_done`
+    in_flight: DashMap<String, AtomicU64>,
+    // Highest generation `update_replicas_at` has applied
+    generation: AtomicU64,
+    // When the table last changed; engine creation until the first update
+    last_updated: Mutex<Instant>,
+    // Set by `check_freshness`, cleared by the next update
+    stale: AtomicBool,
+    affinity_rules: ArcSwap<Vec<AffinityRule>>,
+    write_forward_margin_km: ArcSwapOption<f64>,
+    warmed_up: AtomicBool,
+    weights: ArcSwap<ScoringWeights>,
+    snapshot_path: Option<PathBuf>,
+    route_cache: Option<RouteCache>,
+    strict_geo_validation: bool,
+    // Serializes table updates against each other, never against reads
+    update_lock: Mutex<()>,
+    // Splitmix64 state for `PowerOfTwoChoices` draws
+    rng_state: AtomicU64,
+}
+
+impl Default for RoutingEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RoutingEngine {
@@ -46,154 +553,2711 @@ impl RoutingEngine {
         Self {
             replicas: DashMap::new(),
             zone_replicas: DashMap::new(),
+            failover_order: DashMap::new(),
+            drained: DashSet::new(),
+            probe_failed: DashSet::new(),
+            latency_ewma: DashMap::new(),
+            selections: DashMap::new(),
+            in_flight: DashMap::new(),
+            generation: AtomicU64::new(0),
+            last_updated: Mutex::new(Instant::now()),
+            stale: AtomicBool::new(false),
+            affinity_rules: ArcSwap::from_pointee(Vec::new()),
+            write_forward_margin_km: ArcSwapOption::empty(),
+            warmed_up: AtomicBool::new(true),
+            weights: ArcSwap::from_pointee(ScoringWeights::default()),
+            snapshot_path: None,
+            route_cache: None,
+            strict_geo_validation: false,
+            update_lock: Mutex::new(()),
+            rng_state: AtomicU64::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_nanos() as u64),
+            ),
         }
     }
 
-    pub fn update_replicas(&mut self, replicas: Vec<ReplicaInfo>) -> Result<()> {
-        // Clear existing data
-        self.replicas.clear();
-        self.zone_replicas.clear();
+    /// Persist the replica set to `path` on every `update_replicas`
+    pub fn set_snapshot_path(&mut self, path: PathBuf) {
+        self.snapshot_path = Some(path);
+    }
 
-        // Update with new replicas
-        for replica in replicas {
-            let zone = replica.zone.clone();
-            let node_id = replica.node_id.clone();
+    /// Share decisions between identical requests from the same client
+    /// subnet for up to `ttl`, keeping at most `capacity` of them
+    ///
+    /// Any change to the replica set or routing policy invalidates the
+    /// cache. Reported latencies don't, so the latency averages a cached
+    /// decision was scored with may be up to `ttl` old.
+    pub fn set_route_cache(&mut self, capacity: usize, ttl: Duration) {
+        self.route_cache = Some(RouteCache::new(capacity, ttl));
+    }
+
+    /// Drop pushed replicas with out-of-range coordinates instead of
+    /// clamping them into range
+    ///
+    /// Either way the replica is logged. Clamping keeps a replica with a
+    /// slightly off position routable; rejecting keeps an obviously broken
+    /// one from attracting traffic. Non-finite coordinates are always
+    /// rejected.
+    pub fn set_strict_geo_validation(&mut self, strict: bool) {
+        self.strict_geo_validation = strict;
+    }
+
+    /// Clamp or drop replicas whose coordinates are out of range, returning
+    /// the rest and how many were rejected and clamped
+    fn validate_locations(&self, replicas: Vec<ReplicaInfo>) -> (Vec<ReplicaInfo>, usize, usize) {
+        let (mut rejected, mut clamped) = (0, 0);
+        let replicas = replicas
+            .into_iter()
+            .filter_map(|mut replica| {
+                let location = &replica.geo_location;
+                let (latitude, longitude) = (location.latitude, location.longitude);
+                if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) {
+                    return Some(replica);
+                }
+
+                if self.strict_geo_validation || !latitude.is_finite() || !longitude.is_finite() {
+                    tracing::warn!(
+                        "Rejecting replica {} with invalid coordinates ({}, {})",
+                        replica.node_id,
+                        latitude,
+                        longitude
+                    );
+                    rejected += 1;
+                    return None;
+                }
+
+                tracing::warn!(
+                    "Clamping invalid coordinates ({}, {}) of replica {}",
+                    latitude,
+                    longitude,
+                    replica.node_id
+                );
+                replica.geo_location.latitude = latitude.clamp(-90.0, 90.0);
+                replica.geo_location.longitude = longitude.clamp(-180.0, 180.0);
+                clamped += 1;
+                Some(replica)
+            })
+            .collect();
+        (replicas, rejected, clamped)
+    }
+
+    fn invalidate_route_cache(&self) {
+        if let Some(cache) = &self.route_cache {
+            cache.invalidate();
+        }
+    }
 
-            self.replicas.insert(node_id.clone(), replica);
+    /// Restore replicas from a snapshot unless it is older than `max_age`
+    ///
+    /// Returns whether the snapshot was applied.
+    pub fn load_snapshot(&self, path: &Path, max_age: Duration) -> Result<bool> {
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to read routing snapshot {:?}", path))?;
+        let snapshot: RoutingSnapshot =
+            serde_json::from_slice(&data).context("Failed to parse routing snapshot")?;
 
-            self.zone_replicas
-                .entry(zone)
-                .or_insert_with(Vec::new)
-                .push(node_id);
+        let age = Duration::from_secs(unix_time_secs().saturating_sub(snapshot.saved_at_secs));
+        if age > max_age {
+            tracing::info!("Ignoring routing snapshot {:?} aged {:?}", path, age);
+            return Ok(false);
         }
 
+        self.generation
+            .fetch_max(snapshot.generation, Ordering::Relaxed);
+        self.apply_replicas(snapshot.replicas);
+        // The table is as old as the snapshot, not as the restart
+        self.mark_updated(Instant::now().checked_sub(age).unwrap_or_else(Instant::now));
         tracing::info!(
-            "Updated routing table with {} replicas",
-            self.replicas.len()
+            "Restored {} replicas from routing snapshot {:?}",
+            self.replicas.len(),
+            path
         );
+        Ok(true)
+    }
+
+    pub fn set_scoring_weights(&self, weights: ScoringWeights) {
+        // Counts kept while disabled would be stale once re-enabled
+        if weights.in_flight_penalty == 0.0 {
+            self.in_flight.clear();
+        }
+        self.weights.store(Arc::new(weights));
+        self.invalidate_route_cache();
+    }
+
+    pub fn scoring_weights(&self) -> ScoringWeights {
+        **self.weights.load()
+    }
+
+    /// Suggest a `forward_via` replica on writes when the leader is more
+    /// than `margin_km` farther from the client than the nearest healthy
+    /// replica; `None` disables the hint
+    pub fn set_write_forward_margin(&self, margin_km: Option<f64>) {
+        self.write_forward_margin_km.store(margin_km.map(Arc::new));
+        self.invalidate_route_cache();
+    }
+
+    /// Declare which zones to try, in order, when `zone` has no eligible replica
+    pub fn set_failover_order(&self, zone: String, order: Vec<String>) {
+        if order.is_empty() {
+            self.failover_order.remove(&zone);
+        } else {
+            self.failover_order.insert(zone, order);
+        }
+        self.invalidate_route_cache();
+    }
+
+    /// Replace the affinity rules; the first rule matching a key wins
+    pub fn set_affinity_rules(&self, rules: Vec<AffinityRule>) {
+        tracing::info!("Set {} affinity rules", rules.len());
+        self.affinity_rules.store(Arc::new(rules));
+        self.invalidate_route_cache();
+    }
+
+    pub fn affinity_rules(&self) -> Vec<AffinityRule> {
+        self.affinity_rules.load().as_ref().clone()
+    }
+
+    /// Stop routing to `node_id` while keeping its metadata
+    ///
+    /// The drain survives routing table updates that still list the replica.
+    pub fn drain_replica(&self, node_id: &str) -> Result<(), RoutingError> {
+        if !self.replicas.contains_key(node_id) {
+            return Err(RoutingError::UnknownReplica(node_id.to_string()));
+        }
+        self.drained.insert(node_id.to_string());
+        self.invalidate_route_cache();
+        tracing::info!("Drained replica {}", node_id);
         Ok(())
     }
 
-    pub fn route_request(
-        &self,
-        request: &RoutingRequest,
-        geo_resolver: &GeoResolver,
-    ) -> Result<RoutingResponse> {
-        let start_time = std::time::Instant::now();
+    /// Return a drained replica to rotation
+    pub fn undrain_replica(&self, node_id: &str) -> Result<(), RoutingError> {
+        if self.drained.remove(node_id).is_none() {
+            return Err(RoutingError::ReplicaNotDrained(node_id.to_string()));
+        }
+        self.invalidate_route_cache();
+        tracing::info!("Undrained replica {}", node_id);
+        Ok(())
+    }
 
-        // Resolve client location
-        let client_location = geo_resolver.resolve(request.client_ip)?;
+    pub fn is_drained(&self, node_id: &str) -> bool {
+        self.drained.contains(node_id)
+    }
 
-        // Get available replicas
-        let healthy_replicas: Vec<_> = self
-            .replicas
+    /// Address of every replica, for the active health probe
+    pub fn probe_targets(&self) -> Vec<(String, String, u16)> {
+        self.replicas
             .iter()
-            .filter(|entry| entry.value().healthy)
-            .map(|entry| entry.value().clone())
-            .collect();
+            .map(|entry| {
+                let replica = entry.value();
+                (replica.node_id.clone(), replica.host.clone(), replica.port)
+            })
+            .collect()
+    }
 
-        if healthy_replicas.is_empty() {
-            return Err(anyhow!("No healthy replicas available"));
+    /// Record the outcome of probing `node_id`
+    ///
+    /// A failing replica is skipped even while the control plane still
+    /// reports it healthy, until a later probe succeeds. Results for
+    /// replicas no longer in the table are ignored.
+    pub fn set_probe_result(&self, node_id: &str, reachable: bool) {
+        if !self.replicas.contains_key(node_id) {
+            return;
         }
 
-        // Select best replica based on query type
-        let selected_replica = if request.query_type == "write" {
-            self.select_best_leader(&healthy_replicas, &client_location, geo_resolver)?
+        let changed = if reachable {
+            self.probe_failed.remove(node_id).is_some()
         } else {
-            self.select_best_replica(&healthy_replicas, &client_location, geo_resolver)?
+            self.probe_failed.insert(node_id.to_string())
         };
+        if changed {
+            self.invalidate_route_cache();
+            if reachable {
+                tracing::info!("Replica {} passed its health probe again", node_id);
+            } else {
+                tracing::warn!("Replica {} failed its health probe", node_id);
+            }
+        }
+    }
 
-        let distance_km =
-            geo_resolver.calculate_distance(&client_location, &selected_replica.geo_location);
+    pub fn is_probe_healthy(&self, node_id: &str) -> bool {
+        !self.probe_failed.contains(node_id)
+    }
 
-        let response_time_micros = start_time.elapsed().as_micros() as u64;
+    /// Healthy per the control plane and the probe, and not drained
+    fn is_eligible(&self, node_id: &str, replica: &ReplicaInfo) -> bool {
+        replica.healthy && !self.drained.contains(node_id) && !self.probe_failed.contains(node_id)
+    }
 
-        Ok(RoutingResponse {
-            node_id: selected_replica.node_id,
-            host: selected_replica.host,
-            port: selected_replica.port,
-            distance_km,
-            routing_strategy: "closest_healthy".to_string(),
-            response_time_micros,
-        })
+    /// Fold a client-observed request latency into the replica's moving
+    /// average, which then replaces its static `latency_ms` in scoring
+    ///
+    /// The average survives routing table updates that still list the replica.
+    pub fn report_latency(&self, node_id: &str, latency_micros: u64) -> Result<(), RoutingError> {
+        if !self.replicas.contains_key(node_id) {
+            return Err(RoutingError::UnknownReplica(node_id.to_string()));
+        }
+
+        let sample_ms = latency_micros as f64 / 1_000.0;
+        self.latency_ewma
+            .entry(node_id.to_string())
+            .and_modify(|ewma| *ewma += LATENCY_EWMA_ALPHA * (sample_ms - *ewma))
+            .or_insert(sample_ms);
+        Ok(())
     }
 
-    fn select_best_leader(
+    pub fn latency_ewma_ms(&self, node_id: &str) -> Option<f64> {
+        self.latency_ewma.get(node_id).map(|ewma| *ewma)
+    }
+
+    /// Mark a request routed to `node_id` as finished, lowering its
+    /// in-flight count; see `ScoringWeights::in_flight_penalty`
+    ///
+    /// Extra reports are ignored rather than driving the count below zero.
+    pub fn report_done(&self, node_id: &str) -> Result<(), RoutingError> {
+        if !self.replicas.contains_key(node_id) {
+            return Err(RoutingError::UnknownReplica(node_id.to_string()));
+        }
+
+        if let Some(count) = self.in_flight.get(node_id) {
+            let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        }
+        Ok(())
+    }
+
+    /// Halve every in-flight count
+    ///
+    /// Clients that crash, or route over a transport without `report_done`,
+    /// never report their requests finished; run periodically, this keeps
+    /// those requests from penalizing their replica for good.
+    pub fn decay_in_flight(&self) {
+        for count in self.in_flight.iter() {
+            let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n / 2));
+        }
+    }
+
+    pub fn in_flight_count(&self, node_id: &str) -> u64 {
+        self.in_flight
+            .get(node_id)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn update_replicas(&self, replicas: Vec<ReplicaInfo>) -> Result<(), RoutingError> {
+        self.update_replicas_at(replicas, None).map(|_| ())
+    }
+
+    /// Replace the replica set unless `generation` is older than the
+    /// table's, returning the generation now in effect
+    ///
+    /// Replicas with out-of-range coordinates are clamped or left out; see
+    /// `set_strict_geo_validation`.
+    ///
+    /// Coordinators pushing concurrently during failover can arrive out of
+    /// order; numbering pushes keeps an older table from overwriting a newer
+    /// one. An equal generation is applied again, so a retried push
+    /// succeeds. Pushes without a generation always apply and leave it
+    /// unchanged.
+    pub fn update_replicas_at(
         &self,
-        candidates: &[ReplicaInfo],
-        client_location: &GeoLocation,
-        geo_resolver: &GeoResolver,
-    ) -> Result<ReplicaInfo> {
-        let leaders: Vec<_> = candidates.iter().filter(|r| r.is_leader).collect();
+        replicas: Vec<ReplicaInfo>,
+        generation: Option<u64>,
+    ) -> Result<TableUpdate, RoutingError> {
+        let _guard = self.update_lock.lock();
 
-        if leaders.is_empty() {
-            return Err(anyhow!("No healthy leaders available"));
+        let current = self.generation.load(Ordering::Relaxed);
+        if let Some(received) = generation {
+            if received < current {
+                tracing::warn!(
+                    "Ignoring routing table generation {} older than {}",
+                    received,
+                    current
+                );
+                return Err(RoutingError::StaleGeneration { received, current });
+            }
+            self.generation.store(received, Ordering::Relaxed);
         }
 
-        // Find closest leader
-        let best_leader = leaders
-            .into_iter()
-            .min_by(|a, b| {
-                let dist_a = geo_resolver.calculate_distance(client_location, &a.geo_location);
-                let dist_b = geo_resolver.calculate_distance(client_location, &b.geo_location);
+        let (replicas, rejected, clamped) = self.validate_locations(replicas);
+        self.save_snapshot(&replicas);
+        self.apply_replicas(replicas);
+        self.mark_updated(Instant::now());
 
-                // Factor in load as well
-                let score_a = dist_a + a.load_score * 100.0; // 100km penalty per load unit
-                let score_b = dist_b + b.load_score * 100.0;
+        tracing::info!(
+            "Updated routing table with {} replicas at generation {}",
+            self.replicas.len(),
+            self.table_generation()
+        );
+        Ok(TableUpdate {
+            generation: self.table_generation(),
+            rejected,
+            clamped,
+        })
+    }
 
-                score_a
-                    .partial_cmp(&score_b)
-                    .unwrap_or(std::cmp::Ordering::Equal)
-            })
-            .ok_or_else(|| anyhow!("Failed to select leader"))?;
+    pub fn table_generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    fn mark_updated(&self, at: Instant) {
+        *self.last_updated.lock() = at;
+        if self.stale.swap(false, Ordering::Relaxed) {
+            tracing::info!("Routing table is fresh again");
+        }
+    }
+
+    /// Time since the table was last replaced or patched, or since the
+    /// engine was created if it never has been
+    pub fn table_age(&self) -> Duration {
+        self.last_updated.lock().elapsed()
+    }
+
+    /// Flag the table stale once it is older than `max_age`, returning
+    /// whether it is
+    ///
+    /// Routing carries on against a stale table; the flag only surfaces in
+    /// health checks so clients can choose to fail closed. The next update
+    /// clears it.
+    pub fn check_freshness(&self, max_age: Duration) -> bool {
+        let age = self.table_age();
+        let stale = age > max_age;
+        if stale && !self.stale.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                "No routing table update for {:?}, exceeding the {:?} limit",
+                age,
+                max_age
+            );
+        }
+        stale
+    }
 
-        Ok(best_leader.clone())
+    pub fn is_table_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
     }
 
-    fn select_best_replica(
+    /// Insert or replace `upserts` and drop `removals`, leaving every other
+    /// replica, its drain state and its latency average untouched
+    ///
+    /// Removals are applied first, so a node listed in both ends up present.
+    /// Unknown removals are ignored, making a retried patch harmless.
+    /// Upserts are validated like `update_replicas_at`'s replicas.
+    pub fn patch_replicas(
         &self,
-        candidates: &[ReplicaInfo],
-        client_location: &GeoLocation,
-        geo_resolver: &GeoResolver,
-    ) -> Result<ReplicaInfo> {
-        // For reads, we can use any healthy replica (including followers)
-        let best_replica = candidates
-            .iter()
-            .min_by(|a, b| {
-                let dist_a = geo_resolver.calculate_distance(client_location, &a.geo_location);
-                let dist_b = geo_resolver.calculate_distance(client_location, &b.geo_location);
+        upserts: Vec<ReplicaInfo>,
+        removals: Vec<String>,
+    ) -> Result<TableUpdate, RoutingError> {
+        let _guard = self.update_lock.lock();
+        let (upserts, rejected, clamped) = self.validate_locations(upserts);
 
-                // Prefer leaders for consistency, but factor in distance and load
-                let leader_bonus_a = if a.is_leader { -50.0 } else { 0.0 }; // 50km bonus for leaders
-                let leader_bonus_b = if b.is_leader { -50.0 } else { 0.0 };
+        for node_id in &removals {
+            if let Some((_, replica)) = self.replicas.remove(node_id) {
+                self.remove_from_zone(&replica.zone, node_id);
+                self.drained.remove(node_id);
+                self.probe_failed.remove(node_id);
+                self.latency_ewma.remove(node_id);
+                self.selections.remove(node_id);
+                self.in_flight.remove(node_id);
+            }
+        }
 
-                let score_a = dist_a + a.load_score * 100.0 + a.latency_ms + leader_bonus_a;
-                let score_b = dist_b + b.load_score * 100.0 + b.latency_ms + leader_bonus_b;
+        for replica in &upserts {
+            let node_id = &replica.node_id;
+            let mut zone = self.zone_replicas.entry(replica.zone.clone()).or_default();
+            if !zone.contains(node_id) {
+                zone.push(node_id.clone());
+            }
+        }
+        let upserted = upserts.len();
+        for replica in upserts {
+            let node_id = replica.node_id.clone();
+            let zone = replica.zone.clone();
+            if let Some(previous) = self.replicas.insert(node_id.clone(), replica) {
+                if previous.zone != zone {
+                    self.remove_from_zone(&previous.zone, &node_id);
+                }
+            }
+        }
+        self.invalidate_route_cache();
+        self.mark_updated(Instant::now());
 
-                score_a
-                    .partial_cmp(&score_b)
-                    .unwrap_or(std::cmp::Ordering::Equal)
-            })
-            .ok_or_else(|| anyhow!("Failed to select replica"))?;
+        if self.snapshot_path.is_some() {
+            let replicas: Vec<ReplicaInfo> = self
+                .replicas
+                .iter()
+                .map(|entry| entry.value().clone())
+                .collect();
+            self.save_snapshot(&replicas);
+        }
 
-        Ok(best_replica.clone())
+        tracing::info!(
+            "Patched routing table: {} upserted, {} removed, {} replicas",
+            upserted,
+            removals.len(),
+            self.replicas.len()
+        );
+        Ok(TableUpdate {
+            generation: self.table_generation(),
+            rejected,
+            clamped,
+        })
     }
 
-    pub fn get_replica_count(&self) -> usize {
-        self.replicas.len()
+    fn remove_from_zone(&self, zone: &str, node_id: &str) {
+        if let Some(mut node_ids) = self.zone_replicas.get_mut(zone) {
+            node_ids.retain(|id| id != node_id);
+        }
+        self.zone_replicas
+            .remove_if(zone, |_, node_ids| node_ids.is_empty());
     }
 
-    pub fn get_healthy_replica_count(&self) -> usize {
-        self.replicas
-            .iter()
-            .filter(|entry| entry.value().healthy)
-            .count()
+    fn save_snapshot(&self, replicas: &[ReplicaInfo]) {
+        // A failed snapshot shouldn't reject the update itself
+        if let Some(path) = &self.snapshot_path {
+            if let Err(e) = write_snapshot(path, replicas, self.table_generation()) {
+                tracing::warn!("Failed to write routing snapshot {:?}: {}", path, e);
+            }
+        }
     }
 
-    pub fn get_leader_count(&self) -> usize {
-        self.replicas
-            .iter()
-            .filter(|entry| entry.value().is_leader && entry.value().healthy)
-            .count()
+    /// Replace the replica set in place
+    ///
+    /// New entries are inserted before stale ones are removed, so concurrent
+    /// routes see either replica set or a mix of both, never an empty table.
+    fn apply_replicas(&self, replicas: Vec<ReplicaInfo>) {
+        let mut zone_replicas: HashMap<String, Vec<String>> = HashMap::new();
+        for replica in &replicas {
+            zone_replicas
+                .entry(replica.zone.clone())
+                .or_default()
+                .push(replica.node_id.clone());
+        }
+
+        for replica in replicas {
+            self.replicas.insert(replica.node_id.clone(), replica);
+        }
+        self.replicas.retain(|node_id, replica| {
+            zone_replicas
+                .get(&replica.zone)
+                .is_some_and(|node_ids| node_ids.contains(node_id))
+        });
+
+        self.drained.retain(|node_id| self.replicas.contains_key(node_id));
+        self.probe_failed
+            .retain(|node_id| self.replicas.contains_key(node_id));
+        self.latency_ewma
+            .retain(|node_id, _| self.replicas.contains_key(node_id));
+        self.selections
+            .retain(|node_id, _| self.replicas.contains_key(node_id));
+        self.in_flight
+            .retain(|node_id, _| self.replicas.contains_key(node_id));
+
+        self.zone_replicas.retain(|zone, _| zone_replicas.contains_key(zone));
+        for (zone, node_ids) in zone_replicas {
+            self.zone_replicas.insert(zone, node_ids);
+        }
+        self.invalidate_route_cache();
+    }
+
+    pub fn route_request(
+        &self,
+        request: &RoutingRequest,
+        geo_resolver: &GeoResolver,
+    ) -> Result<RoutingResponse, RoutingError> {
+        // In-flight counts change with every decision, so with them in the
+        // score no decision may be shared
+        let cache = self
+            .route_cache
+            .as_ref()
+            .filter(|_| self.weights.load().in_flight_penalty == 0.0)
+            .zip(RouteCacheKey::for_request(request));
+        let Some((cache, key)) = cache else {
+            let response = self.compute_route(request, geo_resolver)?;
+            self.record_selection(&response.node_id);
+            return Ok(response);
+        };
+
+        let start_time = Instant::now();
+        if let Some(mut response) = cache.get(&key) {
+            response.cached = true;
+            response.response_time_micros = start_time.elapsed().as_micros() as u64;
+            self.record_selection(&response.node_id);
+            return Ok(response);
+        }
+
+        let generation = cache.generation();
+        let response = self.compute_route(request, geo_resolver)?;
+        // A deadline-cut decision is only good enough for this request
+        if !response.degraded {
+            cache.insert(key, response.clone(), generation);
+        }
+        self.record_selection(&response.node_id);
+        Ok(response)
+    }
+
+    fn record_selection(&self, node_id: &str) {
+        increment(&self.selections, node_id);
+        if self.weights.load().in_flight_penalty != 0.0 {
+            increment(&self.in_flight, node_id);
+        }
+    }
+
+    pub fn selection_count(&self, node_id: &str) -> u64 {
+        self.selections
+            .get(node_id)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    fn compute_route(
+        &self,
+        request: &RoutingRequest,
+        geo_resolver: &GeoResolver,
+    ) -> Result<RoutingResponse, RoutingError> {
+        let start_time = Instant::now();
+        let deadline = request
+            .deadline_micros
+            .map(|budget| start_time + Duration::from_micros(budget));
+        let past_deadline = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+        // Get available replicas
+        let healthy_replicas: Vec<_> = self
+            .replicas
+            .iter()
+            .filter(|entry| self.is_eligible(entry.key(), entry.value()))
+            .map(|entry| entry.value().clone())
+            .collect();
+
+        if healthy_replicas.is_empty() {
+            return Err(RoutingError::NoHealthyReplicas);
+        }
+
+        let query_type = QueryType::parse(&request.query_type);
+        if past_deadline() {
+            return self.deadline_fallback(
+                request,
+                &healthy_replicas,
+                query_type,
+                geo_resolver.distance_unit(),
+                start_time,
+            );
+        }
+
+        // Resolve client location, leaving out fanned-out clients once the
+        // deadline passes
+        let mut degraded = false;
+        let client_location = geo_resolver.resolve(request.client_ip)?;
+        let client_location = if request.additional_client_ips.is_empty() {
+            client_location
+        } else {
+            let mut locations = vec![client_location];
+            for ip in &request.additional_client_ips {
+                if past_deadline() {
+                    degraded = true;
+                    break;
+                }
+                locations.push(geo_resolver.resolve(*ip)?);
+            }
+            geographic_centroid(&locations)
+        };
+
+        // A client that couldn't be located has no nearest zone; the zone
+        // nearest the placeholder (0, 0) says nothing about where it is
+        let distance_known =
+            client_location.is_located() || !self.weights.load().ignore_unlocated_distance;
+        let client_zone = request.client_zone.clone().or_else(|| {
+            client_location
+                .is_located()
+                .then(|| self.nearest_zone(&client_location, geo_resolver))
+                .flatten()
+        });
+
+        // Found before `healthy_replicas` is narrowed to the candidates
+        let forward_candidate = match (query_type, self.write_forward_margin_km.load_full()) {
+            (QueryType::Write, Some(margin_km)) if distance_known => {
+                nearest_replica(&healthy_replicas, &client_location, geo_resolver)
+                    .map(|(distance_km, replica)| (distance_km, replica.clone(), *margin_km))
+            }
+            _ => None,
+        };
+
+        // Tenant placement is explicit policy, so it outranks zone locality
+        let affinity_candidates = request
+            .affinity_key
+            .as_deref()
+            .and_then(|key| self.affinity_candidates(&healthy_replicas, key, query_type));
+
+        // The client's own zone order replaces the geographic one
+        let preferred_candidates = match affinity_candidates {
+            Some(_) => None,
+            None => self.preferred_zone_candidates(
+                &healthy_replicas,
+                &request.preferred_zones,
+                query_type,
+            ),
+        };
+        let preferred_zone = preferred_candidates.as_ref().map(|(zone, _)| zone.clone());
+
+        let local_candidates = match (request.zone_locality, &client_zone) {
+            _ if affinity_candidates.is_some() || preferred_candidates.is_some() => None,
+            (ZoneLocality::Any, _) | (ZoneLocality::Preferred, None) => None,
+            (ZoneLocality::Strict, None) => return Err(RoutingError::ClientZoneUnknown),
+            (locality, Some(zone)) => {
+                let local = self.zone_candidates(&healthy_replicas, zone, query_type);
+                if local.is_empty() && locality == ZoneLocality::Strict {
+                    return Err(RoutingError::ZoneUnavailable(zone.clone()));
+                }
+                (!local.is_empty()).then_some(local)
+            }
+        };
+
+        // Narrow to the first declared fallback zone if the nearest one is down
+        let (candidates, failover_path, routing_strategy) =
+            match (affinity_candidates, preferred_candidates, local_candidates) {
+                (Some(affine), _, _) => (affine, Vec::new(), "affinity"),
+                (None, Some((_, preferred)), _) => (preferred, Vec::new(), "preferred_zone"),
+                (None, None, Some(local)) => (local, Vec::new(), "zone_local"),
+                (None, None, None) => {
+                    match self.failover_candidates(
+                        &healthy_replicas,
+                        client_zone.clone(),
+                        query_type,
+                    ) {
+                        Some((candidates, path)) => (candidates, path, "zone_failover"),
+                        None => (healthy_replicas, Vec::new(), "closest_healthy"),
+                    }
+                }
+            };
+
+        // Select best replica based on query type
+        let strategy = request.strategy.unwrap_or_default();
+        let (ranked, truncated) = self.rank_candidates(
+            &candidates,
+            &client_location,
+            geo_resolver,
+            query_type,
+            strategy,
+            deadline,
+        );
+        degraded |= truncated;
+        let (selected_score, selected_replica) =
+            ranked.first().ok_or(no_replicas_error(query_type))?;
+
+        let explain = request.explain.then(|| {
+            let mut reason = format!(
+                "lowest score {:.2} among {} eligible {}",
+                selected_score.score,
+                ranked.len(),
+                match query_type {
+                    QueryType::Write => "leaders",
+                    QueryType::Read => "replicas",
+                }
+            );
+            if let Some(zone) = &preferred_zone {
+                reason.push_str(&format!(" in preferred zone {}", zone));
+            }
+            if !failover_path.is_empty() {
+                reason.push_str(&format!(
+                    " after zone failover {}",
+                    failover_path.join(" -> ")
+                ));
+            }
+            if strategy != RoutingStrategy::Scored {
+                reason.push_str(&format!(", ranked by {}", strategy.as_str()));
+            }
+            if degraded {
+                reason.push_str(", cut short by the deadline");
+            }
+
+            RoutingExplanation {
+                selected: selected_replica.node_id.clone(),
+                reason,
+                candidates: ranked.iter().map(|(score, _)| score.clone()).collect(),
+                excluded: self.excluded_replicas(),
+            }
+        });
+
+        let alternates = ranked
+            .iter()
+            .skip(1)
+            .take(request.candidates.saturating_sub(1))
+            .map(|(score, replica)| ReplicaTarget {
+                node_id: replica.node_id.clone(),
+                host: replica.host.clone(),
+                port: replica.port,
+                zone: replica.zone.clone(),
+                distance_km: score.distance_km,
+                score: score.score,
+            })
+            .collect();
+
+        let forward_via = forward_candidate
+            .filter(|(distance_km, replica, margin_km)| {
+                replica.node_id != selected_replica.node_id
+                    && selected_score.distance_km - distance_km > *margin_km
+            })
+            .map(|(distance_km, replica, _)| ReplicaTarget {
+                node_id: replica.node_id,
+                host: replica.host,
+                port: replica.port,
+                zone: replica.zone,
+                distance_km,
+                score: selected_score.distance_km - distance_km,
+            });
+
+        let response_time_micros = start_time.elapsed().as_micros() as u64;
+
+        Ok(RoutingResponse {
+            node_id: selected_replica.node_id.clone(),
+            host: selected_replica.host.clone(),
+            port: selected_replica.port,
+            zone: selected_replica.zone.clone(),
+            distance_km: selected_score.distance_km,
+            distance: geo_resolver
+                .distance_unit()
+                .convert_km(selected_score.distance_km),
+            distance_unit: geo_resolver.distance_unit(),
+            routing_strategy: match request.strategy {
+                Some(strategy) => format!("{}:{}", strategy.as_str(), routing_strategy),
+                None => routing_strategy.to_string(),
+            },
+            failover_path,
+            response_time_micros,
+            alternates,
+            forward_via,
+            degraded,
+            cached: false,
+            served_from_client_zone: client_zone.as_ref() == Some(&selected_replica.zone),
+            client_zone,
+            preferred_zone,
+            explain,
+        })
+    }
+
+    /// Pick a target without resolving or scoring once the deadline has
+    /// already passed: the least loaded eligible replica, taken from the
+    /// affinity target, else the first preferred zone that has one, else the
+    /// client's declared zone when possible
+    fn deadline_fallback(
+        &self,
+        request: &RoutingRequest,
+        healthy_replicas: &[ReplicaInfo],
+        query_type: QueryType,
+        distance_unit: DistanceUnit,
+        start_time: Instant,
+    ) -> Result<RoutingResponse, RoutingError> {
+        let affine = request
+            .affinity_key
+            .as_deref()
+            .and_then(|key| self.affinity_candidates(healthy_replicas, key, query_type));
+        let preferred = request.preferred_zones.iter().find_map(|zone| {
+            least_loaded(
+                healthy_replicas
+                    .iter()
+                    .filter(|replica| &replica.zone == zone),
+                query_type,
+            )
+            .map(|replica| (zone, replica))
+        });
+        let local = request.client_zone.as_ref().and_then(|zone| {
+            least_loaded(
+                healthy_replicas
+                    .iter()
+                    .filter(|replica| &replica.zone == zone),
+                query_type,
+            )
+        });
+
+        let (selected, routing_strategy) = match (&affine, preferred, local) {
+            (Some(affine), _, _) => (least_loaded(affine.iter(), query_type), "affinity"),
+            (None, Some((_, preferred)), _) => (Some(preferred), "preferred_zone"),
+            (None, None, Some(local)) => (Some(local), "zone_local"),
+            (None, None, None) if request.zone_locality == ZoneLocality::Strict => {
+                return Err(match &request.client_zone {
+                    Some(zone) => RoutingError::ZoneUnavailable(zone.clone()),
+                    None => RoutingError::ClientZoneUnknown,
+                });
+            }
+            (None, None, None) => (
+                least_loaded(healthy_replicas.iter(), query_type),
+                "any_healthy",
+            ),
+        };
+        let selected = selected.ok_or(no_replicas_error(query_type))?;
+
+        let explain = request.explain.then(|| RoutingExplanation {
+            selected: selected.node_id.clone(),
+            reason: "deadline passed before scoring; least loaded eligible replica".to_string(),
+            candidates: Vec::new(),
+            excluded: self.excluded_replicas(),
+        });
+
+        Ok(RoutingResponse {
+            node_id: selected.node_id.clone(),
+            host: selected.host.clone(),
+            port: selected.port,
+            zone: selected.zone.clone(),
+            // Unknown without resolving the client
+            distance_km: 0.0,
+            distance: 0.0,
+            distance_unit,
+            routing_strategy: routing_strategy.to_string(),
+            failover_path: Vec::new(),
+            response_time_micros: start_time.elapsed().as_micros() as u64,
+            alternates: Vec::new(),
+            forward_via: None,
+            degraded: true,
+            cached: false,
+            client_zone: request.client_zone.clone(),
+            served_from_client_zone: request.client_zone.as_ref() == Some(&selected.zone),
+            preferred_zone: preferred
+                .filter(|_| affine.is_none())
+                .map(|(zone, _)| zone.clone()),
+            explain,
+        })
+    }
+
+    /// Zone of the replica nearest the client, judged over all replicas,
+    /// healthy or not
+    fn nearest_zone(
+        &self,
+        client_location: &GeoLocation,
+        geo_resolver: &GeoResolver,
+    ) -> Option<String> {
+        self.replicas
+            .iter()
+            .map(|entry| {
+                let distance = geo_resolver
+                    .calculate_distance_km(client_location, &entry.value().geo_location);
+                (distance, entry.value().zone.clone())
+            })
+            .min_by(|a, b| rank_order(a.0, b.0).then_with(|| a.1.cmp(&b.1)))
+            .map(|(_, zone)| zone)
+    }
+
+    /// Healthy replicas targeted by the first affinity rule matching `key`
+    ///
+    /// Returns `None` when no rule matches or the target has no eligible
+    /// replica, so routing falls through to the geographic logic.
+    fn affinity_candidates(
+        &self,
+        healthy_replicas: &[ReplicaInfo],
+        key: &str,
+        query_type: QueryType,
+    ) -> Option<Vec<ReplicaInfo>> {
+        let rules = self.affinity_rules.load();
+        let rule = rules.iter().find(|rule| rule.matcher.matches(key))?;
+
+        let candidates: Vec<_> = healthy_replicas
+            .iter()
+            .filter(|replica| rule.target.contains(replica))
+            .filter(|replica| query_type == QueryType::Read || replica.is_leader)
+            .cloned()
+            .collect();
+        (!candidates.is_empty()).then_some(candidates)
+    }
+
+    /// Healthy replicas in `zone` that can serve `query_type`
+    fn zone_candidates(
+        &self,
+        healthy_replicas: &[ReplicaInfo],
+        zone: &str,
+        query_type: QueryType,
+    ) -> Vec<ReplicaInfo> {
+        let Some(node_ids) = self.zone_replicas.get(zone) else {
+            return Vec::new();
+        };
+
+        healthy_replicas
+            .iter()
+            .filter(|replica| node_ids.contains(&replica.node_id))
+            .filter(|replica| query_type == QueryType::Read || replica.is_leader)
+            .cloned()
+            .collect()
+    }
+
+    /// First of `zones` with an eligible replica, along with those replicas
+    fn preferred_zone_candidates(
+        &self,
+        healthy_replicas: &[ReplicaInfo],
+        zones: &[String],
+        query_type: QueryType,
+    ) -> Option<(String, Vec<ReplicaInfo>)> {
+        zones.iter().find_map(|zone| {
+            let candidates = self.zone_candidates(healthy_replicas, zone, query_type);
+            (!candidates.is_empty()).then(|| (zone.clone(), candidates))
+        })
+    }
+
+    /// Apply the failover order of the client's zone
+    ///
+    /// Returns `None` when the client's zone can serve the request or has no
+    /// failover order. If every listed zone is also down, all healthy
+    /// replicas are returned so routing falls back to raw distance.
+    fn failover_candidates(
+        &self,
+        healthy_replicas: &[ReplicaInfo],
+        client_zone: Option<String>,
+        query_type: QueryType,
+    ) -> Option<(Vec<ReplicaInfo>, Vec<String>)> {
+        if self.failover_order.is_empty() {
+            return None;
+        }
+
+        let eligible = |replica: &ReplicaInfo| query_type == QueryType::Read || replica.is_leader;
+        let nearest_zone = client_zone?;
+
+        if healthy_replicas
+            .iter()
+            .any(|replica| replica.zone == nearest_zone && eligible(replica))
+        {
+            return None;
+        }
+
+        let order = self.failover_order.get(&nearest_zone)?;
+        let mut path = vec![nearest_zone.clone()];
+
+        for zone in order.iter() {
+            path.push(zone.clone());
+
+            let in_zone: Vec<_> = healthy_replicas
+                .iter()
+                .filter(|replica| &replica.zone == zone && eligible(replica))
+                .cloned()
+                .collect();
+
+            if !in_zone.is_empty() {
+                return Some((in_zone, path));
+            }
+        }
+
+        Some((healthy_replicas.to_vec(), path))
+    }
+
+    /// Score and sort eligible candidates, best first
+    ///
+    /// Writes only consider leaders. This is the single source of truth for
+    /// selection, so explain output always matches the routing decision.
+    fn rank_candidates<'a>(
+        &self,
+        candidates: &'a [ReplicaInfo],
+        client_location: &GeoLocation,
+        geo_resolver: &GeoResolver,
+        query_type: QueryType,
+        strategy: RoutingStrategy,
+        deadline: Option<Instant>,
+    ) -> (Vec<(CandidateScore, &'a ReplicaInfo)>, bool) {
+        let weights = self.weights.load();
+        let mut ranked = Vec::with_capacity(candidates.len());
+        let mut truncated = false;
+        for replica in candidates
+            .iter()
+            .filter(|replica| query_type == QueryType::Read || replica.is_leader)
+        {
+            // Always score one candidate, so there is something to return
+            if !ranked.is_empty() && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                truncated = true;
+                break;
+            }
+            ranked.push((
+                score_replica(
+                    replica,
+                    self.latency_ewma_ms(&replica.node_id),
+                    self.in_flight_count(&replica.node_id),
+                    client_location,
+                    geo_resolver,
+                    query_type,
+                    &weights,
+                ),
+                replica,
+            ));
+        }
+
+        // Ties go to the lower node id rather than table iteration order
+        ranked.sort_by(|a, b| {
+            rank_order(strategy.rank_key(&a.0, a.1), strategy.rank_key(&b.0, b.1))
+                .then_with(|| a.1.node_id.cmp(&b.1.node_id))
+        });
+
+        if strategy == RoutingStrategy::PowerOfTwoChoices && ranked.len() >= 2 {
+            let n = ranked.len() as u64;
+            let draw = self.next_random();
+            let first = (draw % n) as usize;
+            // Drawn from the other n - 1, so the two never coincide
+            let mut second = ((draw >> 32) % (n - 1)) as usize;
+            if second >= first {
+                second += 1;
+            }
+            // The better scored of the two wins a load tie
+            let (better, worse) = (first.min(second), first.max(second));
+            let load = |i: usize| ranked[i].1.effective_load();
+            let pick = if rank_order(load(worse), load(better)).is_lt() {
+                worse
+            } else {
+                better
+            };
+            // The rest stay in score order as alternates
+            ranked[..=pick].rotate_right(1);
+        }
+        (ranked, truncated)
+    }
+
+    /// A fresh pseudo-random value; lock-free, and good enough to spread
+    /// load, not for anything secret
+    fn next_random(&self) -> u64 {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut z = self
+            .rng_state
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Up to `n` healthy replicas closest to `client_ip`, nearest first
+    ///
+    /// Pure geography: load, latency, leadership and query type are ignored,
+    /// so callers can apply their own policy. Each target's `score` is its
+    /// distance in km.
+    pub fn nearest_replicas(
+        &self,
+        client_ip: IpAddr,
+        n: usize,
+        geo_resolver: &GeoResolver,
+    ) -> Result<Vec<ReplicaTarget>, RoutingError> {
+        let client_location = geo_resolver.resolve(client_ip)?;
+
+        let mut nearest: Vec<_> = self
+            .replicas
+            .iter()
+            .filter(|entry| self.is_eligible(entry.key(), entry.value()))
+            .map(|entry| {
+                let replica = entry.value();
+                let distance_km =
+                    geo_resolver.calculate_distance_km(&client_location, &replica.geo_location);
+                ReplicaTarget {
+                    node_id: replica.node_id.clone(),
+                    host: replica.host.clone(),
+                    port: replica.port,
+                    zone: replica.zone.clone(),
+                    distance_km,
+                    score: distance_km,
+                }
+            })
+            .collect();
+
+        // Node id breaks ties so equidistant replicas come back in a stable order
+        nearest.sort_by(|a, b| {
+            rank_order(a.distance_km, b.distance_km).then_with(|| a.node_id.cmp(&b.node_id))
+        });
+        nearest.truncate(n);
+        Ok(nearest)
+    }
+
+    fn excluded_replicas(&self) -> Vec<ExcludedReplica> {
+        let mut excluded: Vec<_> = self
+            .replicas
+            .iter()
+            .filter_map(|entry| {
+                // Draining is deliberate, so report it even for unhealthy replicas
+                let reason = if self.drained.contains(entry.key()) {
+                    ExclusionReason::Drained
+                } else if !entry.value().healthy {
+                    ExclusionReason::Unhealthy
+                } else if self.probe_failed.contains(entry.key()) {
+                    ExclusionReason::ProbeFailed
+                } else {
+                    return None;
+                };
+                Some(ExcludedReplica {
+                    node_id: entry.key().clone(),
+                    zone: entry.value().zone.clone(),
+                    reason,
+                })
+            })
+            .collect();
+        excluded.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        excluded
+    }
+
+    /// Every replica with its drain, probe and latency state, sorted by
+    /// node id
+    pub fn list_replicas(&self) -> Vec<ReplicaState> {
+        let mut replicas: Vec<_> = self
+            .replicas
+            .iter()
+            .map(|entry| {
+                let (node_id, replica) = (entry.key(), entry.value());
+                ReplicaState {
+                    drained: self.drained.contains(node_id),
+                    probe_failed: self.probe_failed.contains(node_id),
+                    latency_ewma_ms: self.latency_ewma_ms(node_id),
+                    effective_load: replica.effective_load(),
+                    eligible: self.is_eligible(node_id, replica),
+                    selections: self.selection_count(node_id),
+                    in_flight: self.in_flight_count(node_id),
+                    replica: replica.clone(),
+                }
+            })
+            .collect();
+        replicas.sort_by(|a, b| a.replica.node_id.cmp(&b.replica.node_id));
+        replicas
+    }
+
+    pub fn get_replica_count(&self) -> usize {
+        self.replicas.len()
+    }
+
+    pub fn get_healthy_replica_count(&self) -> usize {
+        self.replicas
+            .iter()
+            .filter(|entry| entry.value().healthy)
+            .count()
+    }
+
+    /// Replicas routing may currently pick: healthy per the control plane
+    /// and the probe, and not drained
+    pub fn get_eligible_replica_count(&self) -> usize {
+        self.replicas
+            .iter()
+            .filter(|entry| self.is_eligible(entry.key(), entry.value()))
+            .count()
+    }
+
+    pub fn get_leader_count(&self) -> usize {
+        self.replicas
+            .iter()
+            .filter(|entry| entry.value().is_leader && entry.value().healthy)
+            .count()
+    }
+
+    /// Hold readiness until `warm_up` runs; engines start out warm
+    pub fn require_warm_up(&self) {
+        self.warmed_up.store(false, Ordering::Release);
+    }
+
+    pub fn is_warmed_up(&self) -> bool {
+        self.warmed_up.load(Ordering::Acquire)
+    }
+
+    /// Resolve representative clients and route once, so the first real
+    /// request doesn't pay for cold GeoIP pages and caches
+    ///
+    /// Failures are expected (e.g. no replicas pushed yet) and ignored; only
+    /// the work done along the way matters.
+    pub fn warm_up(&self, client_ips: &[IpAddr], geo_resolver: &GeoResolver) {
+        let resolved = client_ips
+            .iter()
+            .filter(|ip| geo_resolver.resolve(**ip).is_ok())
+            .count();
+
+        if let Some(&client_ip) = client_ips.first() {
+            let request = RoutingRequest {
+                client_ip,
+                timestamp: unix_time_secs() * 1_000_000,
+                ..Default::default()
+            };
+            let _ = self.route_request(&request, geo_resolver);
+        }
+
+        self.warmed_up.store(true, Ordering::Release);
+        tracing::info!(
+            "Warm-up resolved {} of {} client IPs",
+            resolved,
+            client_ips.len()
+        );
+    }
+}
+
+/// Least loaded of `replicas` able to serve `query_type`
+fn least_loaded<'a>(
+    replicas: impl Iterator<Item = &'a ReplicaInfo>,
+    query_type: QueryType,
+) -> Option<&'a ReplicaInfo> {
+    replicas
+        .filter(|replica| query_type == QueryType::Read || replica.is_leader)
+        .min_by(|a, b| {
+            rank_order(a.effective_load(), b.effective_load())
+                .then_with(|| a.node_id.cmp(&b.node_id))
+        })
+}
+
+/// Closest of `replicas` to the client, with its distance
+fn nearest_replica<'a>(
+    replicas: &'a [ReplicaInfo],
+    client_location: &GeoLocation,
+    geo_resolver: &GeoResolver,
+) -> Option<(f64, &'a ReplicaInfo)> {
+    replicas
+        .iter()
+        .map(|replica| {
+            let distance =
+                geo_resolver.calculate_distance_km(client_location, &replica.geo_location);
+            (distance, replica)
+        })
+        .min_by(|a, b| rank_order(a.0, b.0).then_with(|| a.1.node_id.cmp(&b.1.node_id)))
+}
+
+/// Why no replica could serve a `query_type` request
+fn no_replicas_error(query_type: QueryType) -> RoutingError {
+    match query_type {
+        QueryType::Write => RoutingError::NoHealthyLeaders,
+        QueryType::Read => RoutingError::NoHealthyReplicas,
+    }
+}
+
+/// Total order on scores, distances and loads, lowest first
+///
+/// A NaN (say from a replica reporting a NaN load) ranks after every number,
+/// infinity included: an overloaded replica is still a known quantity.
+/// `partial_cmp` would call it equal to everything, leaving selection to
+/// the order the table happened to be iterated in.
+fn rank_order(a: f64, b: f64) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.total_cmp(&b),
+    }
+}
+
+/// Write via a temp file and rename so a crash never leaves a torn snapshot
+fn write_snapshot(path: &Path, replicas: &[ReplicaInfo], generation: u64) -> Result<()> {
+    let snapshot = serde_json::json!({
+        "saved_at_secs": unix_time_secs(),
+        "generation": generation,
+        "replicas": replicas,
+    });
+
+    // Append rather than replace the extension, so `table.json` and
+    // `table.bak` don't share a temp file
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(&snapshot)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    // Make the rename itself durable
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Bump `node_id`'s counter, taking the map's write lock only for its
+/// first count
+fn increment(counters: &DashMap<String, AtomicU64>, node_id: &str) {
+    if let Some(count) = counters.get(node_id) {
+        count.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    counters
+        .entry(node_id.to_string())
+        .or_default()
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+fn score_replica(
+    replica: &ReplicaInfo,
+    latency_ewma_ms: Option<f64>,
+    in_flight: u64,
+    client_location: &GeoLocation,
+    geo_resolver: &GeoResolver,
+    query_type: QueryType,
+    weights: &ScoringWeights,
+) -> CandidateScore {
+    let distance_km = geo_resolver.calculate_distance_km(client_location, &replica.geo_location);
+    let distance_known = geo_resolver
+        .known_distance_km(client_location, &replica.geo_location)
+        .is_some();
+    let distance_penalty = if weights.ignore_unlocated_distance && !distance_known {
+        0.0
+    } else {
+        distance_km * weights.distance_km
+    };
+    let load_penalty = replica.effective_load() * weights.load_penalty;
+    let in_flight_penalty = in_flight as f64 * weights.in_flight_penalty;
+    let latency_ms = latency_ewma_ms.unwrap_or(replica.latency_ms);
+
+    let asn_match = client_location.asn.is_some() && client_location.asn == replica.asn;
+
+    // Reads also weigh latency, prefer leaders for consistency, prefer
+    // replicas on the client's own network and follow read weights
+    let (latency_penalty, leader_bonus, asn_bonus, read_weight_bonus) = match query_type {
+        QueryType::Write => (0.0, 0.0, 0.0, 0.0),
+        QueryType::Read => (
+            latency_ms * weights.latency_weight,
+            if replica.is_leader {
+                -weights.leader_bonus
+            } else {
+                0.0
+            },
+            if asn_match {
+                -weights.asn_match_bonus
+            } else {
+                0.0
+            },
+            (1.0 - replica.effective_read_weight()) * weights.read_weight_bonus,
+        ),
+    };
+
+    CandidateScore {
+        node_id: replica.node_id.clone(),
+        zone: replica.zone.clone(),
+        is_leader: replica.is_leader,
+        distance_km,
+        distance_penalty,
+        load_penalty,
+        latency_ms,
+        latency_ewma_ms,
+        latency_penalty,
+        leader_bonus,
+        asn_match,
+        asn_bonus,
+        read_weight_bonus,
+        in_flight,
+        in_flight_penalty,
+        score: distance_penalty
+            + load_penalty
+            + in_flight_penalty
+            + latency_penalty
+            + leader_bonus
+            + asn_bonus
+            + read_weight_bonus,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replica(node_id: &str, zone: &str, latitude: f64, healthy: bool) -> ReplicaInfo {
+        ReplicaInfo {
+            node_id: node_id.to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 9000,
+            is_leader: false,
+            healthy,
+            zone: zone.to_string(),
+            geo_location: GeoLocation {
+                latitude,
+                ..GeoLocation::default()
+            },
+            load_score: 0.0,
+            latency_ms: 0.0,
+            asn: None,
+            capacity_weight: 1.0,
+            read_weight: 1.0,
+        }
+    }
+
+    fn route(engine: &RoutingEngine) -> RoutingResponse {
+        let request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            ..Default::default()
+        };
+        engine
+            .route_request(&request, &GeoResolver::new(None).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_failover_follows_declared_order() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("east-1", "us-east", 1.0, false),
+                replica("eu-1", "eu-west", 2.0, true),
+                replica("west-1", "us-west", 10.0, true),
+            ])
+            .unwrap();
+
+        let resolver = origin_resolver();
+        let response = route_from(&engine, &resolver, "10.0.0.1", "read");
+        assert_eq!(response.node_id, "eu-1");
+        assert!(response.failover_path.is_empty());
+        assert!(response.alternates.is_empty());
+
+        engine.set_failover_order(
+            "us-east".to_string(),
+            vec!["us-west".to_string(), "eu-west".to_string()],
+        );
+
+        let response = route_from(&engine, &resolver, "10.0.0.1", "read");
+        assert_eq!(response.node_id, "west-1");
+        assert_eq!(response.routing_strategy, "zone_failover");
+        assert_eq!(response.failover_path, vec!["us-east", "us-west"]);
+    }
+
+    #[test]
+    fn test_failover_skipped_when_nearest_zone_healthy() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("east-1", "us-east", 1.0, true),
+                replica("west-1", "us-west", 10.0, true),
+            ])
+            .unwrap();
+        engine.set_failover_order("us-east".to_string(), vec!["us-west".to_string()]);
+
+        let response = route(&engine);
+        assert_eq!(response.node_id, "east-1");
+        assert_eq!(response.routing_strategy, "closest_healthy");
+    }
+
+    #[test]
+    fn test_explain_lists_ranked_candidates() {
+        let engine = RoutingEngine::new();
+        let mut leader = replica("leader", "us-east", 1.0, true);
+        leader.is_leader = true;
+        leader.load_score = 0.5;
+        engine
+            .update_replicas(vec![leader, replica("follower", "us-east", 0.5, true)])
+            .unwrap();
+
+        let request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            explain: true,
+            candidates: 2,
+            ..Default::default()
+        };
+        let response = engine
+            .route_request(&request, &GeoResolver::new(None).unwrap())
+            .unwrap();
+
+        assert_eq!(response.alternates.len(), 1);
+        assert_ne!(response.alternates[0].node_id, response.node_id);
+
+        let explain = response.explain.unwrap();
+        assert_eq!(explain.selected, response.node_id);
+        assert_eq!(
+            explain.candidates[1].node_id,
+            response.alternates[0].node_id
+        );
+        assert_eq!(explain.candidates.len(), 2);
+        assert_eq!(explain.candidates[0].node_id, response.node_id);
+        assert!(explain.candidates[0].score <= explain.candidates[1].score);
+
+        let leader = explain.candidates.iter().find(|c| c.is_leader).unwrap();
+        assert_eq!(leader.leader_bonus, -50.0);
+        assert_eq!(leader.load_penalty, 50.0);
+    }
+
+    #[test]
+    fn test_zone_locality() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("east", "us-east", 1.0, true),
+                replica("west-down", "us-west", 2.0, false),
+                replica("eu", "eu-central", 50.0, true),
+            ])
+            .unwrap();
+
+        let resolver = origin_resolver();
+        let request = |zone_locality, client_zone: Option<&str>| RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            zone_locality,
+            client_zone: client_zone.map(str::to_string),
+            ..Default::default()
+        };
+
+        // An explicit zone overrides the nearest one
+        let response = engine
+            .route_request(&request(ZoneLocality::Strict, Some("eu-central")), &resolver)
+            .unwrap();
+        assert_eq!(response.node_id, "eu");
+        assert_eq!(response.routing_strategy, "zone_local");
+
+        assert!(matches!(
+            engine.route_request(&request(ZoneLocality::Strict, Some("us-west")), &resolver),
+            Err(RoutingError::ZoneUnavailable(zone)) if zone == "us-west"
+        ));
+
+        let response = engine
+            .route_request(&request(ZoneLocality::Preferred, Some("us-west")), &resolver)
+            .unwrap();
+        assert_eq!(response.node_id, "east");
+        assert_eq!(response.routing_strategy, "closest_healthy");
+
+        // Derived zone: "east" is nearest to the client at (0, 0)
+        let response = engine
+            .route_request(&request(ZoneLocality::Strict, None), &resolver)
+            .unwrap();
+        assert_eq!(response.node_id, "east");
+        assert_eq!(response.client_zone.as_deref(), Some("us-east"));
+
+        // A client GeoIP couldn't place has no zone to derive
+        let response = engine
+            .route_request(
+                &request(ZoneLocality::Preferred, None),
+                &GeoResolver::new(None).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(response.client_zone, None);
+        assert!(!response.served_from_client_zone);
+
+        // Nor can it insist on one, and waiting won't change that
+        let error = engine
+            .route_request(
+                &request(ZoneLocality::Strict, None),
+                &GeoResolver::new(None).unwrap(),
+            )
+            .unwrap_err();
+        assert!(matches!(error, RoutingError::ClientZoneUnknown));
+        assert_eq!(error.code(), "CLIENT_ZONE_UNKNOWN");
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn test_preferred_zones_override_geography() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("east", "us-east", 1.0, true),
+                replica("west-down", "us-west", 2.0, false),
+                replica("eu", "eu-central", 50.0, true),
+            ])
+            .unwrap();
+
+        let resolver = origin_resolver();
+        let request = |preferred_zones: &[&str]| RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            zone_locality: ZoneLocality::Strict,
+            preferred_zones: preferred_zones
+                .iter()
+                .map(|zone| zone.to_string())
+                .collect(),
+            ..Default::default()
+        };
+
+        // "us-west" has nothing healthy, so the next preference is used even
+        // though "east" is nearer
+        let response = engine
+            .route_request(&request(&["us-west", "eu-central"]), &resolver)
+            .unwrap();
+        assert_eq!(response.node_id, "eu");
+        assert_eq!(response.routing_strategy, "preferred_zone");
+        assert_eq!(response.preferred_zone.as_deref(), Some("eu-central"));
+        assert!(!response.served_from_client_zone);
+
+        // No preference satisfied falls back to the geographic logic
+        let response = engine
+            .route_request(&request(&["us-west", "ap-south"]), &resolver)
+            .unwrap();
+        assert_eq!(response.node_id, "east");
+        assert_eq!(response.routing_strategy, "zone_local");
+        assert_eq!(response.preferred_zone, None);
+
+        // So does the deadline fallback
+        let mut late = request(&["eu-central"]);
+        late.deadline_micros = Some(0);
+        let response = engine.route_request(&late, &resolver).unwrap();
+        assert_eq!(response.node_id, "eu");
+        assert_eq!(response.preferred_zone.as_deref(), Some("eu-central"));
+    }
+
+    #[test]
+    fn test_typed_errors_distinguish_reads_and_writes() {
+        let engine = RoutingEngine::new();
+        let mut request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            ..Default::default()
+        };
+        let resolver = GeoResolver::new(None).unwrap();
+        assert!(matches!(
+            engine.route_request(&request, &resolver),
+            Err(RoutingError::NoHealthyReplicas)
+        ));
+
+        engine
+            .update_replicas(vec![replica("follower", "us-east", 1.0, true)])
+            .unwrap();
+        request.query_type = "write".to_string();
+        let error = engine.route_request(&request, &resolver).unwrap_err();
+        assert!(matches!(error, RoutingError::NoHealthyLeaders));
+        assert_eq!(error.code(), "NO_HEALTHY_LEADERS");
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn test_drained_replica_is_skipped_and_explained() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("near", "us-east", 1.0, true),
+                replica("far", "us-east", 5.0, true),
+                replica("down", "us-east", 0.5, false),
+            ])
+            .unwrap();
+        assert!(engine.drain_replica("missing").is_err());
+
+        engine.drain_replica("near").unwrap();
+        let request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            explain: true,
+            ..Default::default()
+        };
+        let response = engine
+            .route_request(&request, &GeoResolver::new(None).unwrap())
+            .unwrap();
+        assert_eq!(response.node_id, "far");
+
+        let excluded = response.explain.unwrap().excluded;
+        assert_eq!(excluded.len(), 2);
+        assert_eq!(excluded[0].node_id, "down");
+        assert_eq!(excluded[0].reason, ExclusionReason::Unhealthy);
+        assert_eq!(excluded[1].node_id, "near");
+        assert_eq!(excluded[1].reason, ExclusionReason::Drained);
+
+        // Draining outlives a table push that still lists the replica
+        engine
+            .update_replicas(vec![
+                replica("near", "us-east", 1.0, true),
+                replica("far", "us-east", 5.0, true),
+            ])
+            .unwrap();
+        assert!(engine.is_drained("near"));
+
+        engine.undrain_replica("near").unwrap();
+        assert_eq!(route(&engine).node_id, "near");
+        assert!(engine.undrain_replica("near").is_err());
+    }
+
+    #[test]
+    fn test_eligible_count_excludes_probe_failures_and_drains() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("a", "us-east", 1.0, true),
+                replica("b", "us-east", 5.0, true),
+                replica("c", "us-west", 9.0, false),
+            ])
+            .unwrap();
+        assert_eq!(engine.get_healthy_replica_count(), 2);
+        assert_eq!(engine.get_eligible_replica_count(), 2);
+
+        // Reported healthy, but nothing left to route to
+        engine.set_probe_result("a", false);
+        engine.set_probe_result("b", false);
+        assert_eq!(engine.get_healthy_replica_count(), 2);
+        assert_eq!(engine.get_eligible_replica_count(), 0);
+
+        engine.set_probe_result("b", true);
+        assert_eq!(engine.get_eligible_replica_count(), 1);
+
+        engine.drain_replica("b").unwrap();
+        assert_eq!(engine.get_eligible_replica_count(), 0);
+        engine.undrain_replica("b").unwrap();
+        assert_eq!(engine.get_eligible_replica_count(), 1);
+    }
+
+    #[test]
+    fn test_failed_probe_overrides_reported_health() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("near", "us-east", 1.0, true),
+                replica("far", "us-east", 5.0, true),
+            ])
+            .unwrap();
+
+        engine.set_probe_result("near", false);
+        assert!(!engine.is_probe_healthy("near"));
+        let request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            explain: true,
+            ..Default::default()
+        };
+        let response = engine
+            .route_request(&request, &GeoResolver::new(None).unwrap())
+            .unwrap();
+        assert_eq!(response.node_id, "far");
+        let excluded = response.explain.unwrap().excluded;
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].reason, ExclusionReason::ProbeFailed);
+
+        engine.set_probe_result("near", true);
+        assert_eq!(route(&engine).node_id, "near");
+
+        // Results for unknown replicas don't linger until they appear
+        engine.set_probe_result("missing", false);
+        assert!(engine.is_probe_healthy("missing"));
+    }
+
+    #[test]
+    fn test_affinity_rules_precede_geographic_scoring() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("near", "us-east", 1.0, true),
+                replica("far", "eu-west", 40.0, true),
+                replica("pinned", "ap-south", 60.0, true),
+            ])
+            .unwrap();
+        engine.set_affinity_rules(vec![
+            AffinityRule {
+                matcher: AffinityMatch::Key {
+                    key: "tenant-a".to_string(),
+                },
+                target: AffinityTarget::Node {
+                    node_id: "pinned".to_string(),
+                },
+            },
+            AffinityRule {
+                matcher: AffinityMatch::HashRange {
+                    start: 0,
+                    end: u64::MAX,
+                },
+                target: AffinityTarget::Zone {
+                    zone: "eu-west".to_string(),
+                },
+            },
+        ]);
+
+        let request = |affinity_key: Option<&str>| RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            zone_locality: ZoneLocality::Strict,
+            affinity_key: affinity_key.map(str::to_string),
+            ..Default::default()
+        };
+        let resolver = origin_resolver();
+
+        let response = engine.route_request(&request(Some("tenant-a")), &resolver).unwrap();
+        assert_eq!(response.node_id, "pinned");
+        assert_eq!(response.routing_strategy, "affinity");
+
+        // Falls through to the catch-all range rule
+        let response = engine.route_request(&request(Some("tenant-b")), &resolver).unwrap();
+        assert_eq!(response.node_id, "far");
+
+        let response = engine.route_request(&request(None), &resolver).unwrap();
+        assert_eq!(response.node_id, "near");
+
+        // A target with nothing eligible falls back to normal routing
+        engine.drain_replica("pinned").unwrap();
+        let response = engine.route_request(&request(Some("tenant-a")), &resolver).unwrap();
+        assert_eq!(response.node_id, "near");
+        assert_eq!(response.routing_strategy, "zone_local");
+    }
+
+    #[test]
+    fn test_affinity_hash_is_stable() {
+        assert_eq!(affinity_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(affinity_hash("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip_and_staleness() {
+        let path = std::env::temp_dir().join(format!(
+            "geo_router_snapshot_test_{}.json",
+            std::process::id()
+        ));
+
+        let mut engine = RoutingEngine::new();
+        engine.set_snapshot_path(path.clone());
+        engine
+            .update_replicas_at(
+                vec![
+                    replica("east-1", "us-east", 1.0, true),
+                    replica("west-1", "us-west", 10.0, true),
+                ],
+                Some(4),
+            )
+            .unwrap();
+        assert!(!path.with_extension("json.tmp").exists());
+
+        let restored = RoutingEngine::new();
+        assert!(restored
+            .load_snapshot(&path, Duration::from_secs(60))
+            .unwrap());
+        assert_eq!(restored.get_replica_count(), 2);
+        assert_eq!(restored.table_generation(), 4);
+
+        let stale = RoutingSnapshot {
+            saved_at_secs: unix_time_secs() - 120,
+            generation: 0,
+            replicas: vec![replica("east-1", "us-east", 1.0, true)],
+        };
+        std::fs::write(&path, serde_json::to_vec(&stale).unwrap()).unwrap();
+
+        let restored = RoutingEngine::new();
+        assert!(!restored
+            .load_snapshot(&path, Duration::from_secs(60))
+            .unwrap());
+        assert_eq!(restored.get_replica_count(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_asn_match_stacks_with_distance() {
+        let client = GeoLocation {
+            asn: Some(16509),
+            ..GeoLocation::default()
+        };
+        let mut same_asn = replica("same-asn", "us-east", 1.0, true);
+        same_asn.asn = Some(16509);
+        let other_asn = replica("other-asn", "us-east", 1.0, true);
+
+        let resolver = GeoResolver::new(None).unwrap();
+        let weights = ScoringWeights::default();
+        let matched = score_replica(&same_asn, None, 0, &client, &resolver, QueryType::Read, &weights);
+        let unmatched =
+            score_replica(&other_asn, None, 0, &client, &resolver, QueryType::Read, &weights);
+
+        assert!(matched.asn_match);
+        assert!(!unmatched.asn_match);
+        assert_eq!(matched.distance_km, unmatched.distance_km);
+        assert_eq!(matched.score, unmatched.score - weights.asn_match_bonus);
+
+        // Unknown ASNs never match each other
+        let unknown = score_replica(
+            &other_asn,
+            None,
+            0,
+            &GeoLocation::default(),
+            &resolver,
+            QueryType::Read,
+            &weights,
+        );
+        assert!(!unknown.asn_match);
+    }
+
+    #[test]
+    fn test_scoring_weights_change_selection() {
+        let engine = RoutingEngine::new();
+        let mut leader = replica("leader", "us-east", 1.0, true);
+        leader.is_leader = true;
+        engine
+            .update_replicas(vec![leader, replica("follower", "us-east", 0.5, true)])
+            .unwrap();
+
+        // ~111km vs ~56km: the default 50km leader bonus is not enough
+        assert_eq!(route(&engine).node_id, "follower");
+
+        engine.set_scoring_weights(ScoringWeights {
+            leader_bonus: 100.0,
+            ..ScoringWeights::default()
+        });
+        assert_eq!(route(&engine).node_id, "leader");
+    }
+
+    #[test]
+    fn test_unlocated_client_ignores_distance_when_enabled() {
+        let engine = RoutingEngine::new();
+        let mut busy = replica("busy", "us-east", 1.0, true);
+        busy.load_score = 0.5;
+        engine
+            .update_replicas(vec![busy, replica("idle", "eu-west", 5.0, true)])
+            .unwrap();
+
+        // No GeoIP database: distance from the placeholder (0, 0) decides
+        assert_eq!(route(&engine).node_id, "busy");
+
+        engine.set_scoring_weights(ScoringWeights {
+            ignore_unlocated_distance: true,
+            ..ScoringWeights::default()
+        });
+        assert_eq!(route(&engine).node_id, "idle");
+    }
+
+    #[test]
+    fn test_unplaced_replica_ignores_distance_when_enabled() {
+        let engine = RoutingEngine::new();
+        let mut busy = replica("busy", "eu-central", 0.0, true);
+        busy.geo_location = placed(47.5, 19.05);
+        busy.load_score = 0.5;
+        engine
+            .update_replicas(vec![busy, replica("unplaced", "eu-west", 0.0, true)])
+            .unwrap();
+        let geo_resolver = GeoResolver::from_static(HashMap::from([(
+            "198.51.100.1".parse().unwrap(),
+            placed(47.4979, 19.0402),
+        )]));
+
+        assert_eq!(
+            route_from(&engine, &geo_resolver, "198.51.100.1", "read").node_id,
+            "busy"
+        );
+
+        engine.set_scoring_weights(ScoringWeights {
+            ignore_unlocated_distance: true,
+            ..ScoringWeights::default()
+        });
+        assert_eq!(
+            route_from(&engine, &geo_resolver, "198.51.100.1", "read").node_id,
+            "unplaced"
+        );
+    }
+
+    #[test]
+    fn test_read_weight_shifts_reads_to_weighted_follower() {
+        let engine = RoutingEngine::new();
+        let mut leader = replica("leader", "us-east", 10.0, true);
+        leader.is_leader = true;
+        let mut analytics = replica("analytics", "us-east", 1.0, true);
+        analytics.load_score = 0.2;
+        let replicas = vec![leader, analytics, replica("oltp", "us-east", 1.0, true)];
+        engine.update_replicas(replicas.clone()).unwrap();
+        assert_eq!(route(&engine).node_id, "oltp");
+
+        // 20km of extra load penalty against a 50km read weight bonus
+        let mut weighted = replicas;
+        weighted[1].read_weight = 2.0;
+        engine.update_replicas(weighted).unwrap();
+        assert_eq!(route(&engine).node_id, "analytics");
+
+        let geo_resolver = GeoResolver::new(None).unwrap();
+        assert_eq!(
+            route_from(&engine, &geo_resolver, "10.0.0.1", "write").node_id,
+            "leader"
+        );
+    }
+
+    #[test]
+    fn test_forward_via_suggested_for_distant_leader() {
+        let engine = RoutingEngine::new();
+        let mut leader = replica("leader", "eu-west", 10.0, true);
+        leader.is_leader = true;
+        engine
+            .update_replicas(vec![leader, replica("local", "us-east", 1.0, true)])
+            .unwrap();
+
+        let request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            query_type: "write".to_string(),
+            ..Default::default()
+        };
+        let resolver = GeoResolver::new(None).unwrap();
+
+        let response = engine.route_request(&request, &resolver).unwrap();
+        assert!(response.forward_via.is_none());
+
+        // ~1112km to the leader vs ~111km to the local replica
+        engine.set_write_forward_margin(Some(500.0));
+        let response = engine.route_request(&request, &resolver).unwrap();
+        assert_eq!(response.node_id, "leader");
+        let forward_via = response.forward_via.unwrap();
+        assert_eq!(forward_via.node_id, "local");
+        assert!(forward_via.score > 500.0);
+
+        engine.set_write_forward_margin(Some(2_000.0));
+        let response = engine.route_request(&request, &resolver).unwrap();
+        assert!(response.forward_via.is_none());
+    }
+
+    #[test]
+    fn test_route_cache_hits_until_replicas_change() {
+        let mut engine = RoutingEngine::new();
+        engine.set_route_cache(16, Duration::from_secs(60));
+        engine
+            .update_replicas(vec![replica("a", "us-east", 1.0, true)])
+            .unwrap();
+
+        assert!(!route(&engine).cached);
+        assert!(route(&engine).cached);
+        assert_eq!(engine.selection_count("a"), 2);
+
+        engine
+            .update_replicas(vec![
+                replica("b", "us-east", 1.0, true),
+                replica("c", "us-east", 2.0, true),
+            ])
+            .unwrap();
+        let response = route(&engine);
+        assert!(!response.cached);
+        assert_eq!(response.node_id, "b");
+        assert!(route(&engine).cached);
+        assert_eq!(engine.selection_count("a"), 0);
+
+        engine.drain_replica("b").unwrap();
+        let response = route(&engine);
+        assert!(!response.cached);
+        assert_eq!(response.node_id, "c");
+    }
+
+    #[test]
+    fn test_capacity_weight_scales_load() {
+        let engine = RoutingEngine::new();
+        let mut small = replica("small", "us-east", 1.0, true);
+        small.load_score = 0.5;
+        let mut large = replica("large", "us-east", 1.0, true);
+        large.load_score = 0.8;
+        large.capacity_weight = 2.0;
+        assert_eq!(large.effective_load(), 0.4);
+
+        engine.update_replicas(vec![small, large.clone()]).unwrap();
+        assert_eq!(route(&engine).node_id, "large");
+
+        large.capacity_weight = 0.0;
+        assert_eq!(large.effective_load(), 0.8);
+    }
+
+    #[test]
+    fn test_strategy_override_changes_ranking() {
+        let engine = RoutingEngine::new();
+        let mut busy = replica("busy", "us-east", 0.5, true);
+        busy.load_score = 5.0;
+        engine
+            .update_replicas(vec![busy, replica("idle", "us-east", 3.0, true)])
+            .unwrap();
+
+        let mut request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            ..Default::default()
+        };
+        let geo_resolver = GeoResolver::new(None).unwrap();
+        let response = engine.route_request(&request, &geo_resolver).unwrap();
+        assert_eq!(response.node_id, "idle");
+        assert_eq!(response.routing_strategy, "closest_healthy");
+
+        request.strategy = Some(RoutingStrategy::Nearest);
+        let response = engine.route_request(&request, &geo_resolver).unwrap();
+        assert_eq!(response.node_id, "busy");
+        assert_eq!(response.routing_strategy, "nearest:closest_healthy");
+
+        request.strategy = Some(RoutingStrategy::parse("least_loaded").unwrap());
+        let response = engine.route_request(&request, &geo_resolver).unwrap();
+        assert_eq!(response.node_id, "idle");
+
+        let err = RoutingStrategy::parse("round_robin").unwrap_err();
+        assert_eq!(err.code(), "UNKNOWN_STRATEGY");
+    }
+
+    #[test]
+    fn test_power_of_two_choices_spreads_load() {
+        // Load reports that lag a burst of 800 requests, as between pushes
+        let replicas: Vec<_> = (0..8)
+            .map(|i| {
+                let mut replica = replica(&format!("r{}", i), "us-east", 1.0, true);
+                replica.load_score = 0.1 * i as f64;
+                replica
+            })
+            .collect();
+        let geo_resolver = GeoResolver::new(None).unwrap();
+        let busiest = |strategy| {
+            let engine = RoutingEngine::new();
+            engine.update_replicas(replicas.clone()).unwrap();
+            let request = RoutingRequest {
+                client_ip: "10.0.0.1".parse().unwrap(),
+                strategy,
+                ..Default::default()
+            };
+            for _ in 0..800 {
+                engine.route_request(&request, &geo_resolver).unwrap();
+            }
+            let counts: Vec<_> = replicas
+                .iter()
+                .map(|replica| engine.selection_count(&replica.node_id))
+                .collect();
+            assert_eq!(counts.iter().sum::<u64>(), 800);
+            (
+                counts.iter().copied().max().unwrap(),
+                counts.iter().filter(|&&count| count > 0).count(),
+            )
+        };
+
+        // Greedy selection sends the whole burst to the least loaded replica
+        assert_eq!(busiest(None), (800, 1));
+
+        // Each replica but the most loaded wins whenever it's drawn against
+        // a busier one; the least loaded expects a quarter of the burst
+        let (max, used) = busiest(Some(RoutingStrategy::PowerOfTwoChoices));
+        assert!(max < 320, "busiest replica took {} of 800", max);
+        assert_eq!(used, 7);
+
+        assert_eq!(
+            RoutingStrategy::parse("p2c").unwrap(),
+            RoutingStrategy::PowerOfTwoChoices
+        );
+    }
+
+    #[test]
+    fn test_spent_deadline_skips_scoring() {
+        let engine = RoutingEngine::new();
+        let mut busy = replica("busy", "us-east", 1.0, true);
+        busy.load_score = 0.9;
+        let mut idle = replica("idle", "eu-west", 50.0, true);
+        idle.load_score = 0.1;
+        engine.update_replicas(vec![busy, idle]).unwrap();
+
+        let mut request = RoutingRequest {
+            client_ip: "10.0.0.1".parse().unwrap(),
+            deadline_micros: Some(60_000_000),
+            ..Default::default()
+        };
+        let resolver = GeoResolver::new(None).unwrap();
+
+        let response = engine.route_request(&request, &resolver).unwrap();
+        assert_eq!(response.node_id, "busy");
+        assert!(!response.degraded);
+
+        request.deadline_micros = Some(0);
+        let response = engine.route_request(&request, &resolver).unwrap();
+        assert_eq!(response.node_id, "idle");
+        assert_eq!(response.routing_strategy, "any_healthy");
+        assert!(response.degraded);
+
+        request.client_zone = Some("us-east".to_string());
+        let response = engine.route_request(&request, &resolver).unwrap();
+        assert_eq!(response.node_id, "busy");
+        assert!(response.degraded);
+
+        request.client_zone = Some("ap-south".to_string());
+        request.zone_locality = ZoneLocality::Strict;
+        assert!(matches!(
+            engine.route_request(&request, &resolver),
+            Err(RoutingError::ZoneUnavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_nearest_replicas_ignore_load_and_leadership() {
+        let engine = RoutingEngine::new();
+        let mut leader = replica("leader", "eu-west", 30.0, true);
+        leader.is_leader = true;
+        let mut busy = replica("busy", "us-east", 1.0, true);
+        busy.load_score = 10.0;
+        engine
+            .update_replicas(vec![
+                leader,
+                busy,
+                replica("mid", "us-east", 10.0, true),
+                replica("down", "us-east", 0.5, false),
+            ])
+            .unwrap();
+        engine.drain_replica("mid").unwrap();
+
+        let geo_resolver = GeoResolver::new(None).unwrap();
+        let nearest = engine
+            .nearest_replicas("10.0.0.1".parse().unwrap(), 5, &geo_resolver)
+            .unwrap();
+        let ids: Vec<_> = nearest
+            .iter()
+            .map(|target| target.node_id.as_str())
+            .collect();
+        assert_eq!(ids, ["busy", "leader"]);
+        assert!(nearest[0].distance_km < nearest[1].distance_km);
+        assert_eq!(nearest[0].score, nearest[0].distance_km);
+
+        let nearest = engine
+            .nearest_replicas("10.0.0.1".parse().unwrap(), 1, &geo_resolver)
+            .unwrap();
+        assert_eq!(nearest.len(), 1);
+    }
+
+    fn placed(latitude: f64, longitude: f64) -> GeoLocation {
+        GeoLocation {
+            latitude,
+            longitude,
+            located: true,
+            ..GeoLocation::default()
+        }
+    }
+
+    /// Places 10.0.0.1 at (0, 0), where unlocated clients are scored from,
+    /// but as a real position it also has a nearest zone
+    fn origin_resolver() -> GeoResolver {
+        GeoResolver::from_static(HashMap::from([(
+            "10.0.0.1".parse().unwrap(),
+            placed(0.0, 0.0),
+        )]))
+    }
+
+    /// Clients in Budapest and New York against leaders in Frankfurt and
+    /// New York and a follower in Vienna
+    fn placed_engine() -> (RoutingEngine, GeoResolver) {
+        let mut frankfurt = replica("frankfurt", "eu-central", 0.0, true);
+        frankfurt.is_leader = true;
+        frankfurt.geo_location = placed(50.1109, 8.6821);
+        let mut new_york = replica("new-york", "us-east", 0.0, true);
+        new_york.is_leader = true;
+        new_york.geo_location = placed(40.7128, -74.0060);
+        let mut vienna = replica("vienna", "eu-central", 0.0, true);
+        vienna.geo_location = placed(48.2082, 16.3738);
+
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![frankfurt, new_york, vienna])
+            .unwrap();
+        let geo_resolver = GeoResolver::from_static(HashMap::from([
+            ("198.51.100.1".parse().unwrap(), placed(47.4979, 19.0402)),
+            ("203.0.113.1".parse().unwrap(), placed(40.7306, -73.9352)),
+        ]));
+        (engine, geo_resolver)
+    }
+
+    fn route_from(
+        engine: &RoutingEngine,
+        geo_resolver: &GeoResolver,
+        client_ip: &str,
+        query_type: &str,
+    ) -> RoutingResponse {
+        let request = RoutingRequest {
+            client_ip: client_ip.parse().unwrap(),
+            query_type: query_type.to_string(),
+            ..Default::default()
+        };
+        engine.route_request(&request, geo_resolver).unwrap()
+    }
+
+    #[test]
+    fn test_writes_go_to_closest_leader() {
+        let (engine, geo_resolver) = placed_engine();
+
+        let response = route_from(&engine, &geo_resolver, "198.51.100.1", "write");
+        assert_eq!(response.node_id, "frankfurt");
+        assert!((response.distance_km - 811.0).abs() < 5.0);
+
+        let response = route_from(&engine, &geo_resolver, "203.0.113.1", "write");
+        assert_eq!(response.node_id, "new-york");
+    }
+
+    #[test]
+    fn test_reads_go_to_closest_replica() {
+        let (engine, geo_resolver) = placed_engine();
+
+        // Vienna is over 500 km closer than the Frankfurt leader, far more
+        // than the leader bonus
+        let response = route_from(&engine, &geo_resolver, "198.51.100.1", "read");
+        assert_eq!(response.node_id, "vienna");
+        assert!((response.distance_km - 214.0).abs() < 5.0);
+
+        let response = route_from(&engine, &geo_resolver, "203.0.113.1", "read");
+        assert_eq!(response.node_id, "new-york");
+    }
+
+    #[test]
+    fn test_response_reports_serving_outside_client_zone() {
+        let (engine, geo_resolver) = placed_engine();
+
+        let response = route_from(&engine, &geo_resolver, "203.0.113.1", "read");
+        assert_eq!(response.client_zone.as_deref(), Some("us-east"));
+        assert!(response.served_from_client_zone);
+
+        // With New York drained the client still counts as in us-east
+        engine.drain_replica("new-york").unwrap();
+        let response = route_from(&engine, &geo_resolver, "203.0.113.1", "read");
+        assert_eq!(response.zone, "eu-central");
+        assert_eq!(response.client_zone.as_deref(), Some("us-east"));
+        assert!(!response.served_from_client_zone);
+    }
+
+    #[test]
+    fn test_warm_up_gates_readiness() {
+        let engine = RoutingEngine::new();
+        assert!(engine.is_warmed_up());
+
+        engine.require_warm_up();
+        assert!(!engine.is_warmed_up());
+
+        // Routing fails without replicas, which must not block warm-up
+        engine.warm_up(
+            &["10.0.0.1".parse().unwrap()],
+            &GeoResolver::new(None).unwrap(),
+        );
+        assert!(engine.is_warmed_up());
+    }
+
+    #[test]
+    fn test_reported_latency_replaces_static_latency() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("near", "us-east", 1.0, true),
+                replica("far", "us-east", 1.5, true),
+            ])
+            .unwrap();
+        assert!(engine.report_latency("missing", 1_000).is_err());
+        assert_eq!(route(&engine).node_id, "near");
+
+        // ~111km vs ~167km until near's observed 200ms outweighs the gap
+        engine.report_latency("near", 200_000).unwrap();
+        assert_eq!(engine.latency_ewma_ms("near"), Some(200.0));
+        assert_eq!(route(&engine).node_id, "far");
+
+        engine.report_latency("near", 0).unwrap();
+        assert_eq!(engine.latency_ewma_ms("near"), Some(160.0));
+
+        // Averages are dropped along with their replica
+        engine
+            .update_replicas(vec![replica("far", "us-east", 1.5, true)])
+            .unwrap();
+        assert_eq!(engine.latency_ewma_ms("near"), None);
+    }
+
+    #[test]
+    fn test_in_flight_requests_spread_a_burst() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("a", "us-east", 1.0, true),
+                replica("b", "us-east", 1.1, true),
+            ])
+            .unwrap();
+
+        // Off by default, so a burst all lands on the nearest replica
+        assert_eq!(route(&engine).node_id, "a");
+        assert_eq!(route(&engine).node_id, "a");
+        assert_eq!(engine.in_flight_count("a"), 0);
+
+        engine.set_scoring_weights(ScoringWeights {
+            in_flight_penalty: 50.0,
+            ..ScoringWeights::default()
+        });
+        let burst: Vec<_> = (0..4).map(|_| route(&engine).node_id).collect();
+        assert_eq!(burst, ["a", "b", "a", "b"]);
+        assert_eq!(engine.in_flight_count("a"), 2);
+        assert_eq!(engine.list_replicas()[1].in_flight, 2);
+
+        // Extra reports don't push the count below zero
+        for _ in 0..3 {
+            engine.report_done("b").unwrap();
+        }
+        assert_eq!(engine.in_flight_count("b"), 0);
+        assert_eq!(route(&engine).node_id, "b");
+        assert!(engine.report_done("missing").is_err());
+    }
+
+    #[test]
+    fn test_unreported_in_flight_requests_decay() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("a", "us-east", 1.0, true),
+                replica("b", "us-east", 1.1, true),
+            ])
+            .unwrap();
+        engine.set_scoring_weights(ScoringWeights {
+            in_flight_penalty: 50.0,
+            ..ScoringWeights::default()
+        });
+
+        // Never reported done, as from a client that crashed
+        for _ in 0..6 {
+            route(&engine);
+        }
+        assert_eq!(engine.in_flight_count("a"), 3);
+
+        engine.decay_in_flight();
+        assert_eq!(engine.in_flight_count("a"), 1);
+        engine.decay_in_flight();
+        assert_eq!(engine.in_flight_count("a"), 0);
+        assert_eq!(engine.in_flight_count("b"), 0);
+        assert_eq!(route(&engine).node_id, "a");
+    }
+
+    #[test]
+    fn test_in_flight_penalty_bypasses_route_cache() {
+        let mut engine = RoutingEngine::new();
+        engine.set_route_cache(16, Duration::from_secs(60));
+        engine
+            .update_replicas(vec![
+                replica("a", "us-east", 1.0, true),
+                replica("b", "us-east", 1.1, true),
+            ])
+            .unwrap();
+        engine.set_scoring_weights(ScoringWeights {
+            in_flight_penalty: 50.0,
+            ..ScoringWeights::default()
+        });
+
+        // One client subnet, yet the burst still spreads
+        let burst: Vec<_> = (0..4).map(|_| route(&engine)).collect();
+        let node_ids: Vec<_> = burst
+            .iter()
+            .map(|response| response.node_id.as_str())
+            .collect();
+        assert_eq!(node_ids, ["a", "b", "a", "b"]);
+        assert!(burst.iter().all(|response| !response.cached));
+    }
+
+    #[test]
+    fn test_patch_changes_only_listed_replicas() {
+        let engine = RoutingEngine::new();
+        engine
+            .update_replicas(vec![
+                replica("east-1", "us-east", 1.0, true),
+                replica("east-2", "us-east", 2.0, true),
+                replica("west-1", "us-west", 3.0, true),
+            ])
+            .unwrap();
+        engine.drain_replica("east-2").unwrap();
+        engine.report_latency("west-1", 4_000).unwrap();
+
+        engine
+            .patch_replicas(
+                vec![
+                    replica("east-1", "us-west", 1.0, true),
+                    replica("north-1", "ca-central", 5.0, true),
+                ],
+                vec!["west-1".to_string(), "missing".to_string()],
+            )
+            .unwrap();
+
+        assert_eq!(engine.get_replica_count(), 3);
+        assert!(engine.is_drained("east-2"));
+        assert_eq!(engine.latency_ewma_ms("west-1"), None);
+        assert_eq!(
+            engine.zone_replicas.get("us-west").unwrap().as_slice(),
+            ["east-1"]
+        );
+        assert_eq!(
+            engine.zone_replicas.get("us-east").unwrap().as_slice(),
+            ["east-2"]
+        );
+        assert!(engine.zone_replicas.contains_key("ca-central"));
+
+        engine
+            .patch_replicas(Vec::new(), vec!["east-2".to_string()])
+            .unwrap();
+        assert!(!engine.zone_replicas.contains_key("us-east"));
+        assert!(!engine.is_drained("east-2"));
+    }
+
+    #[test]
+    fn test_list_replicas_reports_engine_state() {
+        let engine = RoutingEngine::new();
+        let mut busy = replica("busy", "us-east", 1.0, true);
+        busy.load_score = 0.8;
+        busy.capacity_weight = 2.0;
+        engine
+            .update_replicas(vec![
+                replica("down", "us-west", 2.0, false),
+                busy,
+                replica("drained", "us-east", 3.0, true),
+            ])
+            .unwrap();
+        engine.drain_replica("drained").unwrap();
+        engine.report_latency("busy", 7_000).unwrap();
+        engine.set_probe_result("down", false);
+
+        let replicas = engine.list_replicas();
+        let node_ids: Vec<_> = replicas
+            .iter()
+            .map(|r| r.replica.node_id.as_str())
+            .collect();
+        assert_eq!(node_ids, ["busy", "down", "drained"]);
+
+        assert!(replicas[0].eligible);
+        assert_eq!(replicas[0].effective_load, 0.4);
+        assert_eq!(replicas[0].latency_ewma_ms, Some(7.0));
+        assert!(!replicas[1].eligible && replicas[1].probe_failed);
+        assert!(!replicas[2].eligible && replicas[2].drained);
+
+        route(&engine);
+        assert_eq!(engine.list_replicas()[0].selections, 1);
+
+        let json = serde_json::to_value(&replicas[0]).unwrap();
+        assert_eq!(json["zone"], "us-east");
+        assert_eq!(json["eligible"], true);
+    }
+
+    #[test]
+    fn test_lone_healthy_leader_serves_reads() {
+        let engine = RoutingEngine::new();
+        let mut leader = replica("leader", "us-west", 40.0, true);
+        leader.is_leader = true;
+        engine
+            .update_replicas(vec![
+                leader,
+                replica("follower-1", "us-east", 1.0, false),
+                replica("follower-2", "us-east", 2.0, false),
+            ])
+            .unwrap();
+
+        assert_eq!(route(&engine).node_id, "leader");
+    }
+
+    #[test]
+    fn test_table_goes_stale_without_updates() {
+        let engine = RoutingEngine::new();
+        assert!(!engine.check_freshness(Duration::from_secs(60)));
+        assert!(!engine.is_table_stale());
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(engine.check_freshness(Duration::from_millis(1)));
+        assert!(engine.is_table_stale());
+
+        engine
+            .update_replicas(vec![replica("r1", "us-east", 1.0, false)])
+            .unwrap();
+        assert!(!engine.is_table_stale());
+        assert!(engine.table_age() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_equally_scored_leaders_tie_break_by_node_id() {
+        let geo_resolver = GeoResolver::new(None).unwrap();
+        let leader = |node_id: &str| {
+            let mut leader = replica(node_id, "us-east", 1.0, true);
+            leader.is_leader = true;
+            leader
+        };
+
+        // Whatever order the table is built in, the same leader wins
+        for order in [["leader-b", "leader-a"], ["leader-a", "leader-b"]] {
+            let engine = RoutingEngine::new();
+            engine
+                .update_replicas(order.iter().map(|node_id| leader(node_id)).collect())
+                .unwrap();
+            for _ in 0..10 {
+                let response = route_from(&engine, &geo_resolver, "10.0.0.1", "write");
+                assert_eq!(response.node_id, "leader-a");
+            }
+        }
+    }
+
+    #[test]
+    fn test_nan_scores_rank_last() {
+        let engine = RoutingEngine::new();
+        let mut broken = replica("a-broken", "us-east", 1.0, true);
+        broken.load_score = f64::NAN;
+        engine
+            .update_replicas(vec![
+                broken.clone(),
+                replica("c-sound", "us-east", 1.0, true),
+                replica("b-sound", "us-east", 1.0, true),
+            ])
+            .unwrap();
+
+        // Repeated routes agree despite the NaN and the tie
+        for _ in 0..10 {
+            assert_eq!(route(&engine).node_id, "b-sound");
+        }
+
+        // Even an infinitely loaded replica beats a NaN-scored one
+        let mut overloaded = replica("z-overloaded", "us-east", 1.0, true);
+        overloaded.load_score = f64::INFINITY;
+        engine
+            .update_replicas(vec![broken.clone(), overloaded])
+            .unwrap();
+        assert_eq!(route(&engine).node_id, "z-overloaded");
+
+        engine.update_replicas(vec![broken]).unwrap();
+        assert_eq!(route(&engine).node_id, "a-broken");
+
+        assert_eq!(
+            rank_order(f64::NAN, f64::INFINITY),
+            std::cmp::Ordering::Greater
+        );
+        assert_eq!(rank_order(f64::NAN, f64::NAN), std::cmp::Ordering::Equal);
+        assert_eq!(rank_order(-5.0, f64::NAN), std::cmp::Ordering::Less);
+    }
+
+    #[test]
+    fn test_out_of_range_coordinates_are_clamped_or_rejected() {
+        let mut off_map = replica("off-map", "us-east", 200.0, true);
+        off_map.geo_location.longitude = -190.0;
+        let mut broken = replica("broken", "us-east", 1.0, true);
+        broken.geo_location.longitude = f64::NAN;
+        let replicas = vec![off_map, broken, replica("valid", "us-east", 1.0, true)];
+
+        let engine = RoutingEngine::new();
+        let update = engine.update_replicas_at(replicas.clone(), None).unwrap();
+        assert_eq!((update.rejected, update.clamped), (1, 1));
+        let clamped = engine
+            .list_replicas()
+            .into_iter()
+            .find(|state| state.replica.node_id == "off-map")
+            .unwrap();
+        assert_eq!(clamped.replica.geo_location.latitude, 90.0);
+        assert_eq!(clamped.replica.geo_location.longitude, -180.0);
+        assert_eq!(engine.get_replica_count(), 2);
+
+        let mut engine = RoutingEngine::new();
+        engine.set_strict_geo_validation(true);
+        let update = engine.update_replicas_at(replicas, None).unwrap();
+        assert_eq!((update.rejected, update.clamped), (2, 0));
+        assert_eq!(engine.get_replica_count(), 1);
+
+        let update = engine
+            .patch_replicas(vec![replica("polar", "us-east", -91.0, true)], Vec::new())
+            .unwrap();
+        assert_eq!(update.rejected, 1);
+        assert_eq!(engine.get_replica_count(), 1);
+    }
+
+    #[test]
+    fn test_stale_generation_is_rejected() {
+        let engine = RoutingEngine::new();
+        let newer = vec![replica("new", "us-east", 1.0, true)];
+        assert_eq!(
+            engine
+                .update_replicas_at(newer.clone(), Some(5))
+                .unwrap()
+                .generation,
+            5
+        );
+
+        let error = engine
+            .update_replicas_at(vec![replica("old", "us-east", 1.0, true)], Some(3))
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RoutingError::StaleGeneration {
+                received: 3,
+                current: 5
+            }
+        ));
+        assert_eq!(route(&engine).node_id, "new");
+
+        // Retries of the current push and unversioned pushes still apply
+        assert_eq!(
+            engine
+                .update_replicas_at(newer, Some(5))
+                .unwrap()
+                .generation,
+            5
+        );
+        engine
+            .update_replicas(vec![replica("manual", "us-east", 1.0, true)])
+            .unwrap();
+        assert_eq!(route(&engine).node_id, "manual");
+        assert_eq!(engine.table_generation(), 5);
+    }
+
+    #[test]
+    fn test_routes_while_updating() {
+        let engine = RoutingEngine::new();
+        let east = vec![
+            replica("east-1", "us-east", 1.0, true),
+            replica("east-2", "us-east", 2.0, true),
+        ];
+        let west = vec![replica("west-1", "us-west", 3.0, true)];
+        engine.update_replicas(east.clone()).unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..500 {
+                    let replicas = if i % 2 == 0 { west.clone() } else { east.clone() };
+                    engine.update_replicas(replicas).unwrap();
+                }
+            });
+
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..2_000 {
+                        let response = route(&engine);
+                        let node_id = response.node_id.as_str();
+                        assert!(["east-1", "east-2", "west-1"].contains(&node_id));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(engine.get_replica_count(), 2);
     }
 }
@@ -0,0 +1,96 @@
+//! Optional TLS / mutual-TLS for the TCP listener
+//!
+//! With a client CA configured, clients may still connect without a
+//! certificate, but only connections presenting one signed by that CA are
+//! allowed to send mutating requests.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Byte stream a connection handler can be driven over
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+    verifies_clients: bool,
+}
+
+impl TlsServer {
+    pub fn new(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> Result<Self> {
+        let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to parse TLS certificate")?;
+        let key = rustls_pemfile::private_key(&mut open(key_path)?)
+            .context("Failed to parse TLS private key")?
+            .ok_or_else(|| anyhow!("No private key found in {:?}", key_path))?;
+
+        let builder = ServerConfig::builder();
+        let config = match client_ca_path {
+            Some(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut open(ca_path)?) {
+                    roots
+                        .add(cert.context("Failed to parse client CA certificate")?)
+                        .context("Invalid client CA certificate")?;
+                }
+
+                // Anonymous clients are still served read-only requests
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .allow_unauthenticated()
+                    .build()
+                    .context("Failed to build client certificate verifier")?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        }
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            verifies_clients: client_ca_path.is_some(),
+        })
+    }
+
+    /// Complete the handshake, returning the stream and whether the peer
+    /// may send mutating requests
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+        handshake_timeout: Option<Duration>,
+    ) -> Result<(Box<dyn Connection>, bool)> {
+        let handshake = self.acceptor.accept(stream);
+        let stream = match handshake_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake)
+                .await
+                .map_err(|_| anyhow!("TLS handshake timed out after {:?}", timeout))??,
+            None => handshake.await?,
+        };
+
+        // The verifier has already checked any certificate against the CA
+        let has_client_cert = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .is_some_and(|certs| !certs.is_empty());
+
+        Ok((Box::new(stream), !self.verifies_clients || has_client_cert))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {:?}", path))?,
+    ))
+}
@@ -0,0 +1,288 @@
+//! Accept loop shared by the stream transports
+//!
+//! A transport only has to say how to accept a connection; `serve` holds
+//! back accepts at the connection limit, tracks the connection while it is
+//! open and runs it on its own task. TCP and the Unix socket both go
+//! through it, and so should any new stream listener.
+
+use crate::metrics::MetricsCollector;
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tracing::{debug, info_span, warn, Instrument};
+
+/// A source of incoming byte-stream connections
+///
+/// `accept` must be cancel-safe: `serve` may drop it before it completes.
+pub trait Listener: Send {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// Peer address, or whatever the transport knows about the client
+    type Addr: Send;
+
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Stream, Self::Addr)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Addr = std::net::SocketAddr;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, Self::Addr)> {
+        TcpListener::accept(self).await
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+    type Addr = tokio::net::unix::SocketAddr;
+
+    async fn accept(&mut self) -> io::Result<(UnixStream, Self::Addr)> {
+        UnixListener::accept(self).await
+    }
+}
+
+/// Connection bookkeeping shared by every listener
+#[derive(Clone)]
+pub struct ServeContext {
+    pub connection_limit: Arc<Semaphore>,
+    /// Reported when the limit is hit; `connection_limit` holds the permits
+    pub max_connections: usize,
+    /// Open connections by id, with the time each was accepted
+    pub active_connections: Arc<DashMap<String, SystemTime>>,
+    pub metrics: Arc<MetricsCollector>,
+}
+
+impl ServeContext {
+    /// Take a connection slot, waiting for one if all are in use; the flag
+    /// says whether it had to wait
+    async fn acquire_connection_slot(&self) -> Result<(OwnedSemaphorePermit, bool)> {
+        match Arc::clone(&self.connection_limit).try_acquire_owned() {
+            Ok(permit) => return Ok((permit, false)),
+            Err(TryAcquireError::NoPermits) => {}
+            Err(TryAcquireError::Closed) => bail!("Connection limiter closed"),
+        }
+
+        warn!(
+            "Connection limit of {} reached, pausing accept",
+            self.max_connections
+        );
+        let permit = Arc::clone(&self.connection_limit)
+            .acquire_owned()
+            .await
+            .context("Connection limiter closed")?;
+        Ok((permit, true))
+    }
+}
+
+/// One accepted connection, as prepared by the `serve` handler
+pub struct Session<F> {
+    /// Unique among open connections; keys `active_connections` and names
+    /// the connection's tracing span
+    pub id: String,
+    /// Serves the connection to completion
+    pub task: F,
+}
+
+/// Accept connections from `listener` until accepting fails, handing each
+/// to `handler` and running the session it returns on its own task
+///
+/// The handler runs on the accept loop, so it should only prepare the
+/// session; anything slow, such as a TLS handshake, belongs in `task`.
+pub async fn serve<L, H, F>(mut listener: L, ctx: ServeContext, mut handler: H) -> Result<()>
+where
+    L: Listener,
+    H: FnMut(L::Stream, L::Addr) -> Session<F>,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        // Hold off on accepting until a connection slot frees up
+        let (permit, waited) = ctx.acquire_connection_slot().await?;
+
+        // After a wait, a connection that is ready at once spent it queued
+        // in the listen backlog
+        let queued = if waited {
+            tokio::time::timeout(Duration::ZERO, listener.accept())
+                .await
+                .ok()
+        } else {
+            None
+        };
+        let (stream, addr) = match queued {
+            Some(accepted) => {
+                let accepted = accepted?;
+                ctx.metrics.record_connection_rejected();
+                accepted
+            }
+            None => listener.accept().await?,
+        };
+
+        let Session { id, task } = handler(stream, addr);
+        ctx.active_connections.insert(id.clone(), SystemTime::now());
+        ctx.metrics
+            .set_active_connections(ctx.active_connections.len());
+
+        let ctx = ctx.clone();
+        let span = info_span!("connection", id = %id);
+        tokio::spawn(
+            async move {
+                if let Err(e) = task.await {
+                    debug!("Connection error: {}", e);
+                }
+                ctx.active_connections.remove(&id);
+                ctx.metrics
+                    .set_active_connections(ctx.active_connections.len());
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{Framed, DEFAULT_MAX_FRAME_SIZE};
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+
+    /// Hands out the server ends of in-memory connections
+    struct DuplexListener(mpsc::Receiver<DuplexStream>);
+
+    impl Listener for DuplexListener {
+        type Stream = DuplexStream;
+        type Addr = ();
+
+        async fn accept(&mut self) -> io::Result<(DuplexStream, ())> {
+            match self.0.recv().await {
+                Some(stream) => Ok((stream, ())),
+                None => Err(io::ErrorKind::ConnectionAborted.into()),
+            }
+        }
+    }
+
+    /// Send every frame back until the client hangs up
+    async fn echo(stream: DuplexStream) -> Result<()> {
+        let mut framed = Framed::new(stream, DEFAULT_MAX_FRAME_SIZE);
+        while let Some(frame) = framed.read_frame().await? {
+            framed.write_frame(&frame).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_over_duplex_streams() {
+        let (connect, incoming) = mpsc::channel(4);
+        let ctx = ServeContext {
+            connection_limit: Arc::new(Semaphore::new(1)),
+            max_connections: 1,
+            active_connections: Arc::new(DashMap::new()),
+            metrics: Arc::new(MetricsCollector::new()),
+        };
+
+        let mut next_id = 0;
+        let server = tokio::spawn(serve(
+            DuplexListener(incoming),
+            ctx.clone(),
+            move |stream, ()| {
+                next_id += 1;
+                Session {
+                    id: format!("duplex:{}", next_id),
+                    task: echo(stream),
+                }
+            },
+        ));
+
+        let (client, server_end) = tokio::io::duplex(1024);
+        connect.send(server_end).await.unwrap();
+        let mut client = Framed::new(client, DEFAULT_MAX_FRAME_SIZE);
+        client.write_frame(b"ping").await.unwrap();
+        assert_eq!(client.read_frame().await.unwrap().unwrap(), b"ping");
+        assert!(ctx.active_connections.contains_key("duplex:1"));
+        assert_eq!(ctx.connection_limit.available_permits(), 0);
+
+        // Closing the connection frees its slot for the next one
+        drop(client);
+        let (client, server_end) = tokio::io::duplex(1024);
+        connect.send(server_end).await.unwrap();
+        let mut client = Framed::new(client, DEFAULT_MAX_FRAME_SIZE);
+        client.write_frame(b"again").await.unwrap();
+        assert_eq!(client.read_frame().await.unwrap().unwrap(), b"again");
+        assert!(!ctx.active_connections.contains_key("duplex:1"));
+        assert!(ctx.active_connections.contains_key("duplex:2"));
+
+        // The loop ends once the listener can't accept any more
+        drop(client);
+        drop(connect);
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connections_queued_at_the_limit_are_counted() {
+        let (connect, incoming) = mpsc::channel(4);
+        let ctx = ServeContext {
+            connection_limit: Arc::new(Semaphore::new(1)),
+            max_connections: 1,
+            active_connections: Arc::new(DashMap::new()),
+            metrics: Arc::new(MetricsCollector::new()),
+        };
+
+        let mut next_id = 0;
+        let server = tokio::spawn(serve(
+            DuplexListener(incoming),
+            ctx.clone(),
+            move |stream, ()| {
+                next_id += 1;
+                Session {
+                    id: format!("duplex:{}", next_id),
+                    task: echo(stream),
+                }
+            },
+        ));
+        let open = |payload: &'static [u8]| {
+            let connect = connect.clone();
+            async move {
+                let (client, server_end) = tokio::io::duplex(1024);
+                connect.send(server_end).await.unwrap();
+                let mut client = Framed::new(client, DEFAULT_MAX_FRAME_SIZE);
+                client.write_frame(payload).await.unwrap();
+                client
+            }
+        };
+        let rejected = || ctx.metrics.get_snapshot().connections_rejected;
+
+        let mut first = open(b"first").await;
+        assert_eq!(first.read_frame().await.unwrap().unwrap(), b"first");
+
+        // The second connection waits unanswered behind the first
+        let mut second = open(b"second").await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), second.read_frame())
+                .await
+                .is_err()
+        );
+        assert_eq!(rejected(), 0);
+
+        drop(first);
+        assert_eq!(second.read_frame().await.unwrap().unwrap(), b"second");
+        assert_eq!(rejected(), 1);
+
+        // A connection arriving once a slot is already free never queued
+        drop(second);
+        while !ctx.active_connections.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = open(b"third").await;
+        assert_eq!(third.read_frame().await.unwrap().unwrap(), b"third");
+        assert_eq!(rejected(), 1);
+
+        drop(third);
+        drop(connect);
+        assert!(server.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Which peers may route on behalf of other client IPs
+//!
+//! Route requests name the client to route for, so a client could claim any
+//! address to steer itself to another region. With `--trusted-proxies`, only
+//! peers in the listed networks, such as a load balancer, may name a client
+//! other than themselves; anyone else may only route for its own address.
+//! Unix socket peers are local processes and always trusted.
+
+use crate::error::RoutingError;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address block in CIDR notation, e.g. `10.0.0.0/8`; a bare address is
+/// a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the top `prefix_len` of `bits` bits agree
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let host_bits = u32::from(bits - prefix_len);
+    net.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address in {:?}", s))?
+            .to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", s))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Networks allowed to route for clients other than themselves; empty
+/// trusts everyone, as before `--trusted-proxies` existed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        Self(networks)
+    }
+
+    pub fn trusts(&self, peer: IpAddr) -> bool {
+        self.0.is_empty() || self.0.iter().any(|net| net.contains(peer))
+    }
+
+    /// The peer a connection's client IPs are held to, or `None` if it may
+    /// name any client
+    pub fn restrict(&self, peer: IpAddr) -> Option<IpAddr> {
+        (!self.trusts(peer)).then_some(peer)
+    }
+}
+
+/// Reject any client IP other than `peer` when the peer isn't trusted
+pub fn check_client_ips<'a>(
+    untrusted_peer: Option<IpAddr>,
+    client_ips: impl IntoIterator<Item = &'a IpAddr>,
+) -> Result<(), RoutingError> {
+    let Some(peer) = untrusted_peer else {
+        return Ok(());
+    };
+    match client_ips
+        .into_iter()
+        .find(|ip| ip.to_canonical() != peer.to_canonical())
+    {
+        Some(ip) => Err(RoutingError::UntrustedClientIp(ip.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipnet_parse_and_contains() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        // IPv4-mapped peers match IPv4 networks
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+
+        let host: IpNet = "127.0.0.1".parse().unwrap();
+        assert_eq!(host.to_string(), "127.0.0.1/32");
+        assert!(host.contains(ip("127.0.0.1")));
+        assert!(!host.contains(ip("127.0.0.2")));
+
+        let everything: IpNet = "::/0".parse().unwrap();
+        assert!(everything.contains(ip("2001:db8::1")));
+        assert!(!everything.contains(ip("10.0.0.1")));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("not-an-ip/8".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_untrusted_peer_may_only_route_for_itself() {
+        let proxies = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(proxies.restrict(ip("10.9.9.9")), None);
+        assert_eq!(TrustedProxies::default().restrict(ip("203.0.113.7")), None);
+
+        let peer = proxies.restrict(ip("203.0.113.7"));
+        assert_eq!(peer, Some(ip("203.0.113.7")));
+        assert!(check_client_ips(peer, &[ip("203.0.113.7")]).is_ok());
+        assert!(matches!(
+            check_client_ips(peer, &[ip("203.0.113.7"), ip("198.51.100.1")]),
+            Err(RoutingError::UntrustedClientIp(claimed)) if claimed == "198.51.100.1"
+        ));
+        assert!(check_client_ips(None, &[ip("198.51.100.1")]).is_ok());
+    }
+}
@@ -0,0 +1,497 @@
+//! End-to-end tests of the length-prefixed socket protocol against a
+//! running sidecar binary
+
+use geo_router_sidecar::codec::{
+    decompress_frame, CompactRoute, COMPACT_TAG, COMPRESSED_FLAG, JSON_TAG,
+};
+use serde_json::{json, Value};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const MAX_FRAME_BYTES: usize = 4096;
+
+/// Sidecar process killed when dropped
+struct Sidecar {
+    child: Child,
+    port: u16,
+    socket: PathBuf,
+}
+
+impl Sidecar {
+    fn start() -> Self {
+        Self::start_with(&[])
+    }
+
+    fn start_with(extra_args: &[&str]) -> Self {
+        // Released right away so the sidecar can bind it
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let socket = std::env::temp_dir().join(format!(
+            "geo_router_test_{}_{}.sock",
+            std::process::id(),
+            port
+        ));
+
+        let child = Command::new(env!("CARGO_BIN_EXE_geo_router_sidecar"))
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--max-frame-bytes")
+            .arg(MAX_FRAME_BYTES.to_string())
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start sidecar");
+
+        Self {
+            child,
+            port,
+            socket,
+        }
+    }
+
+    fn connect(&self) -> TcpStream {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            match TcpStream::connect(("127.0.0.1", self.port)) {
+                Ok(stream) => {
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .unwrap();
+                    return stream;
+                }
+                Err(e) if Instant::now() < deadline => {
+                    assert_eq!(e.kind(), ErrorKind::ConnectionRefused, "{}", e);
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => panic!("sidecar never started listening: {}", e),
+            }
+        }
+    }
+}
+
+impl Drop for Sidecar {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) {
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(payload).unwrap();
+}
+
+fn read_frame_bytes(stream: &mut TcpStream) -> Vec<u8> {
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix).unwrap();
+    let mut payload = vec![0u8; u32::from_be_bytes(prefix) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    payload
+}
+
+fn read_frame(stream: &mut TcpStream) -> Value {
+    serde_json::from_slice(&read_frame_bytes(stream)).unwrap()
+}
+
+fn request(stream: &mut TcpStream, request: Value) -> Value {
+    write_frame(stream, request.to_string().as_bytes());
+    read_frame(stream)
+}
+
+fn replica(node_id: &str, zone: &str, latitude: f64) -> Value {
+    json!({
+        "node_id": node_id,
+        "host": "127.0.0.1",
+        "port": 9000,
+        "is_leader": false,
+        "healthy": true,
+        "zone": zone,
+        "geo_location": {
+            "country": "Unknown",
+            "region": "Unknown",
+            "city": "Unknown",
+            "latitude": latitude,
+            "longitude": 0.0,
+            "timezone": "UTC"
+        },
+        "load_score": 0.0,
+        "latency_ms": 0.0
+    })
+}
+
+#[test]
+fn test_ping() {
+    let sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 1}));
+    assert_eq!(response["success"], true);
+    assert_eq!(response["data"]["pong"], true);
+    assert_eq!(response["data"]["keepalive_secs"], 300);
+}
+
+#[test]
+fn test_hello_negotiates_codec() {
+    let sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "hello",
+            "protocol_version": 1,
+            "supported_codecs": ["cbor", "msgpack", "json"],
+            "timestamp": 1
+        }),
+    );
+    assert_eq!(response["success"], true, "{}", response);
+    assert_eq!(response["data"]["protocol_version"], 1);
+    assert_eq!(response["data"]["codec"], "msgpack");
+
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "hello",
+            "protocol_version": 1,
+            "supported_codecs": ["cbor"],
+            "timestamp": 2
+        }),
+    );
+    assert_eq!(response["error_code"], "NO_COMMON_CODEC");
+
+    let response = request(
+        &mut stream,
+        json!({"type": "hello", "protocol_version": 0, "timestamp": 3}),
+    );
+    assert_eq!(response["error_code"], "UNSUPPORTED_PROTOCOL_VERSION");
+
+    // Skipping the handshake keeps working
+    let mut stream = sidecar.connect();
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 1}));
+    assert_eq!(response["success"], true);
+}
+
+#[test]
+fn test_update_routing_table_then_route() {
+    let sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+    let route = json!({
+        "type": "route",
+        "client_ip": "203.0.113.7",
+        "query_type": "read",
+        "timestamp": 1
+    });
+
+    let response = request(&mut stream, route.clone());
+    assert_eq!(response["success"], false);
+    assert_eq!(response["error_code"], "NO_HEALTHY_REPLICAS");
+
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "update_routing_table",
+            "replicas": [replica("far", "eu-west", 40.0), replica("near", "us-east", 1.0)],
+            "timestamp": 2
+        }),
+    );
+    assert_eq!(response["success"], true);
+    assert_eq!(response["data"]["updated"], true);
+
+    // Without a GeoIP database the client is unlocated: it is scored from
+    // the placeholder (0, 0) but gets no zone derived from it
+    let response = request(&mut stream, route);
+    assert_eq!(response["success"], true, "{}", response);
+    assert_eq!(response["data"]["node_id"], "near");
+    assert!(response["data"]["client_zone"].is_null());
+    assert_eq!(response["data"]["zone"], "us-east");
+    assert_eq!(response["data"]["degraded"], false);
+}
+
+#[test]
+fn test_compact_route_reply() {
+    let sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+    let compact_route = |stream: &mut TcpStream| {
+        let mut frame = vec![COMPACT_TAG];
+        frame.extend_from_slice(
+            json!({
+                "type": "route",
+                "client_ip": "203.0.113.7",
+                "query_type": "read",
+                "timestamp": 1
+            })
+            .to_string()
+            .as_bytes(),
+        );
+        write_frame(stream, &frame);
+        read_frame_bytes(stream)
+    };
+
+    // Errors come back as tagged JSON
+    let frame = compact_route(&mut stream);
+    assert_eq!(frame[0], JSON_TAG);
+    let response: Value = serde_json::from_slice(&frame[1..]).unwrap();
+    assert_eq!(response["error_code"], "NO_HEALTHY_REPLICAS");
+
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "update_routing_table",
+            "replicas": [replica("near", "us-east", 1.0)],
+            "timestamp": 2
+        }),
+    );
+    assert_eq!(response["success"], true);
+
+    let frame = compact_route(&mut stream);
+    assert_eq!(frame[0], COMPACT_TAG);
+    let route = CompactRoute::decode(&frame[1..]).unwrap();
+    assert_eq!(route.node_id, "near");
+    assert_eq!(route.host, "127.0.0.1");
+    assert_eq!(route.port, 9000);
+}
+
+#[test]
+fn test_large_responses_compressed_after_hello() {
+    let sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+
+    // In batches small enough for the test's frame limit
+    let replicas: Vec<_> = (0..96)
+        .map(|i| replica(&format!("replica-{}", i), "us-east", i as f64 / 10.0))
+        .collect();
+    for (batch, upserts) in replicas.chunks(8).enumerate() {
+        let response = request(
+            &mut stream,
+            json!({
+                "type": "patch_routing_table",
+                "upserts": upserts,
+                "timestamp": batch
+            }),
+        );
+        assert_eq!(response["success"], true, "{}", response);
+    }
+    let list_replicas = json!({"type": "list_replicas", "timestamp": 100});
+
+    // Without the handshake, responses stay plain
+    let response = request(&mut stream, list_replicas.clone());
+    assert_eq!(response["data"]["replicas"].as_array().unwrap().len(), 96);
+
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "hello",
+            "protocol_version": 1,
+            "accept_compression": ["brotli", "zstd"],
+            "timestamp": 101
+        }),
+    );
+    assert_eq!(response["data"]["compression"], "zstd");
+
+    write_frame(&mut stream, list_replicas.to_string().as_bytes());
+    let frame = read_frame_bytes(&mut stream);
+    assert_eq!(frame[0], JSON_TAG | COMPRESSED_FLAG);
+    let frame = decompress_frame(&frame).unwrap();
+    assert_eq!(frame[0], JSON_TAG);
+    let response: Value = serde_json::from_slice(&frame[1..]).unwrap();
+    let listed = response["data"]["replicas"].as_array().unwrap();
+    assert_eq!(listed.len(), 96);
+    assert!(listed
+        .iter()
+        .any(|replica| replica["node_id"] == "replica-95"));
+
+    // Small responses are never compressed
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 102}));
+    assert_eq!(response["data"]["pong"], true);
+}
+
+#[test]
+fn test_untrusted_peer_cannot_route_for_others() {
+    let sidecar = Sidecar::start_with(&["--trusted-proxies", "10.0.0.0/8,192.168.0.0/16"]);
+    let mut stream = sidecar.connect();
+    let route = |client_ip: &str| {
+        json!({
+            "type": "route",
+            "client_ip": client_ip,
+            "query_type": "read",
+            "timestamp": 1
+        })
+    };
+
+    let response = request(&mut stream, route("203.0.113.7"));
+    assert_eq!(response["success"], false);
+    assert_eq!(response["error_code"], "UNTRUSTED_CLIENT_IP");
+
+    // The peer's own address is always allowed
+    let response = request(&mut stream, route("127.0.0.1"));
+    assert_eq!(response["error_code"], "NO_HEALTHY_REPLICAS");
+}
+
+#[test]
+fn test_malformed_requests_are_coded_and_keep_connection_open() {
+    let sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+
+    write_frame(&mut stream, b"{\"type\": \"ping\",");
+    let response = read_frame(&mut stream);
+    assert_eq!(response["success"], false);
+    assert_eq!(response["error_code"], "MALFORMED_REQUEST");
+
+    let response = request(
+        &mut stream,
+        json!({"type": "no_such_request", "timestamp": 1}),
+    );
+    assert_eq!(response["success"], false);
+    assert_eq!(response["error_code"], "UNKNOWN_REQUEST_TYPE");
+
+    // A known type with a missing field is malformed, not unknown
+    let response = request(&mut stream, json!({"type": "route", "timestamp": 2}));
+    assert_eq!(response["error_code"], "MALFORMED_REQUEST");
+
+    // So is an unknown variant of a nested enum
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "route",
+            "client_ip": "203.0.113.7",
+            "query_type": "read",
+            "zone_locality": "nearby",
+            "timestamp": 3
+        }),
+    );
+    assert_eq!(response["error_code"], "MALFORMED_REQUEST");
+
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "route",
+            "client_ip": "not-an-ip",
+            "query_type": "read",
+            "timestamp": 4
+        }),
+    );
+    assert_eq!(response["success"], false);
+    assert_eq!(response["error_code"], "INVALID_CLIENT_IP");
+
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 5}));
+    assert_eq!(response["success"], true);
+}
+
+#[test]
+fn test_oversized_frame_is_rejected_and_closed() {
+    let sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+
+    // The body is never sent; the length prefix alone is rejected
+    stream
+        .write_all(&(MAX_FRAME_BYTES as u32 + 1).to_be_bytes())
+        .unwrap();
+    let response = read_frame(&mut stream);
+    assert_eq!(response["success"], false);
+    assert_eq!(response["error_code"], "FRAME_TOO_LARGE");
+
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+
+    // Other connections are unaffected
+    let mut stream = sidecar.connect();
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 1}));
+    assert_eq!(response["success"], true);
+}
+
+#[test]
+fn test_connection_closes_after_max_requests() {
+    let sidecar = Sidecar::start_with(&["--max-requests-per-connection", "3"]);
+    let mut stream = sidecar.connect();
+
+    for timestamp in 1..=2 {
+        let response = request(&mut stream, json!({"type": "ping", "timestamp": timestamp}));
+        assert_eq!(response["success"], true);
+        assert!(response.get("close").is_none(), "{}", response);
+    }
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 3}));
+    assert_eq!(response["success"], true);
+    assert_eq!(response["close"], true);
+
+    // The server hangs up after the hinted response
+    let mut byte = [0u8; 1];
+    assert_eq!(stream.read(&mut byte).unwrap(), 0);
+
+    // A new connection starts a fresh count
+    let mut stream = sidecar.connect();
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 4}));
+    assert!(response.get("close").is_none(), "{}", response);
+
+    // Compact clients get their last route as tagged JSON with the hint
+    let response = request(
+        &mut stream,
+        json!({
+            "type": "update_routing_table",
+            "replicas": [replica("near", "us-east", 1.0)],
+            "timestamp": 5
+        }),
+    );
+    assert_eq!(response["success"], true);
+    let mut compact_route = vec![COMPACT_TAG];
+    compact_route.extend_from_slice(
+        json!({
+            "type": "route",
+            "client_ip": "203.0.113.7",
+            "query_type": "read",
+            "timestamp": 6
+        })
+        .to_string()
+        .as_bytes(),
+    );
+    write_frame(&mut stream, &compact_route);
+    let frame = read_frame_bytes(&mut stream);
+    assert_eq!(frame[0], JSON_TAG);
+    let response: Value = serde_json::from_slice(&frame[1..]).unwrap();
+    assert_eq!(response["close"], true);
+    assert_eq!(response["data"]["node_id"], "near");
+    assert_eq!(response["data"]["port"], 9000);
+    assert_eq!(stream.read(&mut byte).unwrap(), 0);
+}
+
+#[test]
+fn test_sigterm_stops_every_task() {
+    let mut sidecar = Sidecar::start();
+    let mut stream = sidecar.connect();
+    let response = request(&mut stream, json!({"type": "ping", "timestamp": 1}));
+    assert_eq!(response["success"], true);
+    drop(stream);
+
+    let status = Command::new("kill")
+        .arg("-TERM")
+        .arg(sidecar.child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Exiting at all means no background task outlived the shutdown
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Some(status) = sidecar.child.try_wait().unwrap() {
+            assert!(status.success(), "{}", status);
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "sidecar still running after SIGTERM"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!sidecar.socket.exists());
+}
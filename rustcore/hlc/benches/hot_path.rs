@@ -0,0 +1,92 @@
+//! Baseline for the clock's hot path: contended `now()`, `update()` against
+//! remotes behind and ahead of the local clock, and encoding round-trips.
+//! Uncontended `now()` is in the `now` suite.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use pyhmssql_hlc::{HLCTimestamp, HybridLogicalClock, TaggedTimestamp};
+use std::time::{Duration, Instant};
+
+/// Split `iters` calls to `now()` across `threads`, timing until the
+/// slowest thread finishes
+fn now_from_threads(hlc: &HybridLogicalClock, threads: u64, iters: u64) -> Duration {
+    let per_thread = iters.div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let start = Instant::now();
+                    for _ in 0..per_thread {
+                        black_box(hlc.now());
+                    }
+                    start.elapsed()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .max()
+            .unwrap_or(Duration::ZERO)
+    })
+}
+
+fn bench_contended_now(c: &mut Criterion) {
+    let mut group = c.benchmark_group("now_contended");
+
+    for threads in [1, 2, 4, 8] {
+        let hlc = HybridLogicalClock::new();
+        group.bench_function(BenchmarkId::from_parameter(threads), |b| {
+            b.iter_custom(|iters| now_from_threads(&hlc, threads, iters))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("update");
+
+    // Behind: the wall clock wins and the logical counter resets. Ahead: the
+    // clock stays at the remote's physical time (an hour out, so the wall
+    // clock never catches up mid-run) and the logical counter keeps growing.
+    let now = HybridLogicalClock::new().now();
+    let remotes = [
+        ("remote_behind", HLCTimestamp::ZERO),
+        (
+            "remote_ahead",
+            HLCTimestamp {
+                physical: now.physical + Duration::from_secs(3600).as_nanos() as u64,
+                logical: 0,
+            },
+        ),
+    ];
+
+    for (name, remote) in remotes {
+        let hlc = HybridLogicalClock::new();
+        group.bench_function(name, |b| b.iter(|| hlc.update(black_box(remote))));
+    }
+
+    group.finish();
+}
+
+fn bench_encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encoding");
+
+    let ts = HybridLogicalClock::new().now();
+    group.bench_function("bytes", |b| {
+        b.iter(|| HLCTimestamp::from_bytes(&black_box(ts).to_bytes()))
+    });
+    group.bench_function("compact", |b| {
+        b.iter(|| HLCTimestamp::from_bytes_compact(&black_box(ts).to_bytes_compact().unwrap()))
+    });
+
+    let tagged = TaggedTimestamp::new(ts, 7);
+    group.bench_function("tagged", |b| {
+        b.iter(|| TaggedTimestamp::from_bytes_versioned(&black_box(tagged).to_bytes_versioned()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_contended_now, bench_update, bench_encoding);
+criterion_main!(benches);
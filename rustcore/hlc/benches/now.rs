@@ -0,0 +1,20 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use pyhmssql_hlc::HybridLogicalClock;
+
+fn bench_now(c: &mut Criterion) {
+    let mut group = c.benchmark_group("now");
+
+    let hlc = HybridLogicalClock::new();
+    group.bench_function("system_clock", |b| b.iter(|| hlc.now()));
+
+    #[cfg(all(feature = "coarse-clock", target_os = "linux"))]
+    {
+        let hlc = HybridLogicalClock::with_clock(pyhmssql_hlc::CoarseClock::new());
+        group.bench_function("coarse_clock", |b| b.iter(|| hlc.now()));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_now);
+criterion_main!(benches);
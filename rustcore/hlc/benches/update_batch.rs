@@ -0,0 +1,29 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use pyhmssql_hlc::{HLCTimestamp, HybridLogicalClock};
+
+fn bench_update_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("update_batch");
+
+    for size in [1, 16, 256] {
+        let source = HybridLogicalClock::new();
+        let remotes: Vec<HLCTimestamp> = (0..size).map(|_| source.now()).collect();
+        let hlc = HybridLogicalClock::new();
+
+        group.bench_with_input(BenchmarkId::new("batch", size), &remotes, |b, remotes| {
+            b.iter(|| hlc.update_batch(black_box(remotes)))
+        });
+        group.bench_with_input(BenchmarkId::new("loop", size), &remotes, |b, remotes| {
+            b.iter(|| {
+                black_box(remotes)
+                    .iter()
+                    .map(|&remote| hlc.update(remote))
+                    .last()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_update_batch);
+criterion_main!(benches);
@@ -0,0 +1,130 @@
+//! Physical time sources for the HLC
+//!
+//! Every timestamp reads the physical clock, so its cost bounds `now()`
+//! throughput. `SystemClock` reads the wall clock each time; `CoarseClock`
+//! trades resolution (typically 1-4ms) for a much cheaper read, leaving the
+//! logical counter to order events within a tick.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of physical time in nanoseconds since the Unix epoch
+pub trait PhysicalClock: Send + Sync {
+    fn now_nanos(&self) -> u64;
+}
+
+/// Unit of the `physical` component of issued timestamps
+///
+/// Physical clocks always report nanoseconds; coarser units truncate that
+/// reading. The counter then orders every event within a unit, so coarser
+/// units lean on it more.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TimeUnit {
+    #[default]
+    Nanos,
+    Micros,
+    Millis,
+}
+
+impl TimeUnit {
+    pub fn nanos_per_unit(self) -> u64 {
+        match self {
+            TimeUnit::Nanos => 1,
+            TimeUnit::Micros => 1_000,
+            TimeUnit::Millis => 1_000_000,
+        }
+    }
+
+    /// Truncate a nanosecond reading to this unit
+    pub fn truncate_nanos(self, nanos: u64) -> u64 {
+        nanos / self.nanos_per_unit()
+    }
+
+    /// Whole units in `duration`, saturating at `u64::MAX`
+    pub fn whole_units(self, duration: Duration) -> u64 {
+        u64::try_from(duration.as_nanos() / u128::from(self.nanos_per_unit())).unwrap_or(u64::MAX)
+    }
+
+    pub fn to_duration(self, units: u64) -> Duration {
+        match self {
+            TimeUnit::Nanos => Duration::from_nanos(units),
+            TimeUnit::Micros => Duration::from_micros(units),
+            TimeUnit::Millis => Duration::from_millis(units),
+        }
+    }
+}
+
+/// Full-resolution wall clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl PhysicalClock for SystemClock {
+    fn now_nanos(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+    }
+}
+
+/// `CLOCK_MONOTONIC_COARSE` offset by the wall clock at construction
+///
+/// Being monotonic, it ignores wall-clock adjustments made after
+/// construction, so long-lived clocks drift by however much NTP slews the
+/// system clock.
+#[cfg(all(feature = "coarse-clock", target_os = "linux"))]
+#[derive(Clone, Copy, Debug)]
+pub struct CoarseClock {
+    wall_base: u64,
+    monotonic_base: u64,
+}
+
+#[cfg(all(feature = "coarse-clock", target_os = "linux"))]
+impl CoarseClock {
+    pub fn new() -> Self {
+        Self {
+            wall_base: SystemClock.now_nanos(),
+            monotonic_base: coarse_monotonic_nanos(),
+        }
+    }
+}
+
+#[cfg(all(feature = "coarse-clock", target_os = "linux"))]
+impl Default for CoarseClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(feature = "coarse-clock", target_os = "linux"))]
+impl PhysicalClock for CoarseClock {
+    fn now_nanos(&self) -> u64 {
+        self.wall_base + coarse_monotonic_nanos().saturating_sub(self.monotonic_base)
+    }
+}
+
+#[cfg(all(feature = "coarse-clock", target_os = "linux"))]
+fn coarse_monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Served from the vDSO without a syscall; cannot fail for this clock id
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(all(test, feature = "coarse-clock", target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coarse_clock_tracks_wall_clock() {
+        let clock = CoarseClock::new();
+        let first = clock.now_nanos();
+        let wall = SystemClock.now_nanos();
+
+        // Coarse ticks are at most a few milliseconds apart
+        assert!(wall.abs_diff(first) < 50_000_000);
+        assert!(clock.now_nanos() >= first);
+    }
+}
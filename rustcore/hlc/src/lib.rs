@@ -3,105 +3,424 @@
 //! This module provides a high-performance HLC implementation compatible with CockroachDB's approach.
 //! HLC combines physical and logical time to provide a globally consistent ordering of events
 //! in distributed systems.
+//!
+//! # Crash recovery
+//!
+//! A fresh clock starts at (0, 0), so after a restart it trusts the wall
+//! clock alone and may issue timestamps behind ones it already handed out.
+//! Clocks that stamp persisted data should call
+//! [`HybridLogicalClock::checkpoint_to`] periodically and start from
+//! [`HybridLogicalClock::recover_from`]. The recovered clock starts at the
+//! checkpoint plus a safety margin, which must cover every timestamp issued
+//! after the last checkpoint: frequent checkpoints allow a small margin at
+//! the cost of an fsync each time, while rare ones need a margin of at least
+//! the checkpoint interval (and push recovered timestamps further ahead of
+//! the wall clock).
+//!
+//! # Node-tagged timestamps
+//!
+//! [`TaggedTimestamp`] pairs a timestamp with the node that issued it and
+//! breaks physical/logical ties by node id. Its encoding starts with a
+//! version byte, while legacy values are the bare 16 bytes of
+//! [`HLCTimestamp::to_bytes`], so [`TaggedTimestamp::from_bytes_versioned`]
+//! reads both. Legacy timestamps carry no node id and compare as node 0.
+//!
+//! To migrate, switch readers to `from_bytes_versioned` first, then have
+//! writers emit [`TaggedTimestamp::to_bytes_versioned`]; legacy values can
+//! be rewritten lazily, since they order the same before and after. Node
+//! ids should be nonzero, so a tagged timestamp never ties with a legacy
+//! one from the same instant.
+//!
+//! # Time units
+//!
+//! `physical` counts nanoseconds since the Unix epoch unless the clock was
+//! built with [`HybridLogicalClock::with_time_unit`], e.g. to match peers
+//! that stamp in microseconds. Nothing in a timestamp records its unit, so
+//! timestamps issued under different units must never be compared, merged
+//! or fed to each other's `update`: a microsecond clock would read every
+//! nanosecond timestamp as centuries ahead. Offsets passed alongside
+//! timestamps, such as `max_offset_nanos`, are in the same unit as
+//! `physical`. Checkpoint recovery, the chrono conversions and the FFI
+//! assume nanoseconds.
 
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::raw::c_char;
+use std::path::Path;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::PoisonError;
+use std::time::Duration;
+
+mod clock;
+mod delta;
+mod sync;
+mod tagged;
+mod trace;
+
+use sync::{Mutex, MutexGuard};
+use trace::TraceBuffer;
+
+#[cfg(all(feature = "coarse-clock", target_os = "linux"))]
+pub use clock::CoarseClock;
+pub use clock::{PhysicalClock, SystemClock, TimeUnit};
+pub use delta::{delta_decode, delta_encode, DeltaDecodeError};
+pub use tagged::{TaggedTimestamp, TimestampDecodeError, LEGACY_LEN, TAGGED_LEN, TAGGED_VERSION};
+pub use trace::HlcEvent;
 
 /// Hybrid Logical Clock structure
+///
+/// The FFI API always uses the default `SystemClock` source.
 #[repr(C)]
-pub struct HybridLogicalClock {
-    logical_counter: AtomicU64,
-    last_physical: AtomicU64,
+pub struct HybridLogicalClock<C: PhysicalClock = SystemClock> {
+    // Physical and logical parts must change together: updating them as two
+    // separate atomics let concurrent callers issue the same timestamp
+    last: Mutex<HLCTimestamp>,
+    clock: C,
+    unit: TimeUnit,
+    // See `with_max_logical_per_tick`
+    max_logical_per_tick: Option<u64>,
+    trace: Option<TraceBuffer>,
+    // Bits of the f64 moving average of remote minus local physical time,
+    // NaN before the first sample; see `estimated_clock_skew_nanos`. An
+    // atomic, so `update` still takes only the `last` lock
+    skew: AtomicU64,
 }
 
+/// Weight of the newest sample in the clock skew moving average
+const SKEW_EWMA_ALPHA: f64 = 0.1;
+
 /// HLC Timestamp structure - compatible with Cython
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct HLCTimestamp {
-    pub physical: u64, // Physical time in nanoseconds since epoch
+    pub physical: u64, // Physical time since epoch, nanoseconds by default
     pub logical: u64,  // Logical counter
 }
 
 impl HybridLogicalClock {
     /// Create a new HLC instance
     pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+
+    /// Create a clock whose first timestamp is strictly greater than `min_ts`
+    ///
+    /// For bootstrapping from the highest timestamp found in durable
+    /// storage. Unlike `advance_to` on a fresh clock, there is no window in
+    /// which another thread could draw a timestamp before the seed applies.
+    pub fn initialized_with(min_ts: HLCTimestamp) -> Self {
         Self {
-            logical_counter: AtomicU64::new(0),
-            last_physical: AtomicU64::new(0),
+            last: Mutex::new(min_ts),
+            clock: SystemClock,
+            unit: TimeUnit::Nanos,
+            max_logical_per_tick: None,
+            trace: None,
+            skew: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+
+    /// Create a clock that never issues timestamps at or below the
+    /// checkpoint in `path` plus `safety_margin`
+    ///
+    /// A missing checkpoint file is treated as a first start and yields a
+    /// fresh clock.
+    pub fn recover_from<P: AsRef<Path>>(path: P, safety_margin: Duration) -> io::Result<Self> {
+        let mut bytes = [0u8; 16];
+        match File::open(path) {
+            Ok(mut file) => file.read_exact(&mut bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e),
         }
+
+        let checkpoint = HLCTimestamp::from_bytes(&bytes);
+        let margin = u64::try_from(safety_margin.as_nanos()).unwrap_or(u64::MAX);
+
+        Ok(Self {
+            last: Mutex::new(HLCTimestamp {
+                physical: checkpoint.physical.saturating_add(margin),
+                logical: checkpoint.logical,
+            }),
+            clock: SystemClock,
+            unit: TimeUnit::Nanos,
+            max_logical_per_tick: None,
+            trace: None,
+            skew: AtomicU64::new(f64::NAN.to_bits()),
+        })
+    }
+
+    /// Whether `a` is provably later than `b` given clocks may disagree by
+    /// up to `max_offset_nanos`
+    ///
+    /// When this is false but `a` still compares greater, the two events may
+    /// have been concurrent and the caller has to wait out the uncertainty.
+    pub fn is_definitely_after(a: &HLCTimestamp, b: &HLCTimestamp, max_offset_nanos: u64) -> bool {
+        a.physical > b.uncertainty_upper(max_offset_nanos)
+    }
+}
+
+impl<C: PhysicalClock> HybridLogicalClock<C> {
+    /// Create an HLC reading physical time from `clock`
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            last: Mutex::new(HLCTimestamp::ZERO),
+            clock,
+            unit: TimeUnit::Nanos,
+            max_logical_per_tick: None,
+            trace: None,
+            skew: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+
+    /// Issue timestamps whose physical component counts `unit`s rather than
+    /// nanoseconds
+    ///
+    /// Set this before issuing anything: timestamps from before and after
+    /// the change aren't comparable. See the crate docs on time units.
+    pub fn with_time_unit(mut self, unit: TimeUnit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    pub fn time_unit(&self) -> TimeUnit {
+        self.unit
+    }
+
+    /// Cap the logical counter at `max`: a timestamp that would go past it
+    /// moves to the next physical unit with the counter back at 0
+    ///
+    /// Keeps counters small under sustained same-tick bursts, or after a
+    /// peer sends a huge one. The price is that the physical component runs
+    /// ahead of the wall clock by one unit per `max + 1` timestamps issued
+    /// within a tick, and stays ahead until the wall clock catches up.
+    /// Bounds derived from `physical`, such as `uncertainty_upper`, should
+    /// allow for that lead. Unbounded unless set.
+    pub fn with_max_logical_per_tick(mut self, max: u64) -> Self {
+        self.max_logical_per_tick = Some(max);
+        self
+    }
+
+    pub fn max_logical_per_tick(&self) -> Option<u64> {
+        self.max_logical_per_tick
+    }
+
+    /// Record the last `capacity` `now()`/`update()` calls for `dump_trace`
+    ///
+    /// Meant for chasing ordering bugs; without it tracing costs nothing. A
+    /// capacity of 0 leaves tracing off.
+    pub fn with_trace_buffer(mut self, capacity: usize) -> Self {
+        self.trace = (capacity > 0).then(|| TraceBuffer::new(capacity));
+        self
+    }
+
+    /// Traced operations, oldest first; empty unless `with_trace_buffer`
+    /// was used
+    pub fn dump_trace(&self) -> Vec<HlcEvent> {
+        self.trace.as_ref().map_or_else(Vec::new, TraceBuffer::dump)
     }
 
     /// Get current timestamp - thread-safe
+    ///
+    /// Every call returns a timestamp strictly greater than all earlier ones
+    /// from this clock, including those issued concurrently.
     pub fn now(&self) -> HLCTimestamp {
-        let physical_now = Self::get_physical_time();
-        let last_physical = self.last_physical.load(Ordering::SeqCst);
+        let physical_now = self.unit.truncate_nanos(self.clock.now_nanos());
+        let mut last = self.lock_last();
+        let previous = *last;
 
-        if physical_now > last_physical {
+        if physical_now > last.physical {
             // Physical time advanced, reset logical counter
-            self.last_physical.store(physical_now, Ordering::SeqCst);
-            self.logical_counter.store(0, Ordering::SeqCst);
-            HLCTimestamp {
+            *last = HLCTimestamp {
                 physical: physical_now,
                 logical: 0,
-            }
+            };
         } else {
             // Same or earlier physical time, increment logical counter
-            let logical = self.logical_counter.fetch_add(1, Ordering::SeqCst) + 1;
-            HLCTimestamp {
-                physical: last_physical,
-                logical,
-            }
+            *last = self.next_after(*last);
         }
+
+        if let Some(trace) = &self.trace {
+            trace.record(HlcEvent::Now {
+                physical_now,
+                previous,
+                issued: *last,
+            });
+        }
+        *last
     }
 
     /// Update HLC with remote timestamp
     pub fn update(&self, remote_ts: HLCTimestamp) -> HLCTimestamp {
-        let physical_now = Self::get_physical_time();
-        let max_physical = physical_now.max(remote_ts.physical);
-
-        let last_physical = self.last_physical.load(Ordering::SeqCst);
-
-        if max_physical > last_physical {
-            // Physical time advanced
-            self.last_physical.store(max_physical, Ordering::SeqCst);
-            let logical = if max_physical == remote_ts.physical {
-                remote_ts.logical + 1
-            } else {
-                0
-            };
-            self.logical_counter.store(logical, Ordering::SeqCst);
-            HLCTimestamp {
-                physical: max_physical,
-                logical,
-            }
+        let physical_now = self.unit.truncate_nanos(self.clock.now_nanos());
+        self.record_skew(remote_ts, physical_now);
+        let mut last = self.lock_last();
+        let physical = physical_now.max(remote_ts.physical).max(last.physical);
+
+        // Continue from whichever counters were already at the new physical time
+        let logical = match (physical == last.physical, physical == remote_ts.physical) {
+            (true, true) => Some(last.logical.max(remote_ts.logical)),
+            (true, false) => Some(last.logical),
+            (false, true) => Some(remote_ts.logical),
+            (false, false) => None,
+        };
+
+        let previous = *last;
+        *last = match logical {
+            Some(logical) => self.next_after(HLCTimestamp { physical, logical }),
+            None => HLCTimestamp {
+                physical,
+                logical: 0,
+            },
+        };
+
+        if let Some(trace) = &self.trace {
+            trace.record(HlcEvent::Update {
+                physical_now,
+                previous,
+                remote: remote_ts,
+                issued: *last,
+            });
+        }
+        *last
+    }
+
+    /// Update HLC with many remote timestamps at once, e.g. a gossip round
+    ///
+    /// Only the greatest remote can affect the result, so this is a single
+    /// `update` with it: one physical clock read and one lock, rather than
+    /// one per timestamp. Only that remote feeds the skew estimate. The
+    /// result is after every input and after all timestamps this clock
+    /// issued before. An empty batch is a `now()`.
+    pub fn update_batch(&self, remotes: &[HLCTimestamp]) -> HLCTimestamp {
+        match remotes.iter().max_by(|a, b| a.compare(b)) {
+            Some(&latest) => self.update(latest),
+            None => self.now(),
+        }
+    }
+
+    /// Merge a remote timestamp without issuing one of our own
+    ///
+    /// For pure observation, e.g. gossip or heartbeats, where `update` would
+    /// be wrong: `update` is a receive *event* and returns a fresh timestamp
+    /// one tick past both clocks, while this only raises the clock to at
+    /// least `remote_ts`, so the next `now()` is after it. Skipping that
+    /// tick keeps the logical counter from growing on every heartbeat.
+    /// Observations are not recorded in the trace buffer, but do feed the
+    /// skew estimate.
+    pub fn observe(&self, remote_ts: HLCTimestamp) {
+        let physical_now = self.unit.truncate_nanos(self.clock.now_nanos());
+        self.record_skew(remote_ts, physical_now);
+        self.advance_to(remote_ts);
+    }
+
+    /// Moving average of how far remote timestamps passed to `update` and
+    /// `observe` lead this node's physical clock when they arrive
+    ///
+    /// A persistently positive value means the local clock is behind the
+    /// cluster, and a negative one that it is ahead. Message delay adds to
+    /// every sample, so expect a small positive bias even between perfectly
+    /// synchronized clocks. Weighted toward recent samples and in the
+    /// clock's time unit; 0 until a remote timestamp has been seen.
+    pub fn estimated_clock_skew_nanos(&self) -> i64 {
+        let skew = f64::from_bits(self.skew.load(Ordering::Relaxed));
+        if skew.is_nan() {
+            0
         } else {
-            // Same physical time, advance logical
-            let logical = if last_physical == remote_ts.physical {
-                let current_logical = self.logical_counter.load(Ordering::SeqCst);
-                let new_logical = current_logical.max(remote_ts.logical) + 1;
-                self.logical_counter.store(new_logical, Ordering::SeqCst);
-                new_logical
-            } else {
-                self.logical_counter.fetch_add(1, Ordering::SeqCst) + 1
-            };
+            skew.round() as i64
+        }
+    }
 
-            HLCTimestamp {
-                physical: last_physical,
-                logical,
-            }
+    /// Move the clock forward so later timestamps are after `ts`
+    ///
+    /// Timestamps already behind the clock are ignored. Use this to bootstrap
+    /// from the highest timestamp found in durable storage at startup.
+    pub fn advance_to(&self, ts: HLCTimestamp) {
+        let mut last = self.lock_last();
+        if ts.is_greater_than(&last) {
+            *last = ts;
         }
     }
 
-    /// Get physical time in nanoseconds
-    fn get_physical_time() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_nanos() as u64
+    /// Durably write the latest issued timestamp to `path`
+    ///
+    /// The file is replaced atomically, so a crash mid-write leaves the
+    /// previous checkpoint intact.
+    pub fn checkpoint_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let ts = *self.lock_last();
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&ts.to_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        // Make the rename itself durable
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// The timestamp to issue after `ts` within its physical tick, spilling
+    /// into the next tick at the logical cap
+    fn next_after(&self, ts: HLCTimestamp) -> HLCTimestamp {
+        match self.max_logical_per_tick {
+            Some(max) if ts.logical >= max && ts.physical < u64::MAX => HLCTimestamp {
+                physical: ts.physical + 1,
+                logical: 0,
+            },
+            _ => ts.successor(),
+        }
+    }
+
+    fn record_skew(&self, remote_ts: HLCTimestamp, physical_now: u64) {
+        let sample = remote_ts.physical as f64 - physical_now as f64;
+        let _ = self
+            .skew
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let average = f64::from_bits(bits);
+                let average = if average.is_nan() {
+                    sample
+                } else {
+                    average + SKEW_EWMA_ALPHA * (sample - average)
+                };
+                Some(average.to_bits())
+            });
+    }
+
+    // Nothing panics while holding the lock, so a poisoned one is still valid
+    fn lock_last(&self) -> MutexGuard<'_, HLCTimestamp> {
+        self.last.lock().unwrap_or_else(PoisonError::into_inner)
     }
 }
 
 impl HLCTimestamp {
+    /// The state of a fresh clock; no clock ever issues it, since every
+    /// timestamp is strictly after the clock's starting point
+    pub const ZERO: HLCTimestamp = HLCTimestamp {
+        physical: 0,
+        logical: 0,
+    };
+
+    /// Lowest possible timestamp, for the open start of a range
+    pub const MIN: HLCTimestamp = HLCTimestamp::ZERO;
+
+    /// Highest possible timestamp, for the open end of a range
+    ///
+    /// Clocks only reach it at `u64::MAX` nanoseconds, in the year 2554,
+    /// after which they keep returning it; see `successor`.
+    pub const MAX: HLCTimestamp = HLCTimestamp {
+        physical: u64::MAX,
+        logical: u64::MAX,
+    };
+
+    pub fn is_zero(&self) -> bool {
+        self.physical == 0 && self.logical == 0
+    }
+
     /// Compare timestamps for ordering
     pub fn compare(&self, other: &HLCTimestamp) -> std::cmp::Ordering {
         match self.physical.cmp(&other.physical) {
@@ -120,6 +439,65 @@ impl HLCTimestamp {
         self.compare(other) == std::cmp::Ordering::Greater
     }
 
+    /// The smallest timestamp after this one
+    ///
+    /// A remote peer can send any logical counter, so it may already be at
+    /// `u64::MAX`. Saturating there would issue the same timestamp twice;
+    /// instead the counter rolls over into the next nanosecond. Only at
+    /// `(u64::MAX, u64::MAX)`, centuries past any real clock, is the same
+    /// timestamp returned.
+    fn successor(&self) -> HLCTimestamp {
+        match self.logical.checked_add(1) {
+            Some(logical) => HLCTimestamp {
+                physical: self.physical,
+                logical,
+            },
+            None if self.physical == u64::MAX => *self,
+            None => HLCTimestamp {
+                physical: self.physical + 1,
+                logical: 0,
+            },
+        }
+    }
+
+    /// Physical time elapsed since `earlier`, zero if `earlier` is later
+    ///
+    /// Both timestamps must come from clocks using `unit`.
+    pub fn duration_since(&self, earlier: &HLCTimestamp, unit: TimeUnit) -> Duration {
+        unit.to_duration(self.physical.saturating_sub(earlier.physical))
+    }
+
+    /// This timestamp moved `nanos` later, keeping the logical counter, or
+    /// `None` past the end of the physical range
+    pub fn checked_add_nanos(&self, nanos: u64) -> Option<HLCTimestamp> {
+        Some(HLCTimestamp {
+            physical: self.physical.checked_add(nanos)?,
+            logical: self.logical,
+        })
+    }
+
+    /// This timestamp moved `nanos` earlier, keeping the logical counter, or
+    /// `None` if that would fall before the epoch
+    pub fn checked_sub_nanos(&self, nanos: u64) -> Option<HLCTimestamp> {
+        Some(HLCTimestamp {
+            physical: self.physical.checked_sub(nanos)?,
+            logical: self.logical,
+        })
+    }
+
+    /// Whether this timestamp falls in `[start, end)`; empty when `end` is
+    /// not after `start`
+    ///
+    /// For leases and TTLs, e.g. with `end` from `start.checked_add_nanos`.
+    pub fn in_interval(&self, start: &HLCTimestamp, end: &HLCTimestamp) -> bool {
+        !self.is_less_than(start) && self.is_less_than(end)
+    }
+
+    /// Upper bound of the uncertainty interval `[physical, physical + max_offset]`
+    pub fn uncertainty_upper(&self, max_offset_nanos: u64) -> u64 {
+        self.physical.saturating_add(max_offset_nanos)
+    }
+
     /// Convert to bytes for serialization
     pub fn to_bytes(&self) -> [u8; 16] {
         let mut bytes = [0u8; 16];
@@ -138,6 +516,161 @@ impl HLCTimestamp {
         ]);
         Self { physical, logical }
     }
+
+    /// 12-byte encoding for space-constrained storage: 64-bit physical, then
+    /// a 32-bit logical counter, both little-endian
+    ///
+    /// The counter only grows while physical time stands still, so it stays
+    /// tiny in practice, but a burst of over four billion events in one tick
+    /// (or a remote peer sending a huge counter) cannot be stored. Rather
+    /// than saturating, which would make distinct timestamps compare equal,
+    /// this fails and leaves the caller to fall back to `to_bytes`.
+    pub fn to_bytes_compact(&self) -> Result<[u8; 12], LogicalOverflow> {
+        let logical = u32::try_from(self.logical).map_err(|_| LogicalOverflow(self.logical))?;
+        let mut bytes = [0u8; 12];
+        bytes[..8].copy_from_slice(&self.physical.to_le_bytes());
+        bytes[8..].copy_from_slice(&logical.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Decode the output of `to_bytes_compact`
+    pub fn from_bytes_compact(bytes: &[u8; 12]) -> Self {
+        let physical = u64::from_le_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]);
+        let logical = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+        Self {
+            physical,
+            logical: u64::from(logical),
+        }
+    }
+}
+
+/// The logical counter doesn't fit the 32 bits of the compact encoding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogicalOverflow(pub u64);
+
+impl std::fmt::Display for LogicalOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "logical counter {} exceeds the compact encoding's 32 bits", self.0)
+    }
+}
+
+impl std::error::Error for LogicalOverflow {}
+
+/// Last-writer-wins merge of two timestamped values
+///
+/// Returns whichever value carries the greater timestamp. Bare timestamps
+/// don't identify their node, so exact ties keep `a`; merge replicas in the
+/// same argument order (e.g. local value first) to stay deterministic, or
+/// use [`lww_merge_tagged`] to break ties by node id.
+pub fn lww_merge<T>(a: (HLCTimestamp, T), b: (HLCTimestamp, T)) -> (HLCTimestamp, T) {
+    if b.0.is_greater_than(&a.0) {
+        b
+    } else {
+        a
+    }
+}
+
+/// Last-writer-wins merge of two values stamped with their issuing node
+///
+/// Exact timestamp ties go to the higher node id, per
+/// [`TaggedTimestamp::compare`], so every replica picks the same winner
+/// whatever the argument order. Only two legacy timestamps from the same
+/// instant still tie, and keep `a`.
+pub fn lww_merge_tagged<T>(
+    a: (TaggedTimestamp, T),
+    b: (TaggedTimestamp, T),
+) -> (TaggedTimestamp, T) {
+    if b.0.compare(&a.0).is_gt() {
+        b
+    } else {
+        a
+    }
+}
+
+/// Wall-clock conversions for correlating with externally timestamped logs
+///
+/// `physical` holds `u64` nanoseconds, which reaches past year 2500, while
+/// chrono's `i64` nanosecond helpers stop at 2262; conversions therefore go
+/// through whole seconds plus subsecond nanos.
+#[cfg(feature = "chrono")]
+impl HLCTimestamp {
+    const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+    /// Interpret the physical component as a UTC time
+    pub fn physical_as_datetime(&self) -> chrono::DateTime<chrono::Utc> {
+        let secs = (self.physical / Self::NANOS_PER_SEC) as i64;
+        let nanos = (self.physical % Self::NANOS_PER_SEC) as u32;
+        // Any u64 nanosecond count fits comfortably in chrono's range
+        chrono::DateTime::from_timestamp(secs, nanos).expect("HLC physical time out of range")
+    }
+
+    /// Build a timestamp from a UTC time, or `None` if it falls before the
+    /// Unix epoch or past the `u64` nanosecond range
+    pub fn from_datetime(dt: chrono::DateTime<chrono::Utc>, logical: u64) -> Option<Self> {
+        let secs = u64::try_from(dt.timestamp()).ok()?;
+        let physical = secs
+            .checked_mul(Self::NANOS_PER_SEC)?
+            .checked_add(u64::from(dt.timestamp_subsec_nanos()))?;
+        Some(Self { physical, logical })
+    }
+}
+
+/// Random timestamps for property tests and fuzzing of code built on the
+/// clock
+///
+/// Every timestamp a clock could issue is generated, i.e. anything but
+/// `ZERO`, including counters near `u64::MAX` that only a misbehaving peer
+/// would send.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for HLCTimestamp {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let ts = HLCTimestamp {
+            physical: u.arbitrary()?,
+            logical: u.arbitrary()?,
+        };
+        Ok(if ts.is_zero() { ts.successor() } else { ts })
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <(u64, u64) as arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+/// Two distinct timestamps, `earlier` strictly before `later`, for testing
+/// logic that depends on their order
+#[cfg(feature = "arbitrary")]
+#[derive(Clone, Copy, Debug)]
+pub struct OrderedTimestamps {
+    pub earlier: HLCTimestamp,
+    pub later: HLCTimestamp,
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for OrderedTimestamps {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let a: HLCTimestamp = u.arbitrary()?;
+        let b: HLCTimestamp = u.arbitrary()?;
+        let (earlier, later) = match a.compare(&b) {
+            std::cmp::Ordering::Less => (a, b),
+            std::cmp::Ordering::Greater => (b, a),
+            // `successor` can't move past MAX, so step back from it instead
+            std::cmp::Ordering::Equal if a.compare(&HLCTimestamp::MAX).is_eq() => (
+                HLCTimestamp {
+                    physical: u64::MAX,
+                    logical: u64::MAX - 1,
+                },
+                a,
+            ),
+            std::cmp::Ordering::Equal => (a, a.successor()),
+        };
+        Ok(Self { earlier, later })
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <(HLCTimestamp, HLCTimestamp) as arbitrary::Arbitrary>::size_hint(depth)
+    }
 }
 
 // C-compatible API for Cython binding
@@ -166,6 +699,98 @@ pub extern "C" fn hlc_update(
     unsafe { (*hlc).update(remote_ts) }
 }
 
+/// `remotes` may be null when `len` is 0
+///
+/// # Safety
+///
+/// `hlc` must point to a live clock from `hlc_new`, and unless `len` is 0,
+/// `remotes` must point to `len` initialized timestamps.
+#[no_mangle]
+pub unsafe extern "C" fn hlc_update_batch(
+    hlc: *const HybridLogicalClock,
+    remotes: *const HLCTimestamp,
+    len: usize,
+) -> HLCTimestamp {
+    let remotes = if len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(remotes, len) }
+    };
+    unsafe { (*hlc).update_batch(remotes) }
+}
+
+/// # Safety
+///
+/// `hlc` must point to a live clock from `hlc_new`.
+#[no_mangle]
+pub unsafe extern "C" fn hlc_observe(hlc: *const HybridLogicalClock, remote_ts: HLCTimestamp) {
+    unsafe { (*hlc).observe(remote_ts) }
+}
+
+/// See `HybridLogicalClock::estimated_clock_skew_nanos`
+///
+/// # Safety
+///
+/// `hlc` must point to a live clock from `hlc_new`.
+#[no_mangle]
+pub unsafe extern "C" fn hlc_estimated_clock_skew_nanos(hlc: *const HybridLogicalClock) -> i64 {
+    unsafe { (*hlc).estimated_clock_skew_nanos() }
+}
+
+/// # Safety
+///
+/// `hlc` must point to a live clock from `hlc_new`.
+#[no_mangle]
+pub unsafe extern "C" fn hlc_advance_to(hlc: *const HybridLogicalClock, ts: HLCTimestamp) {
+    unsafe { (*hlc).advance_to(ts) }
+}
+
+/// Returns 0 on success and -1 if the checkpoint could not be written
+///
+/// # Safety
+///
+/// `hlc` must point to a live clock from `hlc_new`, and `path` must point to
+/// a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hlc_checkpoint_to(
+    hlc: *const HybridLogicalClock,
+    path: *const c_char,
+) -> i32 {
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str() else {
+        return -1;
+    };
+    match unsafe { (*hlc).checkpoint_to(path) } {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hlc_initialized_with(min_ts: HLCTimestamp) -> *mut HybridLogicalClock {
+    Box::into_raw(Box::new(HybridLogicalClock::initialized_with(min_ts)))
+}
+
+/// Returns null if the checkpoint exists but could not be read
+///
+/// # Safety
+///
+/// `path` must point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hlc_recover_from(
+    path: *const c_char,
+    safety_margin_nanos: u64,
+) -> *mut HybridLogicalClock {
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str() else {
+        return std::ptr::null_mut();
+    };
+    match HybridLogicalClock::recover_from(path, Duration::from_nanos(safety_margin_nanos)) {
+        Ok(hlc) => Box::into_raw(Box::new(hlc)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn hlc_timestamp_compare(ts1: *const HLCTimestamp, ts2: *const HLCTimestamp) -> i8 {
     unsafe {
@@ -194,12 +819,22 @@ pub extern "C" fn hlc_timestamp_from_bytes(bytes: *const u8) -> HLCTimestamp {
     }
 }
 
-#[cfg(test)]
+// Loom's primitives only work inside `loom::model`, so the regular tests
+// are left out of loom builds
+#[cfg(all(test, not(loom)))]
 mod tests {
     use super::*;
     use std::thread;
     use std::time::Duration;
 
+    /// Xorshift, so any failing interleaving input is reproducible by seed
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
     #[test]
     fn test_hlc_now() {
         let hlc = HybridLogicalClock::new();
@@ -226,6 +861,404 @@ mod tests {
         assert!(ts2.is_greater_than(&remote_ts));
     }
 
+    #[test]
+    fn test_observe_skips_the_event_tick() {
+        let remote_ts = HLCTimestamp {
+            physical: HybridLogicalClock::new().now().physical + 60_000_000_000,
+            logical: 7,
+        };
+
+        let observed = HybridLogicalClock::new();
+        observed.observe(remote_ts);
+        observed.observe(remote_ts);
+        let ts = observed.now();
+        assert_eq!((ts.physical, ts.logical), (remote_ts.physical, 8));
+
+        // `update` spends a tick on the receive event itself
+        let updated = HybridLogicalClock::new();
+        assert_eq!(updated.update(remote_ts).logical, 8);
+        assert_eq!(updated.now().logical, 9);
+    }
+
+    /// Never advances, so every timestamp shows exactly the configured unit
+    struct FrozenClock(u64);
+
+    impl PhysicalClock for FrozenClock {
+        fn now_nanos(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_time_unit_scales_physical_component() {
+        let nanos = 1_700_000_000_123_456_789;
+        for (unit, physical) in [
+            (TimeUnit::Nanos, nanos),
+            (TimeUnit::Micros, 1_700_000_000_123_456),
+            (TimeUnit::Millis, 1_700_000_000_123),
+        ] {
+            let hlc = HybridLogicalClock::with_clock(FrozenClock(nanos)).with_time_unit(unit);
+            assert_eq!(hlc.time_unit(), unit);
+            let first = hlc.now();
+            let second = hlc.now();
+            assert_eq!((first.physical, first.logical), (physical, 0));
+            assert_eq!((second.physical, second.logical), (physical, 1));
+            assert_eq!(hlc.update(first).physical, physical);
+        }
+
+        let earlier = HLCTimestamp {
+            physical: 1_000,
+            logical: 3,
+        };
+        let later = HLCTimestamp {
+            physical: 1_250,
+            logical: 0,
+        };
+        assert_eq!(
+            later.duration_since(&earlier, TimeUnit::Micros),
+            Duration::from_micros(250)
+        );
+        assert_eq!(
+            later.duration_since(&earlier, TimeUnit::Millis),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            earlier.duration_since(&later, TimeUnit::Nanos),
+            Duration::ZERO
+        );
+        assert_eq!(
+            TimeUnit::Micros.whole_units(Duration::from_millis(2)),
+            2_000
+        );
+    }
+
+    #[test]
+    fn test_skew_estimate_follows_remote_lead() {
+        let hlc = HybridLogicalClock::with_clock(FrozenClock(1_000_000));
+        assert_eq!(hlc.estimated_clock_skew_nanos(), 0);
+
+        let remote = |physical| HLCTimestamp {
+            physical,
+            logical: 0,
+        };
+        hlc.update(remote(1_500_000));
+        assert_eq!(hlc.estimated_clock_skew_nanos(), 500_000);
+
+        // Remotes persistently behind pull the average negative
+        for _ in 0..100 {
+            hlc.observe(remote(900_000));
+        }
+        let skew = hlc.estimated_clock_skew_nanos();
+        assert!((-100_000..-99_000).contains(&skew), "{}", skew);
+    }
+
+    #[test]
+    fn test_initialized_with_issues_after_seed() {
+        let seed = HLCTimestamp {
+            physical: HybridLogicalClock::new().now().physical + 60_000_000_000,
+            logical: 41,
+        };
+        let hlc = HybridLogicalClock::initialized_with(seed);
+        let ts = hlc.now();
+        assert_eq!((ts.physical, ts.logical), (seed.physical, 42));
+
+        // A seed behind the wall clock doesn't hold the clock back
+        let hlc = HybridLogicalClock::initialized_with(HLCTimestamp {
+            physical: 1,
+            logical: 0,
+        });
+        let wall = SystemClock.now_nanos();
+        assert!(hlc.now().physical >= wall);
+
+        let ptr = hlc_initialized_with(seed);
+        assert!(hlc_now(ptr).is_greater_than(&seed));
+        hlc_free(ptr);
+    }
+
+    #[test]
+    fn test_exhausted_logical_counter_rolls_into_physical() {
+        let hlc = HybridLogicalClock::new();
+        let remote_ts = HLCTimestamp {
+            physical: hlc.now().physical + 60_000_000_000,
+            logical: u64::MAX,
+        };
+
+        let ts = hlc.update(remote_ts);
+        assert_eq!((ts.physical, ts.logical), (remote_ts.physical + 1, 0));
+        assert!(hlc.now().is_greater_than(&ts));
+
+        let hlc = HybridLogicalClock::new();
+        hlc.advance_to(remote_ts);
+        let ts = hlc.now();
+        assert_eq!((ts.physical, ts.logical), (remote_ts.physical + 1, 0));
+    }
+
+    #[test]
+    fn test_update_batch_dominates_every_remote() {
+        let hlc = HybridLogicalClock::with_clock(FrozenClock(100));
+        let before = hlc.now();
+        let remotes = [
+            HLCTimestamp {
+                physical: 150,
+                logical: 3,
+            },
+            HLCTimestamp {
+                physical: 200,
+                logical: 1,
+            },
+            HLCTimestamp {
+                physical: 200,
+                logical: 7,
+            },
+            HLCTimestamp {
+                physical: 50,
+                logical: 9,
+            },
+        ];
+
+        let ts = hlc.update_batch(&remotes);
+        assert_eq!((ts.physical, ts.logical), (200, 8));
+        assert!(ts.is_greater_than(&before));
+        assert!(remotes.iter().all(|remote| ts.is_greater_than(remote)));
+
+        // Same as merging them one by one, minus the intermediate ticks
+        let naive = HybridLogicalClock::with_clock(FrozenClock(100));
+        naive.now();
+        let last = remotes
+            .iter()
+            .map(|&remote| naive.update(remote))
+            .last()
+            .unwrap();
+        assert!(!last.is_less_than(&ts));
+
+        assert!(hlc.update_batch(&[]).is_greater_than(&ts));
+    }
+
+    #[test]
+    fn test_logical_cap_spills_into_physical() {
+        let hlc = HybridLogicalClock::with_clock(FrozenClock(100)).with_max_logical_per_tick(2);
+        let issued: Vec<_> = (0..5)
+            .map(|_| {
+                let ts = hlc.now();
+                (ts.physical, ts.logical)
+            })
+            .collect();
+        assert_eq!(issued, [(100, 0), (100, 1), (100, 2), (101, 0), (101, 1)]);
+
+        let remote = HLCTimestamp {
+            physical: 101,
+            logical: 50,
+        };
+        let ts = hlc.update(remote);
+        assert_eq!((ts.physical, ts.logical), (102, 0));
+        assert!(ts.is_greater_than(&remote));
+    }
+
+    #[test]
+    fn test_interval_bounds() {
+        let start = HLCTimestamp {
+            physical: 1_000,
+            logical: 3,
+        };
+        let end = start.checked_add_nanos(500).unwrap();
+        assert_eq!((end.physical, end.logical), (1_500, 3));
+
+        let before_end = HLCTimestamp {
+            physical: 1_500,
+            logical: 2,
+        };
+        let before_start = HLCTimestamp {
+            physical: 1_000,
+            logical: 2,
+        };
+        assert!(start.in_interval(&start, &end));
+        assert!(before_end.in_interval(&start, &end));
+        assert!(!end.in_interval(&start, &end));
+        assert!(!before_start.in_interval(&start, &end));
+        assert!(!start.in_interval(&end, &start));
+        assert!(!start.in_interval(&start, &start));
+
+        let earlier = end.checked_sub_nanos(500).unwrap();
+        assert_eq!(earlier.compare(&start), std::cmp::Ordering::Equal);
+        assert_eq!(start.checked_sub_nanos(1_000).unwrap().physical, 0);
+        assert!(start.checked_sub_nanos(1_001).is_none());
+        assert!(HLCTimestamp::MAX.checked_add_nanos(1).is_none());
+    }
+
+    #[test]
+    fn test_sentinels_bound_issued_timestamps() {
+        assert!(HLCTimestamp::ZERO.is_zero());
+        assert_eq!(
+            HLCTimestamp::MIN.compare(&HLCTimestamp::ZERO),
+            std::cmp::Ordering::Equal
+        );
+
+        let hlc = HybridLogicalClock::with_clock(FrozenClock(0));
+        let first = hlc.now();
+        assert!(!first.is_zero());
+        assert!(HLCTimestamp::MIN.is_less_than(&first));
+        assert!(HLCTimestamp::MAX.is_greater_than(&HybridLogicalClock::new().now()));
+        assert!(HLCTimestamp::MAX.is_greater_than(&HLCTimestamp {
+            physical: u64::MAX,
+            logical: u64::MAX - 1,
+        }));
+    }
+
+    #[test]
+    fn test_arithmetic_saturates_at_the_end_of_time() {
+        let end = HLCTimestamp::MAX;
+        let hlc = HybridLogicalClock::new();
+        let ts = hlc.update(end);
+        assert_eq!((ts.physical, ts.logical), (u64::MAX, u64::MAX));
+        let ts = hlc.now();
+        assert_eq!((ts.physical, ts.logical), (u64::MAX, u64::MAX));
+
+        assert_eq!(end.uncertainty_upper(1), u64::MAX);
+        assert!(!HybridLogicalClock::is_definitely_after(&end, &end, 0));
+    }
+
+    #[test]
+    fn test_advance_to_never_moves_backward() {
+        let hlc = HybridLogicalClock::new();
+        let floor = HLCTimestamp {
+            physical: hlc.now().physical + 60_000_000_000,
+            logical: 9,
+        };
+
+        hlc.advance_to(floor);
+        let ts1 = hlc.now();
+        assert!(ts1.is_greater_than(&floor));
+
+        // An older timestamp is ignored
+        hlc.advance_to(HLCTimestamp {
+            physical: floor.physical - 1,
+            logical: 1_000,
+        });
+        let ts2 = hlc.now();
+        assert!(ts2.is_greater_than(&ts1));
+    }
+
+    #[test]
+    fn test_advance_to_concurrent_with_now() {
+        let hlc = HybridLogicalClock::new();
+        let floor = HLCTimestamp {
+            physical: hlc.now().physical + 60_000_000_000,
+            logical: 3,
+        };
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..1_000 {
+                    hlc.now();
+                }
+            });
+            scope.spawn(|| hlc.advance_to(floor));
+        });
+
+        assert!(hlc.now().is_greater_than(&floor));
+    }
+
+    #[test]
+    fn test_concurrent_timestamps_are_unique_and_monotonic() {
+        let hlc = HybridLogicalClock::new();
+        let base = hlc.now().physical;
+
+        let issued: Vec<Vec<HLCTimestamp>> = thread::scope(|scope| {
+            let handles: Vec<_> = (1..=8u64)
+                .map(|seed| {
+                    let hlc = &hlc;
+                    scope.spawn(move || {
+                        let mut rng = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                        let mut local: Vec<HLCTimestamp> = Vec::with_capacity(5_000);
+                        for _ in 0..5_000 {
+                            let r = next_random(&mut rng);
+                            let logical = (r >> 40) % 1_000;
+                            let ts = match r % 4 {
+                                0 => hlc.now(),
+                                // Remote clock lagging behind ours
+                                1 => hlc.update(HLCTimestamp {
+                                    physical: base - (r >> 8) % 1_000_000,
+                                    logical: r >> 40,
+                                }),
+                                // Tied with the latest physical time seen here
+                                2 => hlc.update(HLCTimestamp {
+                                    physical: local.last().map_or(base, |ts| ts.physical),
+                                    logical,
+                                }),
+                                // Remote clock slightly ahead
+                                _ => hlc.update(HLCTimestamp {
+                                    physical: base + (r >> 8) % 1_000_000,
+                                    logical,
+                                }),
+                            };
+                            local.push(ts);
+                        }
+                        local
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        for local in &issued {
+            for pair in local.windows(2) {
+                assert!(
+                    pair[1].is_greater_than(&pair[0]),
+                    "{:?} issued after {:?}",
+                    pair[1],
+                    pair[0]
+                );
+            }
+        }
+
+        let mut all: Vec<_> = issued
+            .iter()
+            .flatten()
+            .map(|ts| (ts.physical, ts.logical))
+            .collect();
+        let total = all.len();
+        all.sort_unstable();
+        all.dedup();
+        assert_eq!(all.len(), total, "duplicate timestamps issued");
+    }
+
+    #[test]
+    fn test_trace_buffer_keeps_latest_events() {
+        assert!(HybridLogicalClock::new().dump_trace().is_empty());
+
+        let hlc = HybridLogicalClock::new().with_trace_buffer(2);
+        hlc.now();
+        let remote = HLCTimestamp {
+            physical: u64::MAX / 2,
+            logical: 7,
+        };
+        let merged = hlc.update(remote);
+        let latest = hlc.now();
+
+        let trace = hlc.dump_trace();
+        assert_eq!(trace.len(), 2);
+        match trace[0] {
+            HlcEvent::Update {
+                remote: traced_remote,
+                issued,
+                ..
+            } => {
+                assert_eq!(traced_remote.compare(&remote), std::cmp::Ordering::Equal);
+                assert_eq!(issued.compare(&merged), std::cmp::Ordering::Equal);
+            }
+            other => panic!("expected the update first, got {:?}", other),
+        }
+        match trace[1] {
+            HlcEvent::Now {
+                previous, issued, ..
+            } => {
+                assert_eq!(previous.compare(&merged), std::cmp::Ordering::Equal);
+                assert_eq!(issued.compare(&latest), std::cmp::Ordering::Equal);
+            }
+            other => panic!("expected now last, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_timestamp_serialization() {
         let ts = HLCTimestamp {
@@ -239,4 +1272,240 @@ mod tests {
         assert_eq!(ts.physical, restored.physical);
         assert_eq!(ts.logical, restored.logical);
     }
+
+    #[test]
+    fn test_lww_merge() {
+        let older = HLCTimestamp {
+            physical: 1_000,
+            logical: 5,
+        };
+        let newer = HLCTimestamp {
+            physical: 1_000,
+            logical: 6,
+        };
+
+        assert_eq!(lww_merge((older, "a"), (newer, "b")).1, "b");
+        assert_eq!(lww_merge((newer, "b"), (older, "a")).1, "b");
+
+        // Physical time outranks the logical counter
+        let later = HLCTimestamp {
+            physical: 1_001,
+            logical: 0,
+        };
+        assert_eq!(lww_merge((newer, "b"), (later, "c")).1, "c");
+    }
+
+    #[test]
+    fn test_lww_merge_tie_keeps_first() {
+        let ts = HLCTimestamp {
+            physical: 1_000,
+            logical: 1,
+        };
+
+        let (winner_ts, winner) = lww_merge((ts, "local"), (ts, "remote"));
+        assert_eq!(winner, "local");
+        assert_eq!(winner_ts.compare(&ts), std::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn test_lww_merge_tagged_breaks_ties_by_node() {
+        let ts = HLCTimestamp {
+            physical: 1_000,
+            logical: 1,
+        };
+        let node_3 = TaggedTimestamp::new(ts, 3);
+        let node_7 = TaggedTimestamp::new(ts, 7);
+
+        // Same winner in either argument order
+        assert_eq!(lww_merge_tagged((node_3, "a"), (node_7, "b")).1, "b");
+        assert_eq!(lww_merge_tagged((node_7, "b"), (node_3, "a")).1, "b");
+
+        // A legacy timestamp counts as node 0
+        let legacy = TaggedTimestamp::legacy(ts);
+        assert_eq!(lww_merge_tagged((legacy, "old"), (node_3, "a")).1, "a");
+        assert_eq!(lww_merge_tagged((legacy, "x"), (legacy, "y")).1, "x");
+
+        // The timestamp still outranks the node id
+        let later = TaggedTimestamp::new(ts.successor(), 1);
+        assert_eq!(lww_merge_tagged((node_7, "b"), (later, "c")).1, "c");
+    }
+
+    #[test]
+    fn test_compact_serialization() {
+        let ts = HLCTimestamp {
+            physical: 1234567890,
+            logical: u64::from(u32::MAX),
+        };
+
+        let bytes = ts.to_bytes_compact().unwrap();
+        let decoded = HLCTimestamp::from_bytes_compact(&bytes);
+        assert_eq!(decoded.compare(&ts), std::cmp::Ordering::Equal);
+
+        // Little-endian, so it matches the first 12 bytes of the full form
+        assert_eq!(bytes[..], ts.to_bytes()[..12]);
+
+        let overflowing = HLCTimestamp {
+            logical: u64::from(u32::MAX) + 1,
+            ..ts
+        };
+        assert_eq!(
+            overflowing.to_bytes_compact(),
+            Err(LogicalOverflow(u64::from(u32::MAX) + 1))
+        );
+    }
+
+    #[test]
+    fn test_uncertainty_window() {
+        let max_offset = 500_000; // 0.5ms
+        let b = HLCTimestamp {
+            physical: 1_000_000_000,
+            logical: 3,
+        };
+        assert_eq!(b.uncertainty_upper(max_offset), 1_000_500_000);
+
+        // Later, but inside b's uncertainty window
+        let overlapping = HLCTimestamp {
+            physical: b.physical + 200_000,
+            logical: 0,
+        };
+        assert!(overlapping.is_greater_than(&b));
+        assert!(!HybridLogicalClock::is_definitely_after(&overlapping, &b, max_offset));
+
+        // At the edge of the window is still uncertain
+        let edge = HLCTimestamp {
+            physical: b.physical + max_offset,
+            logical: 9,
+        };
+        assert!(!HybridLogicalClock::is_definitely_after(&edge, &b, max_offset));
+
+        let beyond = HLCTimestamp {
+            physical: b.physical + max_offset + 1,
+            logical: 0,
+        };
+        assert!(HybridLogicalClock::is_definitely_after(&beyond, &b, max_offset));
+        assert!(!HybridLogicalClock::is_definitely_after(&b, &beyond, max_offset));
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary_timestamps_are_issuable_and_ordered() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let same = [7u8; 32];
+        let pair = OrderedTimestamps::arbitrary(&mut Unstructured::new(&same)).unwrap();
+        assert!(pair.earlier.is_less_than(&pair.later));
+
+        let end = [0xffu8; 32];
+        let pair = OrderedTimestamps::arbitrary(&mut Unstructured::new(&end)).unwrap();
+        assert!(pair.earlier.is_less_than(&pair.later));
+
+        let zeros = [0u8; 16];
+        let ts = HLCTimestamp::arbitrary(&mut Unstructured::new(&zeros)).unwrap();
+        assert!(!ts.is_zero());
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_datetime_round_trip() {
+        use chrono::{TimeZone, Utc};
+
+        let ts = HLCTimestamp {
+            physical: 1_700_000_000_123_456_789,
+            logical: 4,
+        };
+        let dt = ts.physical_as_datetime();
+        assert_eq!(dt.to_rfc3339(), "2023-11-14T22:13:20.123456789+00:00");
+
+        let restored = HLCTimestamp::from_datetime(dt, ts.logical).unwrap();
+        assert_eq!(restored.physical, ts.physical);
+        assert_eq!(restored.logical, ts.logical);
+
+        // Beyond chrono's i64-nanosecond limit but within u64
+        let late = Utc.with_ymd_and_hms(2400, 1, 1, 0, 0, 0).unwrap();
+        let late_ts = HLCTimestamp::from_datetime(late, 0).unwrap();
+        assert_eq!(late_ts.physical_as_datetime(), late);
+
+        let max = HLCTimestamp {
+            physical: u64::MAX,
+            logical: 0,
+        };
+        assert_eq!(max.physical_as_datetime().timestamp(), (u64::MAX / 1_000_000_000) as i64);
+
+        let pre_epoch = Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap();
+        assert!(HLCTimestamp::from_datetime(pre_epoch, 0).is_none());
+        let too_late = Utc.with_ymd_and_hms(2600, 1, 1, 0, 0, 0).unwrap();
+        assert!(HLCTimestamp::from_datetime(too_late, 0).is_none());
+    }
+
+    #[test]
+    fn test_recovered_clock_stays_ahead_of_checkpoint() {
+        let path = std::env::temp_dir().join(format!("hlc_checkpoint_{}", std::process::id()));
+        let hlc = HybridLogicalClock::new();
+
+        // A timestamp far ahead of the wall clock, as if received from a peer
+        let remote_ts = HLCTimestamp {
+            physical: hlc.now().physical + 60_000_000_000,
+            logical: 7,
+        };
+        let issued = hlc.update(remote_ts);
+        hlc.checkpoint_to(&path).unwrap();
+
+        let margin = Duration::from_millis(10);
+        let recovered = HybridLogicalClock::recover_from(&path, margin).unwrap();
+        let ts = recovered.now();
+        fs::remove_file(&path).unwrap();
+
+        assert!(ts.is_greater_than(&issued));
+        assert!(ts.physical >= issued.physical + margin.as_nanos() as u64);
+    }
+
+    #[test]
+    fn test_recover_without_checkpoint_starts_fresh() {
+        let path = std::env::temp_dir().join("hlc_checkpoint_missing");
+        let hlc = HybridLogicalClock::recover_from(&path, Duration::from_secs(1)).unwrap();
+        assert_eq!(hlc.lock_last().physical, 0);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    /// Never advances, so every call contends on the logical counter
+    struct FrozenClock(u64);
+
+    impl PhysicalClock for FrozenClock {
+        fn now_nanos(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn loom_now_and_update_never_collide() {
+        loom::model(|| {
+            let hlc = Arc::new(HybridLogicalClock::with_clock(FrozenClock(100)));
+            let remote = HLCTimestamp {
+                physical: 100,
+                logical: 1,
+            };
+
+            let other = {
+                let hlc = Arc::clone(&hlc);
+                thread::spawn(move || [hlc.now(), hlc.update(remote)])
+            };
+            let mine = [hlc.update(remote), hlc.now()];
+            let theirs = other.join().unwrap();
+
+            for local in [&mine, &theirs] {
+                assert!(local[1].is_greater_than(&local[0]));
+            }
+            for a in &mine {
+                for b in &theirs {
+                    assert_ne!(a.compare(b), std::cmp::Ordering::Equal);
+                }
+            }
+        });
+    }
 }
@@ -0,0 +1,10 @@
+//! Synchronization primitives, swapped for loom's under `--cfg loom`
+//!
+//! `RUSTFLAGS="--cfg loom" cargo test --release loom` model-checks the clock
+//! over every interleaving of its lock operations.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{Mutex, MutexGuard};
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Mutex, MutexGuard};
@@ -0,0 +1,182 @@
+//! Timestamps tagged with the issuing node, and their versioned encoding
+//!
+//! Legacy timestamps are the bare 16-byte `HLCTimestamp::to_bytes` output.
+//! Tagged ones start with a version byte, so the two can be told apart by
+//! length alone and stored side by side during a migration.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::HLCTimestamp;
+
+/// Leading byte of the current tagged encoding
+pub const TAGGED_VERSION: u8 = 1;
+
+/// Length of a legacy, untagged encoding
+pub const LEGACY_LEN: usize = 16;
+
+/// Length of a version 1 encoding: version, physical, logical, node id
+pub const TAGGED_LEN: usize = 1 + 8 + 8 + 4;
+
+/// An `HLCTimestamp` with the node that issued it, if known
+///
+/// Timestamps decoded from the legacy format have no node id and order as
+/// node 0, so a legacy timestamp never compares after a tagged one from the
+/// same instant.
+#[derive(Clone, Copy, Debug)]
+pub struct TaggedTimestamp {
+    pub timestamp: HLCTimestamp,
+    pub node_id: Option<u32>,
+}
+
+impl TaggedTimestamp {
+    pub fn new(timestamp: HLCTimestamp, node_id: u32) -> Self {
+        Self {
+            timestamp,
+            node_id: Some(node_id),
+        }
+    }
+
+    /// A timestamp from before node ids were recorded
+    pub fn legacy(timestamp: HLCTimestamp) -> Self {
+        Self {
+            timestamp,
+            node_id: None,
+        }
+    }
+
+    /// Order by physical time, then logical counter, then node id, with a
+    /// missing node id counting as 0
+    pub fn compare(&self, other: &TaggedTimestamp) -> Ordering {
+        self.timestamp
+            .compare(&other.timestamp)
+            .then_with(|| self.node_id.unwrap_or(0).cmp(&other.node_id.unwrap_or(0)))
+    }
+
+    /// Encode in the current tagged format, all fields little-endian
+    ///
+    /// A missing node id is written as 0; re-encoding a legacy timestamp
+    /// this way is how stored values are upgraded.
+    pub fn to_bytes_versioned(&self) -> [u8; TAGGED_LEN] {
+        let mut bytes = [0u8; TAGGED_LEN];
+        bytes[0] = TAGGED_VERSION;
+        bytes[1..17].copy_from_slice(&self.timestamp.to_bytes());
+        bytes[17..].copy_from_slice(&self.node_id.unwrap_or(0).to_le_bytes());
+        bytes
+    }
+
+    /// Decode either format, telling them apart by length and version byte
+    pub fn from_bytes_versioned(bytes: &[u8]) -> Result<Self, TimestampDecodeError> {
+        if let Ok(legacy) = <&[u8; LEGACY_LEN]>::try_from(bytes) {
+            return Ok(Self::legacy(HLCTimestamp::from_bytes(legacy)));
+        }
+
+        match bytes.first() {
+            Some(&TAGGED_VERSION) if bytes.len() == TAGGED_LEN => {
+                let mut timestamp = [0u8; LEGACY_LEN];
+                timestamp.copy_from_slice(&bytes[1..17]);
+                let node_id = u32::from_le_bytes([bytes[17], bytes[18], bytes[19], bytes[20]]);
+                Ok(Self::new(HLCTimestamp::from_bytes(&timestamp), node_id))
+            }
+            Some(&TAGGED_VERSION) | None => Err(TimestampDecodeError::Length(bytes.len())),
+            Some(&version) => Err(TimestampDecodeError::UnknownVersion(version)),
+        }
+    }
+}
+
+impl PartialEq for TaggedTimestamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.compare(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TaggedTimestamp {}
+
+impl PartialOrd for TaggedTimestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TaggedTimestamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.compare(other)
+    }
+}
+
+/// Bytes that are neither a legacy nor a known tagged timestamp
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampDecodeError {
+    UnknownVersion(u8),
+    Length(usize),
+}
+
+impl fmt::Display for TimestampDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampDecodeError::UnknownVersion(version) => {
+                write!(f, "unknown timestamp encoding version {}", version)
+            }
+            TimestampDecodeError::Length(len) => {
+                write!(f, "{} bytes is not a valid timestamp encoding", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TimestampDecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(physical: u64, logical: u64) -> HLCTimestamp {
+        HLCTimestamp { physical, logical }
+    }
+
+    #[test]
+    fn test_legacy_and_tagged_round_trip() {
+        let tagged = TaggedTimestamp::new(ts(1_700_000_000_000_000_000, 3), 42);
+        let bytes = tagged.to_bytes_versioned();
+        assert_eq!(bytes[0], TAGGED_VERSION);
+        let decoded = TaggedTimestamp::from_bytes_versioned(&bytes).unwrap();
+        assert_eq!(decoded.node_id, Some(42));
+        assert_eq!(decoded, tagged);
+
+        let legacy = ts(1_700_000_000_000_000_000, 3).to_bytes();
+        let decoded = TaggedTimestamp::from_bytes_versioned(&legacy).unwrap();
+        assert_eq!(decoded.node_id, None);
+        assert_eq!(decoded.timestamp.logical, 3);
+    }
+
+    #[test]
+    fn test_missing_node_id_orders_as_zero() {
+        let legacy = TaggedTimestamp::legacy(ts(100, 5));
+        assert_eq!(legacy, TaggedTimestamp::new(ts(100, 5), 0));
+        assert!(legacy < TaggedTimestamp::new(ts(100, 5), 1));
+        assert!(legacy > TaggedTimestamp::new(ts(100, 4), 9));
+        assert!(legacy < TaggedTimestamp::new(ts(101, 0), 0));
+    }
+
+    #[test]
+    fn test_rejects_unknown_encodings() {
+        let mut bytes = TaggedTimestamp::new(ts(1, 1), 1).to_bytes_versioned();
+        bytes[0] = 9;
+        assert_eq!(
+            TaggedTimestamp::from_bytes_versioned(&bytes),
+            Err(TimestampDecodeError::UnknownVersion(9))
+        );
+        assert_eq!(
+            TaggedTimestamp::from_bytes_versioned(&bytes[..12]),
+            Err(TimestampDecodeError::UnknownVersion(9))
+        );
+        assert_eq!(
+            TaggedTimestamp::from_bytes_versioned(&[TAGGED_VERSION; 5]),
+            Err(TimestampDecodeError::Length(5))
+        );
+        assert_eq!(
+            TaggedTimestamp::from_bytes_versioned(&[]),
+            Err(TimestampDecodeError::Length(0))
+        );
+    }
+}
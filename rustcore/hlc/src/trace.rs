@@ -0,0 +1,70 @@
+//! Opt-in record of recent clock operations for debugging ordering anomalies
+//!
+//! Events are recorded while the clock's own lock is held, so the trace lock
+//! is never contended by timestamp issuance and the trace order matches the
+//! order timestamps were issued in.
+
+use std::collections::VecDeque;
+use std::sync::PoisonError;
+
+use crate::sync::Mutex;
+use crate::HLCTimestamp;
+
+/// One `now()` or `update()` call with its inputs and result
+#[derive(Clone, Copy, Debug)]
+pub enum HlcEvent {
+    Now {
+        /// Physical clock reading
+        physical_now: u64,
+        /// Latest timestamp before the call
+        previous: HLCTimestamp,
+        issued: HLCTimestamp,
+    },
+    Update {
+        /// Physical clock reading
+        physical_now: u64,
+        /// Latest timestamp before the call
+        previous: HLCTimestamp,
+        /// Timestamp received from the remote node
+        remote: HLCTimestamp,
+        issued: HLCTimestamp,
+    },
+}
+
+impl HlcEvent {
+    /// Timestamp the operation returned
+    pub fn issued(&self) -> HLCTimestamp {
+        match self {
+            HlcEvent::Now { issued, .. } | HlcEvent::Update { issued, .. } => *issued,
+        }
+    }
+}
+
+/// Fixed-capacity ring of the most recent events
+pub(crate) struct TraceBuffer {
+    events: Mutex<VecDeque<HlcEvent>>,
+    capacity: usize,
+}
+
+impl TraceBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub(crate) fn record(&self, event: HlcEvent) {
+        let mut events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Events oldest first
+    pub(crate) fn dump(&self) -> Vec<HlcEvent> {
+        let events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        events.iter().copied().collect()
+    }
+}